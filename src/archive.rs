@@ -0,0 +1,274 @@
+//! Tar archives of collections.
+use std::collections::VecDeque;
+use std::io::Read;
+use std::path::PathBuf;
+
+use anyhow::{ensure, Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::blobs::{Blob, EntryKind, Metadata, MetadataV1};
+
+/// The size of a tar block, headers and data are padded to it.
+const TAR_BLOCK_SIZE: u64 = 512;
+
+/// Writes the entries of a collection as a tar archive.
+///
+/// Entries are written in the order of the collection.  Entries without data are written
+/// when they are reached, the data of files is passed to [`TarWriter::append_file`].  Names
+/// and symlink targets which do not fit in a header are written as GNU long name entries.
+#[derive(Debug)]
+pub struct TarWriter<W> {
+    writer: W,
+    /// The entries of the collection which are not yet written.
+    pending: VecDeque<Blob>,
+}
+
+impl<W: AsyncWrite + Unpin> TarWriter<W> {
+    /// Creates a writer for the given entries of a collection.
+    ///
+    /// Without entries, every file appended is written without metadata.
+    pub fn new(writer: W, pending: VecDeque<Blob>) -> Self {
+        Self { writer, pending }
+    }
+
+    /// Appends a file, after all entries without data which come before it.
+    pub async fn append_file<R: AsyncRead + Unpin>(
+        &mut self,
+        name: &str,
+        size: u64,
+        reader: &mut R,
+    ) -> Result<()> {
+        self.append_entries_without_data().await?;
+        let meta = match self.pending.pop_front() {
+            Some(blob) => {
+                ensure!(blob.name() == name, "unexpected entry {name}");
+                metadata_v1(&blob)
+            }
+            None => None,
+        };
+        self.append_header(name, tar::EntryType::Regular, size, meta.as_ref(), None)
+            .await?;
+        let copied = tokio::io::copy(&mut reader.take(size), &mut self.writer).await?;
+        ensure!(copied == size, "file {name} is shorter than {size} bytes");
+        self.append_padding(size).await
+    }
+
+    /// Writes the remaining entries and the end of archive marker.
+    pub async fn finish(mut self) -> Result<()> {
+        self.append_entries_without_data().await?;
+        ensure!(self.pending.is_empty(), "archive is missing files");
+        self.writer
+            .write_all(&[0u8; 2 * TAR_BLOCK_SIZE as usize])
+            .await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    async fn append_entries_without_data(&mut self) -> Result<()> {
+        while let Some(blob) = self.pending.front() {
+            if blob.has_data() {
+                break;
+            }
+            let blob = self.pending.pop_front().expect("checked above");
+            let meta = metadata_v1(&blob);
+            match meta.as_ref().map(|meta| &meta.kind) {
+                Some(EntryKind::Symlink(target)) => {
+                    self.append_header(
+                        blob.name(),
+                        tar::EntryType::Symlink,
+                        0,
+                        meta.as_ref(),
+                        Some(target),
+                    )
+                    .await?
+                }
+                _ => {
+                    let name = format!("{}/", blob.name());
+                    self.append_header(&name, tar::EntryType::Directory, 0, meta.as_ref(), None)
+                        .await?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn append_header(
+        &mut self,
+        name: &str,
+        entry_type: tar::EntryType,
+        size: u64,
+        meta: Option<&MetadataV1>,
+        link: Option<&str>,
+    ) -> Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_size(size);
+        let default_mode = match entry_type {
+            tar::EntryType::Directory => 0o755,
+            tar::EntryType::Symlink => 0o777,
+            _ => 0o644,
+        };
+        header.set_mode(meta.and_then(|meta| meta.mode).unwrap_or(default_mode));
+        header.set_mtime(meta.and_then(|meta| meta.mtime).unwrap_or_default());
+        // names which do not fit in the header use GNU long name entries
+        if !copy_truncated(&mut header.as_old_mut().name, name) {
+            self.append_long_name(tar::EntryType::GNULongName, name)
+                .await?;
+        }
+        if let Some(link) = link {
+            if !copy_truncated(&mut header.as_old_mut().linkname, link) {
+                self.append_long_name(tar::EntryType::GNULongLink, link)
+                    .await?;
+            }
+        }
+        header.set_cksum();
+        self.writer.write_all(header.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn append_long_name(&mut self, entry_type: tar::EntryType, name: &str) -> Result<()> {
+        let mut header = tar::Header::new_gnu();
+        copy_truncated(&mut header.as_old_mut().name, "././@LongLink");
+        header.set_entry_type(entry_type);
+        header.set_mode(0o644);
+        let size = name.len() as u64 + 1;
+        header.set_size(size);
+        header.set_cksum();
+        self.writer.write_all(header.as_bytes()).await?;
+        self.writer.write_all(name.as_bytes()).await?;
+        self.writer.write_all(&[0u8]).await?;
+        self.append_padding(size).await
+    }
+
+    async fn append_padding(&mut self, size: u64) -> Result<()> {
+        let padding = (TAR_BLOCK_SIZE - size % TAR_BLOCK_SIZE) % TAR_BLOCK_SIZE;
+        self.writer
+            .write_all(&[0u8; TAR_BLOCK_SIZE as usize][..padding as usize])
+            .await?;
+        Ok(())
+    }
+}
+
+/// Copies `value` into a tar header field, returns false if it had to be truncated.
+fn copy_truncated(field: &mut [u8], value: &str) -> bool {
+    let len = value.len().min(field.len());
+    field[..len].copy_from_slice(&value.as_bytes()[..len]);
+    value.len() <= field.len()
+}
+
+fn metadata_v1(blob: &Blob) -> Option<MetadataV1> {
+    match blob.metadata() {
+        Metadata::V1(meta) => Some(meta.clone()),
+        _ => None,
+    }
+}
+
+/// Unpacks the tar archive read from `reader` into `dir`.
+///
+/// Entries which would be written outside of `dir` are skipped.
+pub async fn unpack<R: Read + Send + 'static>(reader: R, dir: PathBuf) -> Result<()> {
+    tokio::task::spawn_blocking(move || tar::Archive::new(reader).unpack(dir))
+        .await?
+        .context("Failed to unpack tar archive")
+}
+
+#[cfg(test)]
+mod tests {
+    use testdir::testdir;
+
+    use super::*;
+    use crate::provider::{create_collection, data_sources_from_dir};
+    use crate::util::Hash;
+
+    fn blob(name: &str, kind: EntryKind, data: &[u8]) -> Blob {
+        Blob {
+            name: name.to_string(),
+            hash: Hash::new(data),
+            metadata: Metadata::V1(MetadataV1 {
+                kind,
+                mode: Some(0o750),
+                mtime: Some(1_680_000_000),
+            }),
+        }
+    }
+
+    /// Writes an archive with an empty directory, a file with a long name and a symlink
+    /// with a long target.
+    async fn write_archive(long_name: &str, data: &[u8]) -> Result<Vec<u8>> {
+        let long_target = format!("../{long_name}");
+        let entries = vec![
+            blob("empty", EntryKind::Directory, &[]),
+            blob("dir", EntryKind::Directory, &[]),
+            blob(long_name, EntryKind::File, data),
+            blob("dir/link", EntryKind::Symlink(long_target), &[]),
+        ];
+        let mut archive = Vec::new();
+        let mut writer = TarWriter::new(&mut archive, entries.into());
+        writer
+            .append_file(long_name, data.len() as u64, &mut &data[..])
+            .await?;
+        writer.finish().await?;
+        Ok(archive)
+    }
+
+    #[tokio::test]
+    async fn test_tar_roundtrip() -> Result<()> {
+        let long_name = format!("dir/{}", "x".repeat(150));
+        let data = vec![7u8; 1000];
+        let archive = write_archive(&long_name, &data).await?;
+        assert_eq!(archive.len() % TAR_BLOCK_SIZE as usize, 0);
+
+        let mut entries = Vec::new();
+        for entry in tar::Archive::new(&archive[..]).entries()? {
+            let mut entry = entry?;
+            let header = entry.header().clone();
+            let path = entry.path()?.to_str().unwrap().to_string();
+            let link = entry
+                .link_name()?
+                .map(|link| link.to_str().unwrap().to_string());
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content)?;
+            assert_eq!(header.mode()?, 0o750, "{path}");
+            assert_eq!(header.mtime()?, 1_680_000_000, "{path}");
+            entries.push((path, header.entry_type(), link, content));
+        }
+        assert_eq!(
+            entries,
+            vec![
+                ("empty/".into(), tar::EntryType::Directory, None, vec![]),
+                ("dir/".into(), tar::EntryType::Directory, None, vec![]),
+                (long_name.clone(), tar::EntryType::Regular, None, data),
+                (
+                    "dir/link".into(),
+                    tar::EntryType::Symlink,
+                    Some(format!("../{long_name}")),
+                    vec![]
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tar_unpack() -> Result<()> {
+        let dir = testdir!();
+        let long_name = format!("dir/{}", "x".repeat(150));
+        let data = vec![7u8; 1000];
+        let archive = write_archive(&long_name, &data).await?;
+
+        unpack(std::io::Cursor::new(archive), dir.clone()).await?;
+        assert!(std::fs::read_dir(dir.join("empty"))?.next().is_none());
+        assert_eq!(std::fs::read(dir.join(&long_name))?, data);
+        #[cfg(unix)]
+        assert_eq!(
+            std::fs::read_link(dir.join("dir/link"))?,
+            PathBuf::from(format!("../{long_name}"))
+        );
+
+        // The unpacked directory is served like any other directory.
+        let sources = data_sources_from_dir(&dir).await?;
+        assert_eq!(sources.len(), 4);
+        create_collection(sources).await?;
+        Ok(())
+    }
+}
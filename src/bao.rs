@@ -0,0 +1,931 @@
+//! Bao encoding with chunk groups
+//!
+//! This is the bao verified streaming format, except that the leaves of the tree are groups
+//! of `2^chunk_group_log` chunks rather than single chunks.  The tree is the regular BLAKE3
+//! tree, so the root hash is the BLAKE3 hash of the data, but the outboard only contains the
+//! parent nodes above the chunk groups.  This makes the outboard `2^chunk_group_log` times
+//! smaller, at the cost of verifying, and thus transferring, whole chunk groups.  With a
+//! `chunk_group_log` of `0` this is exactly the bao format.
+//!
+//! The outboard starts with the length of the data as 8 byte little endian integer, followed
+//! by the parent nodes in pre-order.  An encoded slice starts with the same length header,
+//! followed by the parent nodes and chunk groups needed to verify the slice, in pre-order.
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use blake3::guts::{parent_cv, ChunkState, CHUNK_LEN};
+use blake3::platform::Platform;
+use blake3::IncrementCounter;
+use futures::ready;
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// The chunk group log used for new outboards.
+///
+/// Chunk groups of 1 MiB keep outboards at 1/16384 of the data size, e.g. 64 MiB for 1 TiB.
+pub(crate) const DEFAULT_CHUNK_GROUP_LOG: u8 = 10;
+
+/// The largest chunk group log a getter accepts, which bounds the memory needed to verify
+/// a chunk group to 64 MiB.
+pub(crate) const MAX_CHUNK_GROUP_LOG: u8 = 16;
+
+const HEADER_LEN: usize = 8;
+
+/// The BLAKE3 IV and chunk flags from the specification, which `blake3` does not export.
+const IV: [u32; 8] = [
+    0x6A09E667, 0xBB67AE85, 0x3C6EF372, 0xA54FF53A, 0x510E527F, 0x9B05688C, 0x1F83D9AB, 0x5BE0CD19,
+];
+const CHUNK_START: u8 = 1 << 0;
+const CHUNK_END: u8 = 1 << 1;
+const PARENT_LEN: usize = 64;
+
+/// The number of bytes in a chunk group.
+fn group_len(chunk_group_log: u8) -> u64 {
+    (CHUNK_LEN as u64) << chunk_group_log
+}
+
+/// The number of chunk groups of a subtree, an empty subtree has a single empty group.
+///
+/// This does not overflow for any length, as lengths are read from untrusted headers.
+fn count_groups(len: u64, chunk_group_log: u8) -> u64 {
+    div_ceil(len, group_len(chunk_group_log)).max(1)
+}
+
+/// Divides, rounding up, without overflowing.
+fn div_ceil(n: u64, d: u64) -> u64 {
+    n / d + u64::from(n % d != 0)
+}
+
+/// The size of the outboard of data of the given length.
+pub(crate) fn outboard_size(len: u64, chunk_group_log: u8) -> u64 {
+    HEADER_LEN as u64 + PARENT_LEN as u64 * (count_groups(len, chunk_group_log) - 1)
+}
+
+/// The length of the left child of a subtree with more than one chunk.
+///
+/// The left child contains the largest power of two number of chunks which is smaller than
+/// the number of chunks of the subtree.
+fn left_len(len: u64) -> u64 {
+    debug_assert!(len > CHUNK_LEN as u64);
+    let chunks = div_ceil(len, CHUNK_LEN as u64);
+    let left_chunks = 1 << (63 - (chunks - 1).leading_zeros());
+    left_chunks * CHUNK_LEN as u64
+}
+
+/// Computes the chaining value, or root hash, of the subtree starting at `start_chunk`.
+///
+/// `blake3::Hasher` can only compute root hashes, so it is only used for the root.  The
+/// chaining values of other subtrees are computed from their full chunks using the SIMD
+/// implementation of `blake3`, like `blake3::Hasher` does internally.
+fn hash_subtree(start_chunk: u64, data: &[u8], is_root: bool) -> blake3::Hash {
+    if is_root {
+        debug_assert_eq!(start_chunk, 0);
+        return blake3::hash(data);
+    }
+    let full = data.len() / CHUNK_LEN;
+    let chunks = data[..full * CHUNK_LEN]
+        .chunks_exact(CHUNK_LEN)
+        .map(|chunk| <&[u8; CHUNK_LEN]>::try_from(chunk).unwrap())
+        .collect::<Vec<_>>();
+    let mut out = vec![0u8; full * 32];
+    Platform::detect().hash_many(
+        &chunks,
+        &IV,
+        start_chunk,
+        IncrementCounter::Yes,
+        0,
+        CHUNK_START,
+        CHUNK_END,
+        &mut out,
+    );
+    let mut cvs = out
+        .chunks_exact(32)
+        .map(|cv| blake3::Hash::from(<[u8; 32]>::try_from(cv).unwrap()))
+        .collect::<Vec<_>>();
+    let rest = &data[full * CHUNK_LEN..];
+    if !rest.is_empty() || data.is_empty() {
+        cvs.push(
+            ChunkState::new(start_chunk + full as u64)
+                .update(rest)
+                .finalize(false),
+        );
+    }
+    merge_cvs(&cvs)
+}
+
+/// Merges the chaining values of consecutive chunks into the chaining value of their
+/// non-root subtree.
+fn merge_cvs(cvs: &[blake3::Hash]) -> blake3::Hash {
+    if cvs.len() == 1 {
+        cvs[0]
+    } else {
+        let left = left_len((cvs.len() * CHUNK_LEN) as u64) as usize / CHUNK_LEN;
+        parent_cv(&merge_cvs(&cvs[..left]), &merge_cvs(&cvs[left..]), false)
+    }
+}
+
+/// Computes the chaining value of the non-root subtree of `len` bytes starting at
+/// `start_chunk`, reading its data from `reader`.
+///
+/// `len` must be a power of two number of chunks, like the left child of any subtree.
+fn hash_subtree_from(
+    reader: &mut impl Read,
+    start_chunk: u64,
+    len: u64,
+) -> io::Result<blake3::Hash> {
+    let block_len = len.min(group_len(DEFAULT_CHUNK_GROUP_LOG));
+    let mut block = vec![0u8; block_len as usize];
+    let mut stack: Vec<blake3::Hash> = Vec::new();
+    for i in 0..len / block_len {
+        reader.read_exact(&mut block)?;
+        let mut cv = hash_subtree(
+            start_chunk + i * block_len / CHUNK_LEN as u64,
+            &block,
+            false,
+        );
+        let mut total = i + 1;
+        while total & 1 == 0 {
+            let left = stack.pop().expect("subtree to merge");
+            cv = parent_cv(&left, &cv, false);
+            total >>= 1;
+        }
+        stack.push(cv);
+    }
+    debug_assert_eq!(stack.len(), 1);
+    Ok(stack.pop().expect("subtree of at least one block"))
+}
+
+/// Computes the outboard of data of unknown length, written to it incrementally.
+#[derive(Debug)]
+pub(crate) struct Encoder {
+    chunk_group_log: u8,
+    /// The data of the current chunk group.
+    group: Vec<u8>,
+    /// The number of completed chunk groups.
+    groups: u64,
+    /// The chaining values of completed subtrees which still need to be merged.
+    stack: Vec<blake3::Hash>,
+    /// The parent nodes in post-order.
+    parents: Vec<[u8; PARENT_LEN]>,
+    len: u64,
+}
+
+impl Encoder {
+    pub(crate) fn new(chunk_group_log: u8) -> Self {
+        Self {
+            chunk_group_log,
+            group: Vec::new(),
+            groups: 0,
+            stack: Vec::new(),
+            parents: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        let group_len = group_len(self.chunk_group_log) as usize;
+        while !data.is_empty() {
+            // the group is only completed once more data arrives, as the last group might
+            // be the root
+            if self.group.len() == group_len {
+                self.push_group();
+            }
+            let n = (group_len - self.group.len()).min(data.len());
+            self.group.extend_from_slice(&data[..n]);
+            self.len += n as u64;
+            data = &data[n..];
+        }
+    }
+
+    fn push_group(&mut self) {
+        let start_chunk = self.groups << self.chunk_group_log;
+        let mut cv = hash_subtree(start_chunk, &self.group, false);
+        self.group.clear();
+        self.groups += 1;
+        // merge all completed subtrees, the same way as BLAKE3 does for chunks
+        let mut total = self.groups;
+        while total & 1 == 0 {
+            let left = self.stack.pop().expect("subtree to merge");
+            cv = self.push_parent(&left, &cv, false);
+            total >>= 1;
+        }
+        self.stack.push(cv);
+    }
+
+    fn push_parent(
+        &mut self,
+        left: &blake3::Hash,
+        right: &blake3::Hash,
+        is_root: bool,
+    ) -> blake3::Hash {
+        let mut parent = [0u8; PARENT_LEN];
+        parent[..32].copy_from_slice(left.as_bytes());
+        parent[32..].copy_from_slice(right.as_bytes());
+        self.parents.push(parent);
+        parent_cv(left, right, is_root)
+    }
+
+    /// Returns the root hash and the outboard.
+    pub(crate) fn finalize(mut self) -> (blake3::Hash, Vec<u8>) {
+        let start_chunk = self.groups << self.chunk_group_log;
+        let group = std::mem::take(&mut self.group);
+        let mut cv = hash_subtree(start_chunk, &group, self.stack.is_empty());
+        while let Some(left) = self.stack.pop() {
+            let is_root = self.stack.is_empty();
+            cv = self.push_parent(&left, &cv, is_root);
+        }
+        let mut outboard =
+            Vec::with_capacity(outboard_size(self.len, self.chunk_group_log) as usize);
+        outboard.extend_from_slice(&self.len.to_le_bytes());
+        post_to_pre_order(&self.parents, self.len, self.chunk_group_log, &mut outboard);
+        (cv, outboard)
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Appends the parent nodes of a subtree of `len` bytes from post-order to pre-order.
+fn post_to_pre_order(post: &[[u8; PARENT_LEN]], len: u64, chunk_group_log: u8, out: &mut Vec<u8>) {
+    if len <= group_len(chunk_group_log) {
+        debug_assert!(post.is_empty());
+        return;
+    }
+    let (parent, children) = post.split_last().expect("parent of subtree");
+    out.extend_from_slice(parent);
+    let left = left_len(len);
+    let left_parents = (count_groups(left, chunk_group_log) - 1) as usize;
+    post_to_pre_order(&children[..left_parents], left, chunk_group_log, out);
+    post_to_pre_order(&children[left_parents..], len - left, chunk_group_log, out);
+}
+
+/// A subtree in the traversal of an encoded slice.
+#[derive(Debug, Clone)]
+struct Node {
+    /// Offset of the subtree in the data.
+    start: u64,
+    len: u64,
+    /// Index of the parent node of this subtree in the pre-order outboard.
+    parent_index: u64,
+    /// The expected chaining value, or root hash.
+    hash: blake3::Hash,
+    is_root: bool,
+}
+
+#[derive(Debug)]
+enum Step {
+    Parent(Node),
+    Group(Node),
+}
+
+/// The pre-order traversal of the parent nodes and chunk groups of an encoded slice.
+#[derive(Debug)]
+struct Traversal {
+    chunk_group_log: u8,
+    /// The range of the data which is verified.
+    start: u64,
+    end: u64,
+    stack: Vec<Node>,
+    /// The subtrees before `start` which were skipped, they are verified by their hash.
+    skipped: Vec<Node>,
+}
+
+impl Traversal {
+    fn new(len: u64, chunk_group_log: u8, hash: blake3::Hash, offset: u64, slice_len: u64) -> Self {
+        // Like bao, a slice which does not overlap the data still verifies the last chunk
+        // group, to authenticate the length.
+        let start = offset.min(len.saturating_sub(1));
+        let end = offset
+            .saturating_add(slice_len)
+            .min(len)
+            .max(start + 1)
+            .min(len);
+        let root = Node {
+            start: 0,
+            len,
+            parent_index: 0,
+            hash,
+            is_root: true,
+        };
+        Self {
+            chunk_group_log,
+            start,
+            end,
+            stack: vec![root],
+            skipped: Vec::new(),
+        }
+    }
+
+    fn next(&mut self) -> Option<Step> {
+        let node = self.stack.pop()?;
+        if node.len <= group_len(self.chunk_group_log) {
+            Some(Step::Group(node))
+        } else {
+            Some(Step::Parent(node))
+        }
+    }
+
+    /// Continues the traversal with the children of a parent node.
+    ///
+    /// Fails if the node does not fit in a `u64`, which can only happen with a corrupt
+    /// length header.
+    fn descend(&mut self, node: &Node, parent: &[u8; PARENT_LEN]) -> io::Result<()> {
+        let left_len = left_len(node.len);
+        let right_start = node
+            .start
+            .checked_add(left_len)
+            .filter(|start| start.checked_add(node.len - left_len).is_some())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "length overflows"))?;
+        let left = Node {
+            start: node.start,
+            len: left_len,
+            parent_index: node.parent_index + 1,
+            hash: blake3::Hash::from(<[u8; 32]>::try_from(&parent[..32]).unwrap()),
+            is_root: false,
+        };
+        let right = Node {
+            start: right_start,
+            len: node.len - left_len,
+            parent_index: left.parent_index + count_groups(left_len, self.chunk_group_log) - 1,
+            hash: blake3::Hash::from(<[u8; 32]>::try_from(&parent[32..]).unwrap()),
+            is_root: false,
+        };
+        if self.overlaps(&right) {
+            self.stack.push(right);
+        }
+        if self.overlaps(&left) {
+            self.stack.push(left);
+        } else if left.start + left.len <= self.start {
+            self.skipped.push(left);
+        }
+        Ok(())
+    }
+
+    /// Whether the node overlaps the verified range, nodes never extend beyond `u64::MAX`.
+    fn overlaps(&self, node: &Node) -> bool {
+        node.start < self.end && self.start < node.start + node.len
+    }
+}
+
+/// Extracts an encoded slice from data and its outboard.
+#[derive(Debug)]
+pub(crate) struct SliceExtractor<D, O> {
+    data: D,
+    outboard: O,
+    chunk_group_log: u8,
+    offset: u64,
+    len: u64,
+    /// `None` until the header is read.
+    traversal: Option<Traversal>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<D: Read + Seek, O: Read + Seek> SliceExtractor<D, O> {
+    pub(crate) fn new(data: D, outboard: O, chunk_group_log: u8, offset: u64, len: u64) -> Self {
+        Self {
+            data,
+            outboard,
+            chunk_group_log,
+            offset,
+            len,
+            traversal: None,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Fills the buffer with the next part of the encoded slice, returns false when done.
+    fn fill_buf(&mut self) -> io::Result<bool> {
+        self.pos = 0;
+        let traversal = match self.traversal {
+            Some(ref mut traversal) => traversal,
+            None => {
+                let mut header = [0u8; HEADER_LEN];
+                self.outboard.seek(SeekFrom::Start(0))?;
+                self.outboard.read_exact(&mut header)?;
+                let len = u64::from_le_bytes(header);
+                // the extractor does not need to know the hash
+                let hash = blake3::Hash::from([0u8; 32]);
+                self.traversal = Some(Traversal::new(
+                    len,
+                    self.chunk_group_log,
+                    hash,
+                    self.offset,
+                    self.len,
+                ));
+                self.buf.clear();
+                self.buf.extend_from_slice(&header);
+                return Ok(true);
+            }
+        };
+        match traversal.next() {
+            None => Ok(false),
+            Some(Step::Parent(node)) => {
+                let mut parent = [0u8; PARENT_LEN];
+                let offset = HEADER_LEN as u64 + node.parent_index * PARENT_LEN as u64;
+                self.outboard.seek(SeekFrom::Start(offset))?;
+                self.outboard.read_exact(&mut parent)?;
+                traversal.descend(&node, &parent)?;
+                self.buf.clear();
+                self.buf.extend_from_slice(&parent);
+                Ok(true)
+            }
+            Some(Step::Group(node)) => {
+                self.buf.resize(node.len as usize, 0);
+                self.data.seek(SeekFrom::Start(node.start))?;
+                self.data.read_exact(&mut self.buf)?;
+                Ok(true)
+            }
+        }
+    }
+}
+
+impl<D: Read + Seek, O: Read + Seek> Read for SliceExtractor<D, O> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            if !self.fill_buf()? {
+                return Ok(0);
+            }
+        }
+        let n = (self.buf.len() - self.pos).min(buf.len());
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Verifies an encoded slice while reading it, yielding only the bytes of the slice.
+#[derive(Debug)]
+pub(crate) struct SliceDecoder<R> {
+    inner: R,
+    hash: blake3::Hash,
+    chunk_group_log: u8,
+    offset: u64,
+    len: u64,
+    header: [u8; HEADER_LEN],
+    /// `None` until the header is read.
+    traversal: Option<Traversal>,
+    /// The step whose encoded bytes are being read into `buf`.
+    current: Option<Step>,
+    buf: Vec<u8>,
+    filled: usize,
+    /// The range of verified bytes in `buf` which still need to be returned.
+    out_pos: usize,
+    out_end: usize,
+    /// The offset and verified bytes before the slice of the first chunk group.
+    first_group: Option<(u64, Vec<u8>)>,
+}
+
+impl<R: AsyncRead + Unpin> SliceDecoder<R> {
+    pub(crate) fn new(
+        inner: R,
+        hash: blake3::Hash,
+        chunk_group_log: u8,
+        offset: u64,
+        len: u64,
+    ) -> Self {
+        Self {
+            inner,
+            hash,
+            chunk_group_log,
+            offset,
+            len,
+            header: [0u8; HEADER_LEN],
+            traversal: None,
+            current: None,
+            buf: Vec::new(),
+            filled: 0,
+            out_pos: 0,
+            out_end: 0,
+            first_group: None,
+        }
+    }
+
+    /// Reads the length of the data from the header.
+    ///
+    /// The length is not verified until the first chunk group is verified.
+    pub(crate) async fn read_size(&mut self) -> io::Result<u64> {
+        futures::future::poll_fn(|cx| self.poll_header(cx)).await
+    }
+
+    /// Reads up to the first chunk group of the slice and returns what authenticates the
+    /// data before the slice.
+    ///
+    /// Reading the slice afterwards still yields all of its bytes.
+    pub(crate) async fn read_prefix(&mut self) -> io::Result<Prefix> {
+        futures::future::poll_fn(|cx| {
+            while self.first_group.is_none() {
+                if !ready!(self.poll_next_step(cx))? {
+                    break;
+                }
+            }
+            Poll::Ready(Ok::<_, io::Error>(()))
+        })
+        .await?;
+        let subtrees = match self.traversal {
+            Some(ref traversal) => traversal
+                .skipped
+                .iter()
+                .map(|node| (node.start, node.len, node.hash))
+                .collect(),
+            None => Vec::new(),
+        };
+        let (group_start, group) = self.first_group.clone().unwrap_or_default();
+        Ok(Prefix {
+            subtrees,
+            group_start,
+            group,
+        })
+    }
+
+    pub(crate) fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn poll_header(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        if self.traversal.is_some() {
+            // `filled` is reused for the steps once the header is read.
+            return Poll::Ready(Ok(u64::from_le_bytes(self.header)));
+        }
+        while self.filled < HEADER_LEN {
+            let mut buf = ReadBuf::new(&mut self.header[self.filled..]);
+            ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf))?;
+            if buf.filled().is_empty() {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.filled += buf.filled().len();
+        }
+        let len = u64::from_le_bytes(self.header);
+        self.traversal = Some(Traversal::new(
+            len,
+            self.chunk_group_log,
+            self.hash,
+            self.offset,
+            self.len,
+        ));
+        self.filled = 0;
+        Poll::Ready(Ok(len))
+    }
+
+    /// Reads and verifies the next parent node or chunk group, returns false when done.
+    fn poll_next_step(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        if self.traversal.is_none() {
+            ready!(self.poll_header(cx))?;
+        }
+        let traversal = self.traversal.as_mut().expect("header is read");
+        if self.current.is_none() {
+            let step = match traversal.next() {
+                Some(step) => step,
+                None => return Poll::Ready(Ok(false)),
+            };
+            let len = match step {
+                Step::Parent(_) => PARENT_LEN,
+                Step::Group(ref node) => node.len as usize,
+            };
+            self.current = Some(step);
+            self.buf.resize(len, 0);
+            self.filled = 0;
+        }
+        while self.filled < self.buf.len() {
+            let mut buf = ReadBuf::new(&mut self.buf[self.filled..]);
+            ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf))?;
+            if buf.filled().is_empty() {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.filled += buf.filled().len();
+        }
+        let hash_mismatch = || io::Error::new(io::ErrorKind::InvalidData, "hash mismatch");
+        match self.current.take().expect("step being read") {
+            Step::Parent(node) => {
+                let parent: &[u8; PARENT_LEN] = self.buf[..].try_into().unwrap();
+                let left = blake3::Hash::from(<[u8; 32]>::try_from(&parent[..32]).unwrap());
+                let right = blake3::Hash::from(<[u8; 32]>::try_from(&parent[32..]).unwrap());
+                if parent_cv(&left, &right, node.is_root) != node.hash {
+                    return Poll::Ready(Err(hash_mismatch()));
+                }
+                if let Err(err) = traversal.descend(&node, parent) {
+                    return Poll::Ready(Err(err));
+                }
+            }
+            Step::Group(node) => {
+                let start_chunk = node.start / CHUNK_LEN as u64;
+                if hash_subtree(start_chunk, &self.buf, node.is_root) != node.hash {
+                    return Poll::Ready(Err(hash_mismatch()));
+                }
+                let slice_start = self.offset;
+                let slice_end = self.offset.saturating_add(self.len);
+                let node_end = node.start + node.len;
+                self.out_pos = (slice_start.clamp(node.start, node_end) - node.start) as usize;
+                self.out_end = (slice_end.clamp(node.start, node_end) - node.start) as usize;
+                if self.first_group.is_none() {
+                    self.first_group = Some((node.start, self.buf[..self.out_pos].to_vec()));
+                }
+            }
+        }
+        Poll::Ready(Ok(true))
+    }
+}
+
+/// The data before the start of a slice, as authenticated by the encoded slice.
+///
+/// The encoded slice contains the hashes of the subtrees before the slice, and the chunk
+/// group containing the start of the slice is sent and verified in full.
+#[derive(Debug)]
+pub(crate) struct Prefix {
+    /// The offset, length and chaining value of the subtrees before the first chunk group.
+    subtrees: Vec<(u64, u64, blake3::Hash)>,
+    group_start: u64,
+    /// The bytes of the first chunk group before the slice.
+    group: Vec<u8>,
+}
+
+impl Prefix {
+    /// Checks whether `data` starts with the data before the slice.
+    pub(crate) fn verify(&self, mut data: impl Read + Seek) -> io::Result<bool> {
+        let res = (|| -> io::Result<bool> {
+            for &(start, len, hash) in &self.subtrees {
+                data.seek(SeekFrom::Start(start))?;
+                if hash_subtree_from(&mut data, start / CHUNK_LEN as u64, len)? != hash {
+                    return Ok(false);
+                }
+            }
+            let mut group = vec![0u8; self.group.len()];
+            data.seek(SeekFrom::Start(self.group_start))?;
+            data.read_exact(&mut group)?;
+            Ok(group == self.group)
+        })();
+        match res {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            res => res,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for SliceDecoder<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.out_pos == this.out_end {
+            if !ready!(this.poll_next_step(cx))? {
+                return Poll::Ready(Ok(()));
+            }
+        }
+        let n = (this.out_end - this.out_pos).min(buf.remaining());
+        buf.put_slice(&this.buf[this.out_pos..this.out_pos + n]);
+        this.out_pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rand::RngCore;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    const SIZES: &[usize] = &[0, 1, 1023, 1024, 1025, 4096, 5000, 16 * 1024 + 1, 100_000];
+
+    fn encode(data: &[u8], chunk_group_log: u8) -> (blake3::Hash, Vec<u8>) {
+        let mut encoder = Encoder::new(chunk_group_log);
+        // write in odd sized pieces to exercise the group boundaries
+        for piece in data.chunks(777) {
+            encoder.update(piece);
+        }
+        encoder.finalize()
+    }
+
+    fn extract(
+        data: &[u8],
+        outboard: &[u8],
+        chunk_group_log: u8,
+        offset: u64,
+        len: u64,
+    ) -> Vec<u8> {
+        let mut extractor = SliceExtractor::new(
+            Cursor::new(data),
+            Cursor::new(outboard),
+            chunk_group_log,
+            offset,
+            len,
+        );
+        let mut encoded = Vec::new();
+        extractor.read_to_end(&mut encoded).unwrap();
+        encoded
+    }
+
+    async fn decode(
+        encoded: &[u8],
+        hash: blake3::Hash,
+        chunk_group_log: u8,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<Vec<u8>> {
+        let mut decoder = SliceDecoder::new(encoded, hash, chunk_group_log, offset, len);
+        // reading the size again does not advance the stream
+        let size = decoder.read_size().await?;
+        assert_eq!(decoder.read_size().await?, size);
+        let mut decoded = Vec::new();
+        decoder.read_to_end(&mut decoded).await?;
+        Ok(decoded)
+    }
+
+    fn random_data(size: usize) -> Vec<u8> {
+        let mut data = vec![0u8; size];
+        rand::thread_rng().fill_bytes(&mut data);
+        data
+    }
+
+    #[test]
+    fn test_hash_subtree_matches_chunk_state() {
+        // computes the chaining value one chunk at a time, without SIMD
+        fn reference(start_chunk: u64, data: &[u8]) -> blake3::Hash {
+            if data.len() <= CHUNK_LEN {
+                ChunkState::new(start_chunk).update(data).finalize(false)
+            } else {
+                let left = left_len(data.len() as u64) as usize;
+                let right_chunk = start_chunk + (left / CHUNK_LEN) as u64;
+                parent_cv(
+                    &reference(start_chunk, &data[..left]),
+                    &reference(right_chunk, &data[left..]),
+                    false,
+                )
+            }
+        }
+
+        for &size in SIZES {
+            let data = random_data(size);
+            for start_chunk in [0, 16, 1 << 20] {
+                assert_eq!(
+                    hash_subtree(start_chunk, &data, false),
+                    reference(start_chunk, &data),
+                    "size {size}, start chunk {start_chunk}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_encoder_matches_blake3_and_bao() {
+        for &size in SIZES {
+            let data = random_data(size);
+            let (hash, outboard) = encode(&data, 0);
+            assert_eq!(hash, blake3::hash(&data), "size {size}");
+            // without chunk groups this is the bao outboard
+            let (bao_outboard, bao_hash) = abao::encode::outboard(&data);
+            assert_eq!(hash, bao_hash, "size {size}");
+            assert_eq!(outboard, bao_outboard, "size {size}");
+
+            for chunk_group_log in [1, 4] {
+                let (hash, outboard) = encode(&data, chunk_group_log);
+                assert_eq!(hash, blake3::hash(&data), "size {size}");
+                assert_eq!(
+                    outboard.len() as u64,
+                    outboard_size(size as u64, chunk_group_log)
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slice_roundtrip() {
+        for &size in SIZES {
+            let data = random_data(size);
+            for chunk_group_log in [0, 2, 4] {
+                let (hash, outboard) = encode(&data, chunk_group_log);
+                let size = size as u64;
+                let ranges = [
+                    (0, u64::MAX),
+                    (0, 1),
+                    (size / 2, 3000),
+                    (size.saturating_sub(1), 1),
+                    (size, 10),
+                    (size + 5000, 10),
+                ];
+                for (offset, len) in ranges {
+                    let encoded = extract(&data, &outboard, chunk_group_log, offset, len);
+                    let decoded = decode(&encoded, hash, chunk_group_log, offset, len)
+                        .await
+                        .unwrap();
+                    let start = offset.min(size) as usize;
+                    let end = offset.saturating_add(len).min(size) as usize;
+                    assert_eq!(
+                        decoded,
+                        &data[start..end],
+                        "size {size} range {offset}+{len}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slice_smaller_with_chunk_groups() {
+        let data = random_data(100_000);
+        let (_, outboard0) = encode(&data, 0);
+        let (_, outboard4) = encode(&data, 4);
+        assert!(outboard4.len() * 8 < outboard0.len());
+        // a small slice with chunk groups contains whole groups, but fewer parents
+        let encoded0 = extract(&data, &outboard0, 0, 50_000, 10);
+        let encoded4 = extract(&data, &outboard4, 4, 50_000, 10);
+        assert!(encoded4.len() > encoded0.len());
+        assert!(encoded4.len() < 2 * 16 * 1024);
+    }
+
+    #[tokio::test]
+    async fn test_slice_verifies_prefix() {
+        let size = 100_000;
+        let data = random_data(size);
+        for chunk_group_log in [0, 2] {
+            let (hash, outboard) = encode(&data, chunk_group_log);
+            for offset in [0, 1, 1024, 5000, 50_000, size as u64] {
+                let encoded = extract(&data, &outboard, chunk_group_log, offset, u64::MAX);
+                let mut decoder =
+                    SliceDecoder::new(&encoded[..], hash, chunk_group_log, offset, u64::MAX);
+                let prefix = decoder.read_prefix().await.unwrap();
+                let offset = offset as usize;
+                assert!(prefix.verify(Cursor::new(&data[..offset])).unwrap());
+                if offset > 0 {
+                    let mut corrupted = data[..offset].to_vec();
+                    corrupted[offset / 3] ^= 1;
+                    assert!(!prefix.verify(Cursor::new(&corrupted)).unwrap());
+                    assert!(!prefix.verify(Cursor::new(&data[..offset - 1])).unwrap());
+                }
+                // the slice is still yielded in full
+                let mut decoded = Vec::new();
+                decoder.read_to_end(&mut decoded).await.unwrap();
+                assert_eq!(decoded, &data[offset..]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slice_huge_length() {
+        assert_eq!(count_groups(u64::MAX, 0), 1 << 54);
+        assert_eq!(left_len(u64::MAX), 1 << 63);
+        assert_eq!(
+            outboard_size(u64::MAX, 0),
+            HEADER_LEN as u64 + PARENT_LEN as u64 * ((1 << 54) - 1)
+        );
+
+        // A header of `u64::MAX` with parents matching the hash, down to the first chunk.
+        let cv = |byte: u8| blake3::Hash::from([byte; 32]);
+        let mut parents = Vec::new();
+        let mut left = cv(0);
+        for _ in 11..64 {
+            parents.push((left, cv(1)));
+            left = parent_cv(&left, &cv(1), false);
+        }
+        parents.push((left, cv(2)));
+        let hash = parent_cv(&left, &cv(2), true);
+        let mut encoded = u64::MAX.to_le_bytes().to_vec();
+        for (left, right) in parents.iter().rev() {
+            encoded.extend_from_slice(left.as_bytes());
+            encoded.extend_from_slice(right.as_bytes());
+        }
+        encoded.extend_from_slice(&[0u8; CHUNK_LEN]);
+        let err = decode(&encoded, hash, 0, 0, 1).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = decode(&encoded, cv(3), 0, 0, 1).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_slice_detects_corruption() {
+        let data = random_data(100_000);
+        for chunk_group_log in [0, 4] {
+            let (hash, outboard) = encode(&data, chunk_group_log);
+            let encoded = extract(&data, &outboard, chunk_group_log, 0, u64::MAX);
+            for pos in [
+                HEADER_LEN,
+                HEADER_LEN + 70,
+                encoded.len() / 2,
+                encoded.len() - 1,
+            ] {
+                let mut corrupted = encoded.clone();
+                corrupted[pos] ^= 1;
+                let err = decode(&corrupted, hash, chunk_group_log, 0, u64::MAX)
+                    .await
+                    .unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData, "pos {pos}");
+            }
+            // decoding with the wrong chunk group log fails as well
+            let other = if chunk_group_log == 0 { 4 } else { 0 };
+            assert!(decode(&encoded, hash, other, 0, u64::MAX).await.is_err());
+        }
+    }
+}
@@ -1,4 +1,7 @@
 //! Types for blobs and collections of blobs
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 
@@ -38,14 +41,260 @@ impl Collection {
     pub fn total_entries(&self) -> u64 {
         self.blobs.len() as u64
     }
+
+    /// The entries of this collection
+    pub fn blobs(&self) -> &[Blob] {
+        &self.blobs
+    }
+
+    /// Validates and normalizes the names of all entries, see [`normalize_name`].
+    ///
+    /// Additionally rejects duplicate names, symlinks whose target is outside of the
+    /// collection and entries below a symlink entry of this collection, wherever the symlink
+    /// is listed, as writing them would follow the symlink.  Symlink targets passing through
+    /// another symlink of the collection are rejected as well, since following it changes
+    /// where the rest of the target is resolved.
+    pub(crate) fn normalize_names(&mut self) -> Result<(), InvalidName> {
+        let mut names = HashSet::new();
+        let mut symlinks = HashMap::new();
+        for blob in self.blobs.iter_mut() {
+            blob.name = normalize_name(&blob.name)?;
+            if !names.insert(blob.name.clone()) {
+                return Err(InvalidName::Duplicate(blob.name.clone()));
+            }
+            if let Metadata::V1(MetadataV1 {
+                kind: EntryKind::Symlink(ref target),
+                ..
+            }) = blob.metadata
+            {
+                let resolved = resolve_symlink(&blob.name, target)
+                    .ok_or_else(|| InvalidName::SymlinkTarget(blob.name.clone()))?;
+                symlinks.insert(blob.name.clone(), resolved);
+            }
+        }
+        for blob in &self.blobs {
+            if ancestors(&blob.name).any(|parent| symlinks.contains_key(parent)) {
+                return Err(InvalidName::BelowSymlink(blob.name.clone()));
+            }
+        }
+        for (name, resolved) in &symlinks {
+            if ancestors(resolved).any(|parent| symlinks.contains_key(parent)) {
+                return Err(InvalidName::SymlinkTarget(name.clone()));
+            }
+        }
+        Ok(())
+    }
 }
 
+/// An entry in a [`Collection`]
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
-pub(crate) struct Blob {
+pub struct Blob {
     /// The name of this blob of data
     pub(crate) name: String,
     /// The hash of the blob of data
     pub(crate) hash: Hash,
+    /// Metadata of the file this blob was created from
+    pub(crate) metadata: Metadata,
+}
+
+impl Blob {
+    /// The name of this entry, a relative path using `/` as separator
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The hash of the data of this entry
+    pub fn hash(&self) -> Hash {
+        self.hash
+    }
+
+    /// The metadata of this entry
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Whether this entry has data which is transferred
+    ///
+    /// Directories and symlinks have no data, they are fully described by their metadata.
+    pub fn has_data(&self) -> bool {
+        match &self.metadata {
+            Metadata::None => true,
+            Metadata::V1(meta) => meta.kind == EntryKind::File,
+        }
+    }
+}
+
+/// Metadata of a collection entry
+///
+/// This is versioned so new fields can be added without breaking the decoding of existing
+/// collections.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Metadata {
+    /// No metadata is known, the entry is a regular file
+    #[default]
+    None,
+    /// Version 1 of the metadata
+    V1(MetadataV1),
+}
+
+/// Version 1 of the metadata of a collection entry
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetadataV1 {
+    /// The kind of the entry
+    pub kind: EntryKind,
+    /// The unix permission bits, if known
+    pub mode: Option<u32>,
+    /// The modification time in seconds since the unix epoch, if known
+    pub mtime: Option<u64>,
+}
+
+/// The kind of a collection entry
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryKind {
+    /// A regular file, its content is the data of the blob
+    File,
+    /// A directory
+    Directory,
+    /// A symbolic link to the given target
+    Symlink(String),
+}
+
+/// Error for an entry name which is not a safe relative path.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum InvalidName {
+    /// The name has no components.
+    #[error("name is empty")]
+    Empty,
+    /// The name is an absolute path.
+    #[error("absolute path: {0:?}")]
+    Absolute(String),
+    /// The name contains a `..` component.
+    #[error("parent directory component: {0:?}")]
+    ParentDir(String),
+    /// The name contains a backslash, which is a separator on some platforms.
+    #[error("backslash in name: {0:?}")]
+    Backslash(String),
+    /// The name contains a NUL byte.
+    #[error("NUL byte in name: {0:?}")]
+    NulByte(String),
+    /// The name contains a reserved device name, like `CON` or `NUL`.
+    #[error("reserved device name: {0:?}")]
+    DeviceName(String),
+    /// The name is below a symlink of the collection.
+    #[error("below a symlink: {0:?}")]
+    BelowSymlink(String),
+    /// The name is used by more than one entry.
+    #[error("duplicate name: {0:?}")]
+    Duplicate(String),
+    /// The symlink with this name points outside of the collection.
+    #[error("symlink target outside of the collection: {0:?}")]
+    SymlinkTarget(String),
+}
+
+/// Device names reserved on windows, with or without extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Validates an entry name received from a provider and returns it normalized.
+///
+/// Names are relative paths using `/` as the separator.  Empty and `.` components are
+/// removed.  Absolute paths, `..` components, backslashes, NUL bytes and reserved device
+/// names are rejected, on all platforms, so a name can never refer to a path outside the
+/// directory it is written to.  The empty name is returned unchanged, it marks a blob
+/// without a name.
+pub fn normalize_name(name: &str) -> Result<String, InvalidName> {
+    if name.is_empty() {
+        return Ok(String::new());
+    }
+    let invalid = |f: fn(String) -> InvalidName| Err(f(name.to_string()));
+    if name.contains('\0') {
+        return invalid(InvalidName::NulByte);
+    }
+    if name.contains('\\') {
+        return invalid(InvalidName::Backslash);
+    }
+    if name.starts_with('/') {
+        return invalid(InvalidName::Absolute);
+    }
+    let mut components = Vec::new();
+    for component in name.split('/') {
+        match component {
+            "" | "." => continue,
+            ".." => return invalid(InvalidName::ParentDir),
+            _ => {}
+        }
+        // a drive prefix like `C:` makes the path absolute on windows
+        if components.is_empty() && component.len() >= 2 && component.as_bytes()[1] == b':' {
+            return invalid(InvalidName::Absolute);
+        }
+        let stem = component.split('.').next().unwrap_or_default().trim_end();
+        if RESERVED_NAMES
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+        {
+            return invalid(InvalidName::DeviceName);
+        }
+        components.push(component);
+    }
+    if components.is_empty() {
+        return Err(InvalidName::Empty);
+    }
+    Ok(components.join("/"))
+}
+
+/// Resolves the target of the symlink with the normalized `name` to a normalized name
+/// relative to the directory the collection is written to.
+///
+/// Returns `None` if the target is not contained in that directory: absolute targets, and
+/// relative targets whose `..` components leave it.  A `..` following a regular component
+/// is rejected too, as that component might itself be a symlink.  Backslashes and NUL
+/// bytes are rejected like in names.
+fn resolve_symlink(name: &str, target: &str) -> Option<String> {
+    if target.starts_with('/') || target.contains('\\') || target.contains('\0') {
+        return None;
+    }
+    // the symlink is resolved relative to the directory containing it
+    let mut components: Vec<&str> = name.split('/').collect();
+    components.pop();
+    let mut descended = false;
+    for (i, component) in target.split('/').enumerate() {
+        match component {
+            "" | "." => {}
+            ".." if descended => return None,
+            ".." => {
+                components.pop()?;
+            }
+            // a drive prefix like `C:` makes the path absolute on windows
+            _ if i == 0 && component.len() >= 2 && component.as_bytes()[1] == b':' => return None,
+            _ => {
+                descended = true;
+                components.push(component);
+            }
+        }
+    }
+    Some(components.join("/"))
+}
+
+/// The proper ancestors of the normalized `name`, from the closest to the root.
+fn ancestors(name: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(name.rsplit_once('/').map(|(parent, _)| parent), |parent| {
+        parent.rsplit_once('/').map(|(parent, _)| parent)
+    })
+}
+
+/// Returns the path of the entry with the given name below `root`.
+///
+/// The name is validated using [`normalize_name`], the empty name is rejected.
+pub fn entry_path(root: impl AsRef<Path>, name: &str) -> Result<PathBuf, InvalidName> {
+    let name = normalize_name(name)?;
+    if name.is_empty() {
+        return Err(InvalidName::Empty);
+    }
+    Ok(name
+        .split('/')
+        .fold(root.as_ref().to_path_buf(), |path, part| path.join(part)))
 }
 
 #[cfg(test)]
@@ -61,6 +310,11 @@ mod tests {
             )
             .unwrap()
             .into(),
+            metadata: Metadata::V1(MetadataV1 {
+                kind: EntryKind::Symlink("target".to_string()),
+                mode: Some(0o755),
+                mtime: Some(1_680_000_000),
+            }),
         };
 
         let mut buf = bytes::BytesMut::zeroed(1024);
@@ -68,4 +322,126 @@ mod tests {
         let deserialize_b: Blob = postcard::from_bytes(&buf).unwrap();
         assert_eq!(b, deserialize_b);
     }
+
+    #[test]
+    fn test_normalize_name() {
+        assert_eq!(normalize_name("foo").unwrap(), "foo");
+        assert_eq!(normalize_name("./a//b/./c/").unwrap(), "a/b/c");
+        assert_eq!(normalize_name("").unwrap(), "");
+        assert_eq!(normalize_name("a..b/.c").unwrap(), "a..b/.c");
+        assert_eq!(normalize_name("console.txt").unwrap(), "console.txt");
+
+        let err = |name: &str| normalize_name(name).unwrap_err();
+        assert_eq!(err("./"), InvalidName::Empty);
+        assert_eq!(
+            err("/etc/passwd"),
+            InvalidName::Absolute("/etc/passwd".into())
+        );
+        assert_eq!(err("C:/foo"), InvalidName::Absolute("C:/foo".into()));
+        assert_eq!(
+            err("../../.bashrc"),
+            InvalidName::ParentDir("../../.bashrc".into())
+        );
+        assert_eq!(err("a/../../b"), InvalidName::ParentDir("a/../../b".into()));
+        assert_eq!(err("..\\x"), InvalidName::Backslash("..\\x".into()));
+        assert_eq!(err("a\0b"), InvalidName::NulByte("a\0b".into()));
+        assert_eq!(
+            err("a/nul.txt"),
+            InvalidName::DeviceName("a/nul.txt".into())
+        );
+        assert_eq!(err("COM1"), InvalidName::DeviceName("COM1".into()));
+    }
+
+    #[test]
+    fn test_entry_path() {
+        let root = Path::new("out");
+        assert_eq!(entry_path(root, "a//b").unwrap(), root.join("a").join("b"));
+        assert_eq!(entry_path(root, "").unwrap_err(), InvalidName::Empty);
+    }
+
+    #[test]
+    fn test_normalize_collection_names() {
+        let hash = Hash::new([]);
+        let blob = |name: &str, kind| Blob {
+            name: name.to_string(),
+            hash,
+            metadata: Metadata::V1(MetadataV1 {
+                kind,
+                mode: None,
+                mtime: None,
+            }),
+        };
+        let mut collection = Collection {
+            name: "collection".to_string(),
+            blobs: vec![
+                blob("./a", EntryKind::Directory),
+                blob("a//b", EntryKind::File),
+                blob("link", EntryKind::Symlink("a/./b".to_string())),
+                blob("a/up", EntryKind::Symlink("../link".to_string())),
+            ],
+            total_blobs_size: 0,
+        };
+        collection.normalize_names().unwrap();
+        let names: Vec<_> = collection.blobs().iter().map(|b| b.name()).collect();
+        assert_eq!(names, vec!["a", "a/b", "link", "a/up"]);
+
+        let mut below = collection.clone();
+        below.blobs.push(blob("link/passwd", EntryKind::File));
+        assert_eq!(
+            below.normalize_names().unwrap_err(),
+            InvalidName::BelowSymlink("link/passwd".to_string())
+        );
+
+        // the symlink may be listed after the entries below it
+        let mut below = collection.clone();
+        below.blobs.insert(0, blob("link/passwd", EntryKind::File));
+        assert_eq!(
+            below.normalize_names().unwrap_err(),
+            InvalidName::BelowSymlink("link/passwd".to_string())
+        );
+
+        let mut duplicate = collection.clone();
+        duplicate.blobs.push(blob("./a/b", EntryKind::File));
+        assert_eq!(
+            duplicate.normalize_names().unwrap_err(),
+            InvalidName::Duplicate("a/b".to_string())
+        );
+
+        for target in ["/etc", "..", "a/../../etc", "C:/Windows", "..\\x", "a/../b"] {
+            let mut escaping = collection.clone();
+            escaping
+                .blobs
+                .push(blob("escape", EntryKind::Symlink(target.to_string())));
+            assert_eq!(
+                escaping.normalize_names().unwrap_err(),
+                InvalidName::SymlinkTarget("escape".to_string()),
+                "target {target}"
+            );
+        }
+
+        // a chain through another symlink of the collection, wherever it is listed
+        for chain in ["d/l2/x", "./d//l2/y/z"] {
+            let mut chained = collection.clone();
+            chained.blobs.extend([
+                blob("s", EntryKind::Symlink(chain.to_string())),
+                blob("d", EntryKind::Directory),
+                blob("d/l2", EntryKind::Symlink("..".to_string())),
+            ]);
+            assert_eq!(
+                chained.normalize_names().unwrap_err(),
+                InvalidName::SymlinkTarget("s".to_string()),
+                "target {chain}"
+            );
+        }
+        let mut chained = collection.clone();
+        chained.blobs.extend([
+            blob("d", EntryKind::Directory),
+            blob("d/l2", EntryKind::Symlink("..".to_string())),
+            blob("s", EntryKind::Symlink("d/l2/../x".to_string())),
+        ]);
+        assert_eq!(
+            chained.normalize_names().unwrap_err(),
+            InvalidName::SymlinkTarget("s".to_string())
+        );
+    }
 }
@@ -0,0 +1,200 @@
+//! Capabilities to get content from a provider, signed by the provider.
+//!
+//! A [`SignedCapability`] can be used instead of an [`AuthToken`](crate::protocol::AuthToken)
+//! to authenticate to a provider.  It lists the hashes which may be requested and can
+//! expire or be limited to a single getter.  The provider verifies its own signature, so it
+//! does not need to remember the capabilities it handed out.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::tls::{Keypair, PeerId, PublicKey, Signature};
+use crate::util::{self, Hash};
+
+/// Prefix of the signed message, so a capability signature can not be mistaken for a
+/// signature of something else.
+const SIGNATURE_CONTEXT: &[u8] = b"sendme capability v1";
+
+/// What a getter is allowed to get from a provider.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capability {
+    /// The hashes which may be requested.
+    ///
+    /// Access to a collection includes its blobs as part of the collection, but they can
+    /// not be requested individually unless they are listed as well.
+    pub hashes: Vec<Hash>,
+    /// When the capability expires, in seconds since the unix epoch.  `None` never expires.
+    pub expires: Option<u64>,
+    /// The only getter allowed to use the capability.  `None` allows anyone.
+    pub getter: Option<PeerId>,
+}
+
+impl Capability {
+    /// Creates a capability to get the given hashes, which does not expire.
+    pub fn new(hashes: impl IntoIterator<Item = Hash>) -> Self {
+        Self {
+            hashes: hashes.into_iter().collect(),
+            expires: None,
+            getter: None,
+        }
+    }
+
+    /// Expires the capability at the given time.
+    pub fn expires_at(mut self, time: SystemTime) -> Self {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        self.expires = Some(secs);
+        self
+    }
+
+    /// Expires the capability once the given duration elapsed from now.
+    pub fn expires_in(self, duration: Duration) -> Self {
+        self.expires_at(SystemTime::now() + duration)
+    }
+
+    /// Limits the capability to the getter with the given [`PeerId`].
+    pub fn getter(mut self, getter: PeerId) -> Self {
+        self.getter = Some(getter);
+        self
+    }
+
+    /// Signs the capability with the keypair of the provider.
+    pub fn sign(self, keypair: &Keypair) -> SignedCapability {
+        let signature = keypair.sign(&self.signing_message());
+        SignedCapability {
+            capability: self,
+            signature,
+        }
+    }
+
+    fn signing_message(&self) -> Vec<u8> {
+        let mut message = SIGNATURE_CONTEXT.to_vec();
+        message.extend(postcard::to_stdvec(self).expect("postcard::to_stdvec is infallible"));
+        message
+    }
+}
+
+/// A [`Capability`] signed by a provider.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedCapability {
+    capability: Capability,
+    signature: Signature,
+}
+
+impl SignedCapability {
+    /// The signed capability.
+    pub fn capability(&self) -> &Capability {
+        &self.capability
+    }
+
+    /// Verifies the capability can be used by `getter` on the provider with the key
+    /// `provider`.
+    pub fn verify(
+        &self,
+        provider: &PublicKey,
+        getter: Option<&PeerId>,
+    ) -> Result<(), CapabilityError> {
+        use ed25519_dalek::Verifier;
+
+        provider
+            .verify(&self.capability.signing_message(), &self.signature)
+            .map_err(|_| CapabilityError::InvalidSignature)?;
+        if let Some(expires) = self.capability.expires {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default();
+            if expires <= now {
+                return Err(CapabilityError::Expired);
+            }
+        }
+        if let Some(ref allowed) = self.capability.getter {
+            if getter != Some(allowed) {
+                return Err(CapabilityError::WrongGetter);
+            }
+        }
+        Ok(())
+    }
+
+    /// An identifier of the capability, which can be logged without revealing it.
+    pub fn id(&self) -> String {
+        util::encode(&blake3::hash(&self.signature.to_bytes()).as_bytes()[..8])
+    }
+
+    /// Whether the capability allows requesting `hash`.
+    pub fn allows(&self, hash: &Hash) -> bool {
+        self.capability.hashes.contains(hash)
+    }
+}
+
+/// Why a [`SignedCapability`] was not accepted.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapabilityError {
+    /// The capability was not signed by the provider.
+    #[error("invalid signature")]
+    InvalidSignature,
+    /// The capability expired.
+    #[error("capability expired")]
+    Expired,
+    /// The capability is limited to another getter.
+    #[error("capability is for another getter")]
+    WrongGetter,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capability_verify() {
+        let provider = Keypair::generate();
+        let getter: PeerId = Keypair::generate().public().into();
+        let hash = Hash::new(b"hello");
+
+        let signed = Capability::new([hash]).sign(&provider);
+        assert_eq!(signed.verify(&provider.public(), None), Ok(()));
+        assert!(signed.allows(&hash));
+        assert!(!signed.allows(&Hash::new(b"other")));
+
+        // Only the signing provider is accepted.
+        let other = Keypair::generate();
+        assert_eq!(
+            signed.verify(&other.public(), None),
+            Err(CapabilityError::InvalidSignature)
+        );
+
+        // The capability can not be altered.
+        let mut altered = signed.clone();
+        altered.capability.hashes.push(Hash::new(b"other"));
+        assert_eq!(
+            altered.verify(&provider.public(), None),
+            Err(CapabilityError::InvalidSignature)
+        );
+
+        let expired = Capability::new([hash])
+            .expires_at(SystemTime::now())
+            .sign(&provider);
+        assert_eq!(
+            expired.verify(&provider.public(), None),
+            Err(CapabilityError::Expired)
+        );
+        let expiring = Capability::new([hash])
+            .expires_in(Duration::from_secs(60))
+            .sign(&provider);
+        assert_eq!(expiring.verify(&provider.public(), None), Ok(()));
+
+        let bound = Capability::new([hash]).getter(getter).sign(&provider);
+        assert_eq!(bound.verify(&provider.public(), Some(&getter)), Ok(()));
+        let other_getter: PeerId = other.public().into();
+        assert_eq!(
+            bound.verify(&provider.public(), Some(&other_getter)),
+            Err(CapabilityError::WrongGetter)
+        );
+        assert_eq!(
+            bound.verify(&provider.public(), None),
+            Err(CapabilityError::WrongGetter)
+        );
+    }
+}
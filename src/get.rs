@@ -3,28 +3,34 @@
 //! The main entry point is [`run`]. This function takes callbacks that will
 //! be invoked when blobs or collections are received. It is up to the caller
 //! to store the received data.
+//!
+//! The requested hash can either refer to a collection or to a single blob.
 use std::fmt::Debug;
 use std::io;
 use std::net::SocketAddr;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use crate::bao::{SliceDecoder, MAX_CHUNK_GROUP_LOG};
 use crate::blobs::Collection;
 use crate::protocol::{
-    read_bao_encoded, read_lp_data, write_lp, AuthToken, Handshake, Request, Res, Response,
+    read_bao_encoded, read_lp_data, write_lp, Auth, Handshake, LimitExceeded, RangeSpec, Request,
+    Res, Response, DEFAULT_MAX_MESSAGE_SIZE,
 };
 use crate::tls::{self, Keypair, PeerId};
-use abao::decode::AsyncSliceDecoder;
 use anyhow::{anyhow, bail, ensure, Result};
 use bytes::BytesMut;
 use futures::Future;
-use postcard::experimental::max_size::MaxSize;
+use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
 use tracing::{debug, error};
 
 pub use crate::util::Hash;
 
-const MAX_DATA_SIZE: u64 = 1024 * 1024 * 1024;
+/// The size of the data beyond which a blob or collection is considered corrupt, even if
+/// [`Limits::max_data_size`] is not set.
+const MAX_DATA_SIZE: u64 = 1 << 50;
 
 /// Options for the client
 #[derive(Clone, Debug)]
@@ -35,6 +41,11 @@ pub struct Options {
     pub peer_id: Option<PeerId>,
     /// Whether to log the SSL keys when `SSLKEYLOGFILE` environment variable is set.
     pub keylog: bool,
+    /// The keypair identifying the getter, a new one is generated for every connection
+    /// if `None`
+    pub keypair: Option<Arc<Keypair>>,
+    /// Limits on the data accepted from the provider
+    pub limits: Limits,
 }
 
 impl Default for Options {
@@ -43,13 +54,59 @@ impl Default for Options {
             addr: "127.0.0.1:4433".parse().unwrap(),
             peer_id: None,
             keylog: false,
+            keypair: None,
+            limits: Limits::default(),
+        }
+    }
+}
+
+/// Limits on the data accepted from the provider
+///
+/// Exceeding a limit fails the transfer with a [`LimitExceeded`] error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// The maximum size of a response message, defaults to 100MiB.
+    pub max_message_size: u64,
+    /// The maximum size of a collection, which is kept in memory, defaults to 100MiB.
+    ///
+    /// This limits the encoded list of blobs, not the data of the blobs.
+    pub max_collection_size: u64,
+    /// The maximum size of the data of a blob or all blobs of a collection, unlimited by
+    /// default.
+    ///
+    /// Sizes beyond 1PiB are always rejected, they only occur with corrupt responses.
+    pub max_data_size: Option<u64>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_collection_size: 100 * 1024 * 1024,
+            max_data_size: None,
         }
     }
 }
 
+impl Limits {
+    fn check_data_size(&self, size: u64) -> Result<(), LimitExceeded> {
+        let max = self
+            .max_data_size
+            .unwrap_or(MAX_DATA_SIZE)
+            .min(MAX_DATA_SIZE);
+        if size > max {
+            return Err(LimitExceeded::DataSize { size, max });
+        }
+        Ok(())
+    }
+}
+
 /// Setup a QUIC connection to the provided address.
 async fn setup(opts: Options) -> Result<quinn::Connection> {
-    let keypair = Keypair::generate();
+    let keypair = match opts.keypair {
+        Some(keypair) => keypair,
+        None => Arc::new(Keypair::generate()),
+    };
 
     let tls_client_config = tls::make_client_config(&keypair, opts.peer_id, opts.keylog)?;
     let mut client_config = quinn::ClientConfig::new(Arc::new(tls_client_config));
@@ -67,6 +124,19 @@ async fn setup(opts: Options) -> Result<quinn::Connection> {
     Ok(connection)
 }
 
+/// The provider refused to send a collection, because some of the requested blobs are not
+/// available.
+///
+/// Transfers failing for this reason can be recognised by downcasting the error to this
+/// type.  The available blobs can still be requested by skipping the missing ones using
+/// [`run_ranges`], with [`RangeSpec::Skip`] at their indices.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("collection incomplete on the provider, {} blobs missing", missing.len())]
+pub struct IncompleteCollection {
+    /// The indices of the missing blobs in the collection, in ascending order.
+    pub missing: Vec<usize>,
+}
+
 /// Stats about the transfer.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Stats {
@@ -84,24 +154,224 @@ impl Stats {
     }
 }
 
+/// The state of a download, used to resume it after an interruption.
+///
+/// For each blob the state records how much verified data is already stored by the caller.
+/// [`ResumeState::ranges`] returns the ranges to pass to [`run_ranges`] so that only the
+/// missing blobs and the missing parts of partially downloaded blobs are transferred.  The
+/// [`DataStream::offset`] tells the `on_blob` callback where the received data starts.
+///
+/// How the data is stored is up to the caller.  Data yielded by a [`DataStream`] is
+/// already verified, so it is safe to store it as partial data.  Complete blobs can be
+/// verified again using [`verify_file`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeState {
+    hash: Hash,
+    entries: Vec<ResumeEntry>,
+}
+
+/// The state of a single blob in a [`ResumeState`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeEntry {
+    /// The name of the blob, empty when a single blob is downloaded.
+    pub name: String,
+    /// The hash of the blob.
+    pub hash: Hash,
+    /// How much of the blob is already stored.
+    pub progress: BlobProgress,
+}
+
+/// How much of a blob is already stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BlobProgress {
+    /// Nothing is stored yet.
+    Missing,
+    /// The given number of bytes from the start of the blob are stored.
+    Partial(u64),
+    /// The entire blob is stored.
+    Complete,
+}
+
+impl ResumeState {
+    /// Creates a new state for downloading the given hash.
+    ///
+    /// The state does not know whether the hash is a collection or a blob until either
+    /// [`ResumeState::set_collection`] or [`ResumeState::set_blob`] are called.
+    pub fn new(hash: Hash) -> Self {
+        Self {
+            hash,
+            entries: Vec::new(),
+        }
+    }
+
+    /// The hash being downloaded.
+    pub fn hash(&self) -> Hash {
+        self.hash
+    }
+
+    /// The entries, one per blob in the collection, or one for a single blob.
+    pub fn entries(&self) -> &[ResumeEntry] {
+        &self.entries
+    }
+
+    /// Mutable access to the entries, to update their progress.
+    pub fn entries_mut(&mut self) -> &mut [ResumeEntry] {
+        &mut self.entries
+    }
+
+    /// Records that the hash refers to the given collection.
+    ///
+    /// If the state already has entries, they must match the blobs of the collection.
+    pub fn set_collection(&mut self, collection: &Collection) -> Result<()> {
+        if self.entries.is_empty() {
+            self.entries = collection
+                .blobs
+                .iter()
+                .map(|blob| ResumeEntry {
+                    name: blob.name.clone(),
+                    hash: blob.hash,
+                    // entries without data are never transferred
+                    progress: if blob.has_data() {
+                        BlobProgress::Missing
+                    } else {
+                        BlobProgress::Complete
+                    },
+                })
+                .collect();
+        } else {
+            ensure!(
+                self.entries.len() == collection.blobs.len()
+                    && self
+                        .entries
+                        .iter()
+                        .zip(collection.blobs.iter())
+                        .all(|(entry, blob)| entry.hash == blob.hash && entry.name == blob.name),
+                "resume state does not match the collection"
+            );
+        }
+        Ok(())
+    }
+
+    /// Records that the hash refers to a single blob.
+    pub fn set_blob(&mut self) {
+        if self.entries.is_empty() {
+            self.entries.push(ResumeEntry {
+                name: String::new(),
+                hash: self.hash,
+                progress: BlobProgress::Missing,
+            });
+        }
+    }
+
+    /// The ranges to request to get the missing data.
+    pub fn ranges(&self) -> Vec<RangeSpec> {
+        self.entries
+            .iter()
+            .map(|entry| match entry.progress {
+                BlobProgress::Missing => RangeSpec::All,
+                BlobProgress::Partial(offset) => RangeSpec::from_offset(offset),
+                BlobProgress::Complete => RangeSpec::Skip,
+            })
+            .collect()
+    }
+
+    /// Deserializes the state from bytes.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let state = postcard::from_bytes(bytes)?;
+        Ok(state)
+    }
+
+    /// Serializes the state to bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        postcard::to_stdvec(self).expect("postcard::to_stdvec is infallible")
+    }
+}
+
+/// Checks whether the file at the given path contains exactly the blob with the given hash.
+pub async fn verify_file(path: impl AsRef<Path>, hash: Hash) -> Result<bool> {
+    let path = path.as_ref().to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = blake3::Hasher::new();
+        io::copy(&mut file, &mut hasher)?;
+        Ok(Hash::from(hasher.finalize()) == hash)
+    })
+    .await?
+}
+
 /// A verified stream of data coming from the provider
 ///
 /// We guarantee that the data is correct by incrementally verifying a hash
-#[repr(transparent)]
+///
+/// The data is verified in chunk groups, as announced by the provider, so no data of a
+/// chunk group is yielded before the entire group is received.  When a range was requested,
+/// only the bytes of that range are yielded.
 #[derive(Debug)]
-pub struct DataStream(AsyncSliceDecoder<quinn::RecvStream>);
+pub struct DataStream {
+    decoder: SliceDecoder<quinn::RecvStream>,
+    range: RangeSpec,
+}
 
 impl DataStream {
-    fn new(inner: quinn::RecvStream, hash: Hash) -> Self {
-        DataStream(AsyncSliceDecoder::new(inner, &hash.into(), 0, u64::MAX))
+    fn new(
+        inner: quinn::RecvStream,
+        hash: Hash,
+        range: RangeSpec,
+        chunk_group_log: u8,
+    ) -> Result<Self> {
+        // chunk groups are verified in memory, so their size must be bounded
+        ensure!(
+            chunk_group_log <= MAX_CHUNK_GROUP_LOG,
+            "chunk groups too large: {chunk_group_log} > {MAX_CHUNK_GROUP_LOG}"
+        );
+        let (offset, len) = range.slice();
+        Ok(DataStream {
+            decoder: SliceDecoder::new(inner, hash.into(), chunk_group_log, offset, len),
+            range,
+        })
+    }
+
+    /// Returns the range of the blob this stream yields.
+    pub fn range(&self) -> RangeSpec {
+        self.range
+    }
+
+    /// Returns the offset in the blob of the first byte this stream yields.
+    pub fn offset(&self) -> u64 {
+        self.range.slice().0
     }
 
-    async fn read_size(&mut self) -> io::Result<u64> {
-        self.0.read_size().await
+    /// Returns the size of the blob.
+    ///
+    /// This is the size of the entire blob, this does not advance the stream once the
+    /// size is known.
+    pub async fn read_size(&mut self) -> io::Result<u64> {
+        self.decoder.read_size().await
+    }
+
+    /// Checks whether the file at `path` starts with the data of the blob before
+    /// [`DataStream::offset`].
+    ///
+    /// This verifies the partial data of a resumed download against the hash of the blob,
+    /// using the parent nodes and the first chunk group the provider sends anyway.  It
+    /// reads up to the first chunk group of the stream, but does not advance the data
+    /// yielded by it.
+    pub async fn verify_prefix(&mut self, path: impl AsRef<Path>) -> Result<bool> {
+        let offset = self.offset();
+        if offset > self.read_size().await? {
+            return Ok(false);
+        }
+        let prefix = self.decoder.read_prefix().await?;
+        let path = path.as_ref().to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            let file = std::fs::File::open(path)?;
+            Ok(prefix.verify(io::BufReader::new(file))?)
+        })
+        .await?
     }
 
     fn into_inner(self) -> quinn::RecvStream {
-        self.0.into_inner()
+        self.decoder.into_inner()
     }
 }
 
@@ -111,14 +381,62 @@ impl AsyncRead for DataStream {
         cx: &mut std::task::Context<'_>,
         buf: &mut ReadBuf,
     ) -> std::task::Poll<std::io::Result<()>> {
-        std::pin::Pin::new(&mut self.0).poll_read(cx, buf)
+        std::pin::Pin::new(&mut self.decoder).poll_read(cx, buf)
     }
 }
 
-/// Get a collection and all its blobs from a provider
+/// Get a collection and all its blobs, or a single blob, from a provider
+///
+/// If the hash refers to a collection, `on_collection` is invoked once the collection is
+/// received, followed by `on_blob` for each blob in the collection which has data.  Entries
+/// without data, like directories and symlinks, are only described by the collection.  If
+/// the hash refers to a single blob, `on_collection` is not invoked and `on_blob` is called
+/// once with an empty name.
+///
+/// Entry names are chosen by the provider.  They are validated and normalized using
+/// [`normalize_name`](crate::blobs::normalize_name) before `on_collection` is invoked, a
+/// collection with unsafe names fails with an [`InvalidName`](crate::blobs::InvalidName) error.
 pub async fn run<A, B, C, FutA, FutB, FutC>(
     hash: Hash,
-    auth_token: AuthToken,
+    auth: impl Into<Auth>,
+    opts: Options,
+    on_connected: A,
+    on_collection: B,
+    on_blob: C,
+) -> Result<Stats>
+where
+    A: FnOnce() -> FutA,
+    FutA: Future<Output = Result<()>>,
+    B: FnOnce(&Collection) -> FutB,
+    FutB: Future<Output = Result<()>>,
+    C: FnMut(Hash, DataStream, String) -> FutC,
+    FutC: Future<Output = Result<DataStream>>,
+{
+    run_ranges(
+        hash,
+        Vec::new(),
+        auth,
+        opts,
+        on_connected,
+        on_collection,
+        on_blob,
+    )
+    .await
+}
+
+/// Get ranges of the blobs of a collection, or a range of a single blob, from a provider
+///
+/// This behaves like [`run`], except that only the given ranges of the blobs are
+/// transferred and verified.  For a single blob the first entry of `ranges` applies to the
+/// blob, for a collection the entry at index `i` applies to the `i`th blob in the
+/// collection.  Missing entries default to [`RangeSpec::All`].
+///
+/// The [`DataStream`] passed to `on_blob` only yields the bytes of the requested range.
+/// Blobs for which [`RangeSpec::Skip`] is requested are not passed to `on_blob` at all.
+pub async fn run_ranges<A, B, C, FutA, FutB, FutC>(
+    hash: Hash,
+    ranges: Vec<RangeSpec>,
+    auth: impl Into<Auth>,
     opts: Options,
     on_connected: A,
     on_collection: B,
@@ -133,32 +451,31 @@ where
     FutC: Future<Output = Result<DataStream>>,
 {
     let now = Instant::now();
+    let limits = opts.limits;
     let connection = setup(opts).await?;
 
     let (mut writer, mut reader) = connection.open_bi().await?;
 
     on_connected().await?;
 
-    let mut out_buffer = BytesMut::zeroed(std::cmp::max(
-        Request::POSTCARD_MAX_SIZE,
-        Handshake::POSTCARD_MAX_SIZE,
-    ));
-
     // 1. Send Handshake
     {
         debug!("sending handshake");
-        let handshake = Handshake::new(auth_token);
-        let used = postcard::to_slice(&handshake, &mut out_buffer)?;
-        write_lp(&mut writer, used).await?;
+        let handshake = Handshake::new(auth.into());
+        let used = postcard::to_stdvec(&handshake)?;
+        write_lp(&mut writer, &used).await?;
     }
 
     // 2. Send Request
+    let request = Request {
+        id: 1,
+        name: hash,
+        ranges,
+    };
     {
         debug!("sending request");
-        let req = Request { id: 1, name: hash };
-
-        let used = postcard::to_slice(&req, &mut out_buffer)?;
-        write_lp(&mut writer, used).await?;
+        let used = postcard::to_stdvec(&request)?;
+        write_lp(&mut writer, &used).await?;
     }
     writer.finish().await?;
     drop(writer);
@@ -171,44 +488,47 @@ where
         // track total amount of blob data transferred
         let mut data_len = 0;
         // read next message
-        match read_lp_data(&mut reader, &mut in_buffer).await? {
+        match read_lp_data(&mut reader, &mut in_buffer, limits.max_message_size).await? {
             Some(response_buffer) => {
                 let response: Response = postcard::from_bytes(&response_buffer)?;
                 match response.data {
                     // server is sending over a collection of blobs
                     Res::FoundCollection { total_blobs_size } => {
-                        ensure!(
-                            total_blobs_size <= MAX_DATA_SIZE,
-                            "size too large: {} > {}",
-                            total_blobs_size,
-                            MAX_DATA_SIZE
-                        );
-
-                        data_len = total_blobs_size;
+                        limits.check_data_size(total_blobs_size)?;
 
                         // read entire collection data into buffer
-                        let data = read_bao_encoded(&mut reader, hash).await?;
+                        let data =
+                            read_bao_encoded(&mut reader, hash, limits.max_collection_size).await?;
 
                         // decode the collection
-                        let collection = Collection::from_bytes(&data)?;
+                        let mut collection = Collection::from_bytes(&data)?;
+                        // names are chosen by the provider, make sure they are safe to use
+                        collection.normalize_names()?;
                         on_collection(&collection).await?;
 
                         // expect to get blob data in the order they appear in the collection
                         let mut remaining_size = total_blobs_size;
-                        for blob in collection.blobs {
-                            let mut blob_reader =
-                                handle_blob_response(blob.hash, reader, &mut in_buffer).await?;
+                        for (i, blob) in collection.blobs.into_iter().enumerate() {
+                            let range = request.range(i);
+                            if !blob.has_data() || range == RangeSpec::Skip {
+                                continue;
+                            }
+                            let mut blob_reader = handle_blob_response(
+                                blob.hash,
+                                reader,
+                                &mut in_buffer,
+                                range,
+                                limits.max_message_size,
+                            )
+                            .await?;
 
                             let size = blob_reader.read_size().await?;
-                            anyhow::ensure!(
-                                size <= MAX_DATA_SIZE,
-                                "size too large: {size} > {MAX_DATA_SIZE}"
-                            );
                             anyhow::ensure!(
                                 size <= remaining_size,
                                 "downloaded more than {total_blobs_size}"
                             );
                             remaining_size -= size;
+                            data_len += range.byte_len(size);
                             let mut blob_reader =
                                 on_blob(blob.hash, blob_reader, blob.name).await?;
 
@@ -219,17 +539,37 @@ where
                         }
                     }
 
-                    // unexpected message
-                    Res::Found { .. } => {
-                        // we should only receive `Res::FoundCollection` or `Res::NotFound` from the
-                        // provider at this point in the exchange
-                        bail!("Unexpected message from provider. Ending transfer early.");
+                    // server is sending over a single blob
+                    Res::Found { .. } if request.range(0) == RangeSpec::Skip => {}
+                    Res::Found { chunk_group_log } => {
+                        let range = request.range(0);
+                        let mut blob_reader =
+                            DataStream::new(reader, hash, range, chunk_group_log)?;
+                        let size = blob_reader.read_size().await?;
+                        limits.check_data_size(size)?;
+                        data_len = range.byte_len(size);
+                        let mut blob_reader = on_blob(hash, blob_reader, String::new()).await?;
+
+                        if blob_reader.read_exact(&mut [0u8; 1]).await.is_ok() {
+                            bail!("`on_blob` callback did not fully read the blob content")
+                        }
+                        reader = blob_reader.into_inner();
                     }
 
                     // data associated with the hash is not found
                     Res::NotFound => {
                         Err(anyhow!("data not found"))?;
                     }
+
+                    // data associated with the hash was modified on the provider
+                    Res::Modified => {
+                        Err(anyhow!("data was modified on the provider"))?;
+                    }
+
+                    // some blobs of the collection are not available on the provider
+                    Res::CollectionIncomplete { missing } => {
+                        Err(IncompleteCollection { missing })?;
+                    }
                 }
 
                 // Shut down the stream
@@ -260,21 +600,25 @@ async fn handle_blob_response(
     hash: Hash,
     mut reader: quinn::RecvStream,
     buffer: &mut BytesMut,
+    range: RangeSpec,
+    max_message_size: u64,
 ) -> Result<DataStream> {
-    match read_lp_data(&mut reader, buffer).await? {
+    match read_lp_data(&mut reader, buffer, max_message_size).await? {
         Some(response_buffer) => {
             let response: Response = postcard::from_bytes(&response_buffer)?;
             match response.data {
                 // unexpected message
-                Res::FoundCollection { .. } => Err(anyhow!(
+                Res::FoundCollection { .. } | Res::CollectionIncomplete { .. } => Err(anyhow!(
                     "Unexpected message from provider. Ending transfer early."
                 ))?,
                 // blob data not found
                 Res::NotFound => Err(anyhow!("data for {} not found", hash))?,
+                // blob data was modified on the provider
+                Res::Modified => Err(anyhow!("data for {} was modified on the provider", hash))?,
                 // next blob in collection will be sent over
-                Res::Found => {
+                Res::Found { chunk_group_log } => {
                     assert!(buffer.is_empty());
-                    let decoder = DataStream::new(reader, hash);
+                    let decoder = DataStream::new(reader, hash, range, chunk_group_log)?;
                     Ok(decoder)
                 }
             }
@@ -282,3 +626,34 @@ async fn handle_blob_response(
         None => Err(anyhow!("server disconnected"))?,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits_data_size() {
+        let limits = Limits::default();
+        assert_eq!(limits.check_data_size(MAX_DATA_SIZE), Ok(()));
+        assert_eq!(
+            limits.check_data_size(u64::MAX),
+            Err(LimitExceeded::DataSize {
+                size: u64::MAX,
+                max: MAX_DATA_SIZE
+            })
+        );
+
+        let limits = Limits {
+            max_data_size: Some(1024),
+            ..Default::default()
+        };
+        assert_eq!(limits.check_data_size(1024), Ok(()));
+        assert_eq!(
+            limits.check_data_size(1025),
+            Err(LimitExceeded::DataSize {
+                size: 1025,
+                max: 1024
+            })
+        );
+    }
+}
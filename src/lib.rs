@@ -1,12 +1,16 @@
 //! Send data over the internet.
 #![deny(missing_docs)]
 #![deny(rustdoc::broken_intra_doc_links)]
+#[cfg(feature = "cli")]
+pub mod archive;
 pub mod blobs;
+pub mod capability;
 pub mod get;
 pub mod progress;
 pub mod protocol;
 pub mod provider;
 
+mod bao;
 mod tls;
 mod util;
 
@@ -19,7 +23,7 @@ mod tests {
         net::SocketAddr,
         path::PathBuf,
         sync::{atomic::AtomicUsize, Arc},
-        time::Duration,
+        time::{Duration, SystemTime},
     };
 
     use anyhow::{anyhow, Context, Result};
@@ -29,13 +33,40 @@ mod tests {
     use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
     use tracing_subscriber::{prelude::*, EnvFilter};
 
-    use crate::protocol::AuthToken;
-    use crate::provider::{create_collection, Event, Provider};
+    use crate::capability::Capability;
+    use crate::protocol::{Auth, AuthToken, LimitExceeded, RangeSpec};
+    use crate::provider::{create_collection, Event, Provider, Ticket};
     use crate::tls::PeerId;
     use crate::util::Hash;
 
     use super::*;
 
+    /// A builder for a provider of `db`, listening on a random local port.
+    fn test_provider(db: provider::Database) -> provider::Builder {
+        Provider::builder(db).bind_addr("127.0.0.1:0".parse().unwrap())
+    }
+
+    /// Options to get from `provider`, expecting its [`PeerId`].
+    fn get_options(provider: &Provider) -> get::Options {
+        get::Options {
+            addr: provider.listen_addr(),
+            peer_id: Some(provider.peer_id()),
+            keylog: true,
+            keypair: None,
+            limits: Default::default(),
+        }
+    }
+
+    /// A blob callback for [`get::run`] which reads and discards the data.
+    async fn discard(
+        _hash: Hash,
+        mut reader: get::DataStream,
+        _name: String,
+    ) -> Result<get::DataStream> {
+        io::copy(&mut reader, &mut io::sink()).await?;
+        Ok(reader)
+    }
+
     #[tokio::test]
     async fn basics() -> Result<()> {
         transfer_data(vec![("hello_world", "hello world!".as_bytes().to_vec())]).await
@@ -116,6 +147,8 @@ mod tests {
                 addr,
                 peer_id: Some(peer_id),
                 keylog: true,
+                keypair: None,
+                limits: Default::default(),
             };
             let content = &content;
             let name = &name;
@@ -158,6 +191,1215 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn get_single_blob() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let foo = dir.join("foo");
+        let bar = dir.join("bar");
+        tokio::fs::write(&foo, b"hello foo").await?;
+        tokio::fs::write(&bar, b"hello bar").await?;
+        let (db, _) = create_collection(vec![foo.into(), bar.into()]).await?;
+        let blob_hash = Hash::new(b"hello bar");
+        let provider = test_provider(db).spawn()?;
+
+        let opts = get_options(&provider);
+        let stats = get::run(
+            blob_hash,
+            provider.auth_token(),
+            opts,
+            || async { Ok(()) },
+            |_collection| async { panic!("requested a blob, not a collection") },
+            |got_hash, mut reader, got_name| async move {
+                assert_eq!(got_hash, blob_hash);
+                assert_eq!(got_name, "");
+                let mut got = Vec::new();
+                reader.read_to_end(&mut got).await?;
+                assert_eq!(got, b"hello bar");
+                Ok(reader)
+            },
+        )
+        .await?;
+        assert_eq!(stats.data_len, 9);
+
+        provider.shutdown();
+        provider.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_directory_tree() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let root = dir.join("root");
+        tokio::fs::create_dir_all(root.join("empty")).await?;
+        tokio::fs::create_dir_all(root.join("sub")).await?;
+        tokio::fs::write(root.join("sub/foo"), b"hello foo").await?;
+        tokio::fs::write(root.join("bar"), b"hello bar").await?;
+        let sources = provider::data_sources_from_dir(&root).await?;
+        let (db, hash) = create_collection(sources).await?;
+        let provider = test_provider(db).spawn()?;
+
+        let opts = get_options(&provider);
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let stats = get::run(
+            hash,
+            provider.auth_token(),
+            opts,
+            || async { Ok(()) },
+            |collection| {
+                let names: Vec<_> = collection.blobs().iter().map(|b| b.name()).collect();
+                assert_eq!(names, vec!["bar", "empty", "sub", "sub/foo"]);
+                async { Ok(()) }
+            },
+            |_hash, mut reader, name| {
+                let received = received.clone();
+                async move {
+                    let mut got = Vec::new();
+                    reader.read_to_end(&mut got).await?;
+                    received.lock().unwrap().push((name, got));
+                    Ok(reader)
+                }
+            },
+        )
+        .await?;
+        assert_eq!(stats.data_len, 18);
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![
+                ("bar".to_string(), b"hello bar".to_vec()),
+                ("sub/foo".to_string(), b"hello foo".to_vec()),
+            ]
+        );
+
+        provider.shutdown();
+        provider.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reject_unsafe_names() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let foo = dir.join("foo");
+        tokio::fs::write(&foo, b"evil").await?;
+        let source = provider::DataSource::with_name(foo, "../../.bashrc".to_string());
+        let (db, hash) = create_collection(vec![source]).await?;
+        let provider = test_provider(db).spawn()?;
+
+        let opts = get_options(&provider);
+        let err = get::run(
+            hash,
+            provider.auth_token(),
+            opts,
+            || async { Ok(()) },
+            |_collection| async { panic!("unsafe collection must be rejected") },
+            |_hash, _reader, _name| async { panic!("unsafe collection must be rejected") },
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<blobs::InvalidName>(),
+            Some(&blobs::InvalidName::ParentDir("../../.bashrc".to_string()))
+        );
+
+        provider.shutdown();
+        provider.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_ranges() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let mut content = vec![0u8; 1024 * 1024];
+        rand::thread_rng().fill_bytes(&mut content);
+        let foo = dir.join("foo");
+        let bar = dir.join("bar");
+        tokio::fs::write(&foo, &content).await?;
+        tokio::fs::write(&bar, &content[..2000]).await?;
+        let (db, collection_hash) = create_collection(vec![foo.into(), bar.into()]).await?;
+        let provider = test_provider(db).spawn()?;
+        let opts = get_options(&provider);
+
+        // A range of a single blob.
+        let blob_hash = Hash::new(&content);
+        let expect = &content[5000..15000];
+        let stats = get::run_ranges(
+            blob_hash,
+            vec![RangeSpec::new(5000, 10000)],
+            provider.auth_token(),
+            opts.clone(),
+            || async { Ok(()) },
+            |_collection| async { Ok(()) },
+            |_hash, mut reader, _name| async move {
+                let mut got = Vec::new();
+                reader.read_to_end(&mut got).await?;
+                assert_eq!(got, expect);
+                Ok(reader)
+            },
+        )
+        .await?;
+        assert_eq!(stats.data_len, 10000);
+
+        // Ranges of the blobs of a collection, the second one is clamped to the blob size.
+        let expects = [&content[1024 * 1024 - 10..], &content[1000..2000]];
+        let i = AtomicUsize::new(0);
+        let stats = get::run_ranges(
+            collection_hash,
+            vec![
+                RangeSpec::new(1024 * 1024 - 10, 100),
+                RangeSpec::new(1000, 5000),
+            ],
+            provider.auth_token(),
+            opts,
+            || async { Ok(()) },
+            |_collection| async { Ok(()) },
+            |_hash, mut reader, _name| {
+                let i = &i;
+                let expects = &expects;
+                async move {
+                    let mut got = Vec::new();
+                    reader.read_to_end(&mut got).await?;
+                    let i = i.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    assert_eq!(got, expects[i]);
+                    Ok(reader)
+                }
+            },
+        )
+        .await?;
+        assert_eq!(stats.data_len, 1010);
+        assert_eq!(i.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+        provider.shutdown();
+        provider.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn incomplete_collection() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let foo = dir.join("foo");
+        let bar = dir.join("bar");
+        tokio::fs::write(&foo, b"hello foo").await?;
+        tokio::fs::write(&bar, b"hello bar").await?;
+        let (db, collection_hash) = create_collection(vec![foo.into(), bar.clone().into()]).await?;
+        let provider = test_provider(db).spawn()?;
+        let mut events = provider.subscribe();
+        tokio::fs::remove_file(&bar).await?;
+
+        let opts = get_options(&provider);
+        // A single download, which is not used up by the incomplete collection.
+        let ticket = provider.ticket_with_scope(
+            collection_hash,
+            provider::TokenScope::all().max_downloads(1),
+        );
+        let get_ranges = |ranges| {
+            get::run_ranges(
+                collection_hash,
+                ranges,
+                ticket.auth.clone(),
+                opts.clone(),
+                || async { Ok(()) },
+                |_collection| async { Ok(()) },
+                discard,
+            )
+        };
+
+        let err = get_ranges(Vec::new()).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<get::IncompleteCollection>(),
+            Some(&get::IncompleteCollection { missing: vec![1] })
+        );
+        loop {
+            if let Event::TransferAborted { reason, .. } = events.recv().await? {
+                assert_eq!(reason, provider::AbortReason::CollectionIncomplete);
+                break;
+            }
+        }
+        assert!(provider
+            .metrics()
+            .contains("sendme_provider_requests_total{outcome=\"incomplete\"} 1\n"));
+
+        // The available blobs can still be requested.
+        let stats = get_ranges(vec![RangeSpec::All, RangeSpec::Skip]).await?;
+        assert_eq!(stats.data_len, 9);
+        get_ranges(vec![RangeSpec::All, RangeSpec::Skip])
+            .await
+            .expect_err("download limit reached");
+
+        provider.shutdown();
+        provider.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn provide_from_store() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let mut content = vec![0u8; 3 * 1024 * 1024 + 17];
+        rand::thread_rng().fill_bytes(&mut content);
+        let foo = dir.join("foo");
+        tokio::fs::write(&foo, &content).await?;
+        let (db, collection_hash) = create_collection(vec![foo.into()]).await?;
+        let store = dir.join("store");
+        db.save(&store).await?;
+        drop(db);
+
+        // The blob outboards are served from the store.
+        let db = provider::Database::load(&store).await?;
+        let provider = test_provider(db).spawn()?;
+        let opts = get_options(&provider);
+        let content = &content;
+        get::run(
+            collection_hash,
+            provider.auth_token(),
+            opts.clone(),
+            || async { Ok(()) },
+            |_collection| async { Ok(()) },
+            |_hash, mut reader, _name| async move {
+                let mut got = Vec::new();
+                reader.read_to_end(&mut got).await?;
+                assert!(got == *content);
+                Ok(reader)
+            },
+        )
+        .await?;
+
+        let expect = &content[2 * 1024 * 1024 - 100..2 * 1024 * 1024 + 100];
+        get::run_ranges(
+            Hash::new(content),
+            vec![RangeSpec::new(2 * 1024 * 1024 - 100, 200)],
+            provider.auth_token(),
+            opts,
+            || async { Ok(()) },
+            |_collection| async { Ok(()) },
+            |_hash, mut reader, _name| async move {
+                let mut got = Vec::new();
+                reader.read_to_end(&mut got).await?;
+                assert_eq!(got, expect);
+                Ok(reader)
+            },
+        )
+        .await?;
+
+        provider.shutdown();
+        provider.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_resume() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let mut content = vec![0u8; 100_000];
+        rand::thread_rng().fill_bytes(&mut content);
+        let foo = dir.join("foo");
+        let bar = dir.join("bar");
+        let baz = dir.join("baz");
+        tokio::fs::write(&foo, &content[..1000]).await?;
+        tokio::fs::write(&bar, &content).await?;
+        tokio::fs::write(&baz, &content[..5000]).await?;
+        let (db, collection_hash) =
+            create_collection(vec![foo.into(), bar.into(), baz.into()]).await?;
+        let provider = test_provider(db).spawn()?;
+        let opts = get_options(&provider);
+
+        // Pretend a previous download got the first blob and part of the second one.
+        let mut state = get::ResumeState::new(collection_hash);
+        let state = {
+            let state = &mut state;
+            get::run_ranges(
+                collection_hash,
+                vec![RangeSpec::Skip; 3],
+                provider.auth_token(),
+                opts.clone(),
+                || async { Ok(()) },
+                |collection| {
+                    let res = state.set_collection(collection);
+                    async move { res }
+                },
+                |_hash, _reader, _name| async { panic!("all blobs are skipped") },
+            )
+            .await?;
+            state.entries_mut()[0].progress = get::BlobProgress::Complete;
+            state.entries_mut()[1].progress = get::BlobProgress::Partial(30_000);
+            get::ResumeState::from_bytes(&state.to_bytes())?
+        };
+        assert_eq!(
+            state.ranges(),
+            vec![
+                RangeSpec::Skip,
+                RangeSpec::from_offset(30_000),
+                RangeSpec::All
+            ]
+        );
+
+        let received = tokio::sync::Mutex::new(Vec::new());
+        let stats = get::run_ranges(
+            collection_hash,
+            state.ranges(),
+            provider.auth_token(),
+            opts,
+            || async { Ok(()) },
+            |_collection| async { Ok(()) },
+            |_hash, mut reader, name| {
+                let received = &received;
+                let dir = &dir;
+                let content = &content;
+                async move {
+                    let offset = reader.offset();
+                    if offset > 0 {
+                        // The partial data is verified before the rest is received.
+                        let partial = dir.join("partial");
+                        tokio::fs::write(&partial, &content[..offset as usize]).await?;
+                        assert!(reader.verify_prefix(&partial).await?);
+                        let mut corrupted = content[..offset as usize].to_vec();
+                        corrupted[10] ^= 1;
+                        tokio::fs::write(&partial, corrupted).await?;
+                        assert!(!reader.verify_prefix(&partial).await?);
+                    }
+                    let mut got = Vec::new();
+                    reader.read_to_end(&mut got).await?;
+                    received.lock().await.push((name, offset, got));
+                    Ok(reader)
+                }
+            },
+        )
+        .await?;
+        assert_eq!(stats.data_len, 70_000 + 5000);
+        let received = received.into_inner();
+        assert_eq!(
+            received,
+            vec![
+                ("bar".to_string(), 30_000, content[30_000..].to_vec()),
+                ("baz".to_string(), 0, content[..5000].to_vec()),
+            ]
+        );
+
+        provider.shutdown();
+        provider.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn add_remove_content() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let foo = dir.join("foo");
+        let mut content = vec![0u8; 8 * 1024 * 1024];
+        rand::thread_rng().fill_bytes(&mut content);
+        tokio::fs::write(&foo, &content).await?;
+        let provider = test_provider(provider::Database::default()).spawn()?;
+        let opts = get_options(&provider);
+        let get_collection = |hash| {
+            get::run(
+                hash,
+                provider.auth_token(),
+                opts.clone(),
+                || async { Ok(()) },
+                |_collection| async { Ok(()) },
+                discard,
+            )
+        };
+
+        // Content added while running is served.
+        let (db, hash) = create_collection(vec![foo.into()]).await?;
+        get_collection(hash).await.expect_err("not added yet");
+        provider.add(&db);
+        get_collection(hash).await?;
+
+        // Removing a blob aborts a transfer in progress.
+        let blob_hash = Hash::new(&content);
+        let mut events = provider.subscribe();
+        let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+        let started_tx = std::sync::Mutex::new(Some(started_tx));
+        let transfer = get::run(
+            hash,
+            provider.auth_token(),
+            opts.clone(),
+            || async { Ok(()) },
+            |_collection| async { Ok(()) },
+            |_hash, mut reader, _name| {
+                let started_tx = started_tx.lock().unwrap().take();
+                async move {
+                    let mut buf = [0u8; 1024];
+                    reader.read_exact(&mut buf).await?;
+                    started_tx.unwrap().send(()).ok();
+                    io::copy(&mut reader, &mut io::sink()).await?;
+                    Ok(reader)
+                }
+            },
+        );
+        let remove = async {
+            started_rx.await.ok();
+            assert!(provider.remove(&blob_hash));
+        };
+        let (res, ()) = tokio::join!(transfer, remove);
+        res.expect_err("transfer should be aborted");
+        loop {
+            if let Event::TransferAborted { .. } = events.recv().await? {
+                break;
+            }
+        }
+
+        // The collection is still there, but its blob is not.
+        assert!(!provider.remove(&blob_hash));
+        get_collection(hash).await.expect_err("blob is removed");
+        assert!(provider.remove(&hash));
+        get_collection(hash)
+            .await
+            .expect_err("collection is removed");
+
+        provider.shutdown();
+        provider.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scoped_tokens() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let foo = dir.join("foo");
+        let bar = dir.join("bar");
+        tokio::fs::write(&foo, b"hello foo").await?;
+        tokio::fs::write(&bar, b"hello bar").await?;
+        let (db, collection_hash) = create_collection(vec![foo.into(), bar.into()]).await?;
+        let provider = test_provider(db).spawn()?;
+        let get_hash = |hash, auth: Auth| {
+            let opts = get_options(&provider);
+            get::run(
+                hash,
+                auth,
+                opts,
+                || async { Ok(()) },
+                |_collection| async { Ok(()) },
+                discard,
+            )
+        };
+        let foo_hash = Hash::new(b"hello foo");
+
+        // A ticket only gives access to its own hash.
+        let ticket = provider.ticket(collection_hash);
+        assert_ne!(ticket.auth, provider.auth_token().into());
+        assert_eq!(provider.ticket(collection_hash).auth, ticket.auth);
+        get_hash(collection_hash, ticket.auth.clone()).await?;
+        get_hash(foo_hash, ticket.auth)
+            .await
+            .expect_err("blob is not in scope");
+        get_hash(foo_hash, provider.auth_token().into()).await?;
+
+        // Limited downloads and revocation.
+        let ticket =
+            provider.ticket_with_scope(foo_hash, provider::TokenScope::all().max_downloads(1));
+        get_hash(Hash::new(b"missing"), ticket.auth.clone())
+            .await
+            .expect_err("not found");
+        get_hash(foo_hash, ticket.auth.clone()).await?;
+        get_hash(foo_hash, ticket.auth)
+            .await
+            .expect_err("download limit reached");
+        let token = provider.mint_token(provider::TokenScope::all());
+        get_hash(foo_hash, token.into()).await?;
+        assert!(provider.revoke_token(&token));
+        get_hash(foo_hash, token.into())
+            .await
+            .expect_err("token revoked");
+        get_hash(foo_hash, AuthToken::generate().into())
+            .await
+            .expect_err("unknown token");
+        get_hash(foo_hash, Auth::Identity)
+            .await
+            .expect_err("no getters are allowed by identity");
+
+        provider.shutdown();
+        provider.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signed_capabilities() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let foo = dir.join("foo");
+        let bar = dir.join("bar");
+        tokio::fs::write(&foo, b"hello foo").await?;
+        tokio::fs::write(&bar, b"hello bar").await?;
+        let (db, collection_hash) = create_collection(vec![foo.into(), bar.into()]).await?;
+        let provider = test_provider(db).spawn()?;
+        let get_hash = |hash, auth: Auth| {
+            let opts = get_options(&provider);
+            get::run(
+                hash,
+                auth,
+                opts,
+                || async { Ok(()) },
+                |_collection| async { Ok(()) },
+                discard,
+            )
+        };
+        let foo_hash = Hash::new(b"hello foo");
+
+        // The ticket survives a roundtrip and only gives access to the signed hashes.
+        let capability = Capability::new([collection_hash]).expires_in(Duration::from_secs(60));
+        let ticket = provider.ticket_with_capability(collection_hash, capability);
+        let ticket: Ticket = ticket.to_string().parse()?;
+        get_hash(collection_hash, ticket.auth.clone()).await?;
+        get_hash(foo_hash, ticket.auth)
+            .await
+            .expect_err("blob is not in capability");
+
+        // Expired or foreign capabilities are rejected.
+        let expired = provider.sign_capability(
+            Capability::new([foo_hash]).expires_at(SystemTime::now() - Duration::from_secs(1)),
+        );
+        get_hash(foo_hash, expired.into())
+            .await
+            .expect_err("capability expired");
+        let foreign = Capability::new([foo_hash]).sign(&Keypair::generate());
+        get_hash(foo_hash, foreign.into())
+            .await
+            .expect_err("not signed by the provider");
+
+        // A capability for another getter is rejected, each get uses a new keypair.
+        let keypair = Arc::new(Keypair::generate());
+        let getter: PeerId = keypair.public().into();
+        let bound = provider.sign_capability(Capability::new([foo_hash]).getter(getter));
+        get_hash(foo_hash, bound.clone().into())
+            .await
+            .expect_err("capability is for another getter");
+        let opts = get::Options {
+            keypair: Some(keypair),
+            ..get_options(&provider)
+        };
+        get::run(
+            foo_hash,
+            bound,
+            opts,
+            || async { Ok(()) },
+            |_collection| async { Ok(()) },
+            discard,
+        )
+        .await?;
+
+        // Watched directories announce tickets with a capability as well.
+        #[cfg(feature = "cli")]
+        {
+            let watched = dir.join("watched");
+            tokio::fs::create_dir(&watched).await?;
+            tokio::fs::write(watched.join("baz"), b"hello baz").await?;
+            let mut events = provider.subscribe();
+            let watched_hash = provider
+                .watch_with_ticket_expiry(&watched, Duration::from_secs(60))
+                .await?;
+            let ticket = loop {
+                if let Event::CollectionPublished { ticket, .. } = events.recv().await? {
+                    break ticket;
+                }
+            };
+            assert_eq!(ticket.hash, watched_hash);
+            assert!(matches!(ticket.auth, Auth::Capability(_)));
+            get_hash(watched_hash, ticket.auth.clone()).await?;
+            get_hash(foo_hash, ticket.auth)
+                .await
+                .expect_err("blob is not in capability");
+        }
+
+        provider.shutdown();
+        provider.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn allowed_peers() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let foo = dir.join("foo");
+        tokio::fs::write(&foo, b"hello foo").await?;
+        let (db, hash) = create_collection(vec![foo.into()]).await?;
+        let allowed = Arc::new(Keypair::generate());
+        let provider = test_provider(db)
+            .allowed_peers([allowed.public().into()])
+            .spawn()?;
+        let get_hash = |keypair, auth: Auth| {
+            let opts = get::Options {
+                addr: provider.listen_addr(),
+                peer_id: Some(provider.peer_id()),
+                keylog: true,
+                keypair,
+                limits: Default::default(),
+            };
+            get::run(
+                hash,
+                auth,
+                opts,
+                || async { Ok(()) },
+                |_collection| async { Ok(()) },
+                discard,
+            )
+        };
+
+        // Allowed getters are authenticated by their identity, or by a token.
+        get_hash(Some(allowed.clone()), Auth::Identity).await?;
+        get_hash(Some(allowed), provider.auth_token().into()).await?;
+
+        // Other getters can not connect, not even with a token.
+        let other = Arc::new(Keypair::generate());
+        get_hash(Some(other.clone()), Auth::Identity)
+            .await
+            .expect_err("getter is not allowed");
+        get_hash(Some(other), provider.auth_token().into())
+            .await
+            .expect_err("getter is not allowed");
+        get_hash(None, provider.auth_token().into())
+            .await
+            .expect_err("getter is not allowed");
+
+        provider.shutdown();
+        provider.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rate_limit() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let foo = dir.join("foo");
+        let mut content = vec![0u8; 128 * 1024];
+        rand::thread_rng().fill_bytes(&mut content);
+        tokio::fs::write(&foo, &content).await?;
+        let (db, hash) = create_collection(vec![foo.into()]).await?;
+        let provider = test_provider(db).max_rate(256 * 1024).spawn()?;
+
+        let opts = get_options(&provider);
+        let stats = get::run(
+            hash,
+            provider.auth_token(),
+            opts,
+            || async { Ok(()) },
+            |_collection| async { Ok(()) },
+            discard,
+        )
+        .await?;
+        // Only a short burst is sent at full speed.
+        assert!(
+            stats.elapsed > Duration::from_millis(350),
+            "{:?}",
+            stats.elapsed
+        );
+
+        provider.shutdown();
+        provider.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn size_limits() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let foo = dir.join("foo");
+        tokio::fs::write(&foo, vec![1u8; 128 * 1024]).await?;
+        let (db, hash) = create_collection(vec![foo.into()]).await?;
+        let provider = test_provider(db).spawn()?;
+
+        let get = |limits: get::Limits| {
+            let opts = get::Options {
+                limits,
+                ..get_options(&provider)
+            };
+            get::run(
+                hash,
+                provider.auth_token(),
+                opts,
+                || async { Ok(()) },
+                |_collection| async { Ok(()) },
+                discard,
+            )
+        };
+
+        let err = get(get::Limits {
+            max_data_size: Some(64 * 1024),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<LimitExceeded>(),
+            Some(&LimitExceeded::DataSize {
+                size: 128 * 1024,
+                max: 64 * 1024
+            })
+        );
+
+        let err = get(get::Limits {
+            max_collection_size: 10,
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<LimitExceeded>(),
+            Some(&LimitExceeded::CollectionSize { max: 10 })
+        );
+
+        let err = get(get::Limits {
+            max_message_size: 1,
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<LimitExceeded>(),
+            Some(LimitExceeded::MessageSize { max: 1, .. })
+        ));
+
+        get(get::Limits::default()).await?;
+
+        provider.shutdown();
+        provider.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connection_events() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let foo = dir.join("foo");
+        tokio::fs::write(&foo, vec![1u8; 1024]).await?;
+        let (db, hash) = create_collection(vec![foo.into()]).await?;
+        let provider = test_provider(db).spawn()?;
+        let mut events = provider.subscribe();
+
+        let keypair = Arc::new(Keypair::generate());
+        let getter = PeerId::from(keypair.public());
+        let opts = get::Options {
+            keypair: Some(keypair),
+            ..get_options(&provider)
+        };
+        get::run(
+            hash,
+            provider.auth_token(),
+            opts,
+            || async { Ok(()) },
+            |_collection| async { Ok(()) },
+            discard,
+        )
+        .await?;
+
+        let mut connected = None;
+        let mut blob_bytes = 0;
+        let mut transfer_bytes = 0;
+        loop {
+            match tokio::time::timeout(Duration::from_secs(10), events.recv()).await?? {
+                Event::ClientConnected {
+                    connection_id,
+                    remote_addr,
+                    peer_id,
+                } => {
+                    assert!(remote_addr.ip().is_loopback());
+                    assert_eq!(peer_id, Some(getter));
+                    connected = Some(connection_id);
+                }
+                Event::BlobStarted { size, .. } => assert_eq!(size, 1024),
+                Event::BlobCompleted { bytes_sent, .. } => blob_bytes += bytes_sent,
+                Event::TransferCompleted { bytes_sent, .. } => transfer_bytes = bytes_sent,
+                Event::ClientDisconnected { connection_id } => {
+                    assert_eq!(Some(connection_id), connected);
+                    break;
+                }
+                _ => (),
+            }
+        }
+        // The blob is sent after the encoded collection.
+        assert!(blob_bytes > 1024, "{blob_bytes}");
+        assert!(transfer_bytes > blob_bytes, "{transfer_bytes}");
+
+        provider.shutdown();
+        provider.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn event_sink() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let mut files = Vec::new();
+        for i in 0..20 {
+            let path = dir.join(format!("file{i}"));
+            tokio::fs::write(&path, vec![i as u8; 1024]).await?;
+            files.push(path.into());
+        }
+        let (db, hash) = create_collection(files).await?;
+        // A sink without spare capacity, which would lag behind a broadcast channel.
+        let (sink, mut events) = tokio::sync::mpsc::channel(1);
+        let provider = test_provider(db).event_sink(sink).spawn()?;
+        let events_task = tokio::spawn(async move {
+            let mut received = Vec::new();
+            while let Some(event) = events.recv().await {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                let disconnected = matches!(event, Event::ClientDisconnected { .. });
+                received.push(event);
+                if disconnected {
+                    break;
+                }
+            }
+            received
+        });
+
+        let opts = get_options(&provider);
+        get::run(
+            hash,
+            provider.auth_token(),
+            opts,
+            || async { Ok(()) },
+            |_collection| async { Ok(()) },
+            discard,
+        )
+        .await?;
+
+        let events = tokio::time::timeout(Duration::from_secs(10), events_task).await??;
+        // The last event is the disconnect, so the rest of a transfer is received as well.
+        let mut events = events.into_iter();
+        assert!(matches!(
+            events.next_back(),
+            Some(Event::ClientDisconnected { .. })
+        ));
+        assert_events(events.collect(), 20);
+
+        provider.shutdown();
+        provider.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn metrics() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let foo = dir.join("foo");
+        tokio::fs::write(&foo, vec![1u8; 1024]).await?;
+        let (db, hash) = create_collection(vec![foo.into()]).await?;
+        // Large enough for all events of the requests, which are only received afterwards.
+        let (sink, mut events) = tokio::sync::mpsc::channel(1024);
+        let provider = test_provider(db)
+            .metrics_addr("127.0.0.1:0".parse().unwrap())
+            .event_sink(sink)
+            .spawn()?;
+
+        let get_hash = |hash, auth: AuthToken| {
+            let opts = get_options(&provider);
+            get::run(
+                hash,
+                auth,
+                opts,
+                || async { Ok(()) },
+                |_collection| async { Ok(()) },
+                discard,
+            )
+        };
+        get_hash(hash, provider.auth_token()).await?;
+        get_hash(Hash::new(b"missing"), provider.auth_token())
+            .await
+            .unwrap_err();
+        get_hash(hash, AuthToken::generate()).await.unwrap_err();
+        // Wait for the provider to finish all three requests.
+        let mut finished = 0;
+        while finished < 3 {
+            if let Some(Event::TransferCompleted { .. } | Event::TransferAborted { .. }) =
+                events.recv().await
+            {
+                finished += 1;
+            }
+        }
+
+        let addr = provider.metrics_addr().unwrap();
+        let http_get = |path: &'static str| async move {
+            let mut stream = tokio::net::TcpStream::connect(addr).await?;
+            stream
+                .write_all(format!("GET {path} HTTP/1.1\r\nHost: {addr}\r\n\r\n").as_bytes())
+                .await?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await?;
+            anyhow::Ok(response)
+        };
+        let response = http_get("/metrics").await?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert_eq!(
+            response.split_once("\r\n\r\n").unwrap().1,
+            provider.metrics()
+        );
+        let metrics = provider.metrics();
+        for expected in [
+            "sendme_provider_connections_total 3\n",
+            "sendme_provider_requests_total{outcome=\"found\"} 1\n",
+            "sendme_provider_requests_total{outcome=\"not_found\"} 1\n",
+            "sendme_provider_auth_failures_total 1\n",
+            "sendme_provider_transfer_duration_seconds_count 1\n",
+        ] {
+            assert!(metrics.contains(expected), "{expected} in {metrics}");
+        }
+        let response = http_get("/other").await?;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        provider.shutdown();
+        provider.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let foo = dir.join("foo");
+        let mut content = vec![0u8; 8 * 1024 * 1024];
+        rand::thread_rng().fill_bytes(&mut content);
+        tokio::fs::write(&foo, &content).await?;
+        let (db, hash) = create_collection(vec![foo.into()]).await?;
+
+        // Starts a transfer which stalls after the first bytes until `resume` completes.
+        async fn stalled_transfer(
+            provider: &Provider,
+            hash: Hash,
+            started: tokio::sync::oneshot::Sender<()>,
+            resume: impl std::future::Future<Output = ()>,
+        ) -> Result<()> {
+            let opts = get_options(provider);
+            let started = std::sync::Mutex::new(Some(started));
+            let resume = std::sync::Mutex::new(Some(Box::pin(resume)));
+            get::run(
+                hash,
+                provider.auth_token(),
+                opts,
+                || async { Ok(()) },
+                |_collection| async { Ok(()) },
+                |_hash, mut reader, _name| {
+                    let started = started.lock().unwrap().take().unwrap();
+                    let resume = resume.lock().unwrap().take().unwrap();
+                    async move {
+                        let mut buf = [0u8; 1024];
+                        reader.read_exact(&mut buf).await?;
+                        started.send(()).ok();
+                        resume.await;
+                        io::copy(&mut reader, &mut io::sink()).await?;
+                        Ok(reader)
+                    }
+                },
+            )
+            .await?;
+            Ok(())
+        }
+
+        // A running transfer is finished, while new ones are rejected.
+        let provider = test_provider(db.clone()).spawn()?;
+        let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+        let (resume_tx, resume_rx) = tokio::sync::oneshot::channel::<()>();
+        let transfer = stalled_transfer(&provider, hash, started_tx, async {
+            resume_rx.await.ok();
+        });
+        let shutdown = async {
+            started_rx.await?;
+            let shutdown = provider.graceful_shutdown(Duration::from_secs(30));
+            let (started_tx, _started_rx) = tokio::sync::oneshot::channel();
+            stalled_transfer(&provider, hash, started_tx, async {})
+                .await
+                .expect_err("no new transfers while shutting down");
+            resume_tx.send(()).ok();
+            anyhow::Ok(shutdown.await)
+        };
+        let (res, stats) = tokio::join!(transfer, shutdown);
+        res?;
+        assert_eq!(
+            stats?,
+            provider::ShutdownStats {
+                drained: 1,
+                aborted: 0
+            }
+        );
+        provider.await?;
+
+        // A transfer which does not finish in time is aborted.
+        let provider = test_provider(db).spawn()?;
+        let (started_tx, started_rx) = tokio::sync::oneshot::channel();
+        let transfer = stalled_transfer(&provider, hash, started_tx, async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        });
+        let shutdown = async {
+            started_rx.await?;
+            anyhow::Ok(provider.graceful_shutdown(Duration::from_millis(100)).await)
+        };
+        let (res, stats) = tokio::join!(transfer, shutdown);
+        res.expect_err("transfer should be aborted");
+        assert_eq!(
+            stats?,
+            provider::ShutdownStats {
+                drained: 0,
+                aborted: 1
+            }
+        );
+        provider.await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn modified_content() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let foo = dir.join("foo");
+        tokio::fs::write(&foo, b"hello foo").await?;
+        let (db, hash) = create_collection(vec![foo.clone().into()]).await?;
+        let provider = test_provider(db).spawn()?;
+        let mut events = provider.subscribe();
+
+        // Same size, but a different modification time.
+        tokio::fs::write(&foo, b"hello bar").await?;
+        filetime::set_file_mtime(&foo, filetime::FileTime::from_unix_time(1_000_000_000, 0))?;
+
+        let opts = get_options(&provider);
+        let get_hash = |hash| {
+            get::run(
+                hash,
+                provider.auth_token(),
+                opts.clone(),
+                || async { Ok(()) },
+                |_collection| async { panic!("incomplete collection must not be sent") },
+                discard,
+            )
+        };
+        let blob_hash = Hash::new(b"hello foo");
+
+        // The collection is refused up front.
+        let err = get_hash(hash)
+            .await
+            .expect_err("modified data must not be sent");
+        assert_eq!(
+            err.downcast_ref::<get::IncompleteCollection>(),
+            Some(&get::IncompleteCollection { missing: vec![0] })
+        );
+
+        let err = get_hash(blob_hash)
+            .await
+            .expect_err("modified data must not be sent");
+        assert!(err.to_string().contains("modified"), "{err}");
+
+        loop {
+            if let Event::BlobModified {
+                hash: blob_hash,
+                path,
+                ..
+            } = events.recv().await?
+            {
+                assert_eq!(blob_hash, Hash::new(b"hello foo"));
+                assert_eq!(path, foo);
+                break;
+            }
+        }
+
+        provider.shutdown();
+        provider.await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "cli")]
+    #[tokio::test]
+    async fn watch_directory() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let src = dir.join("src");
+        tokio::fs::create_dir_all(src.join("sub")).await?;
+        tokio::fs::write(src.join("foo"), b"hello foo").await?;
+        tokio::fs::write(src.join("sub").join("bar"), b"hello bar").await?;
+        tokio::fs::write(src.join("sub").join("qux"), b"hello qux").await?;
+        // Large enough for all events of the test, which are only received at the end.
+        let (sink, mut events) = tokio::sync::mpsc::channel(1024);
+        let provider = test_provider(provider::Database::default())
+            .event_sink(sink)
+            .spawn()?;
+
+        // A blob added on its own, which must not be removed with the file of the watch.
+        let qux = dir.join("qux");
+        tokio::fs::write(&qux, b"hello qux").await?;
+        let (qux_db, qux_collection) = create_collection(vec![qux.into()]).await?;
+        provider.add(&qux_db);
+        provider.remove(&qux_collection);
+
+        let opts = get_options(&provider);
+        let get_collection = |hash, auth: Auth| {
+            let opts = opts.clone();
+            async move {
+                let received = std::sync::Mutex::new(Vec::new());
+                get::run(
+                    hash,
+                    auth,
+                    opts,
+                    || async { Ok(()) },
+                    |_collection| async { Ok(()) },
+                    |_hash, mut reader, name| {
+                        let received = &received;
+                        async move {
+                            let mut got = Vec::new();
+                            reader.read_to_end(&mut got).await?;
+                            received.lock().unwrap().push((name, got));
+                            Ok(reader)
+                        }
+                    },
+                )
+                .await?;
+                anyhow::Ok(received.into_inner().unwrap())
+            }
+        };
+
+        let hash = provider.watch(&src).await?;
+        assert_eq!(
+            get_collection(hash, provider.auth_token().into()).await?,
+            vec![
+                ("foo".to_string(), b"hello foo".to_vec()),
+                ("sub/bar".to_string(), b"hello bar".to_vec()),
+                ("sub/qux".to_string(), b"hello qux".to_vec()),
+            ]
+        );
+
+        // Content of another collection, which must not be removed with the changed file.
+        let other = dir.join("other");
+        tokio::fs::write(&other, b"hello foo").await?;
+        let (other_db, other_hash) = create_collection(vec![other.into()]).await?;
+        provider.database().merge(&other_db);
+
+        // Changing files publishes a new collection, replacing the previous one.
+        tokio::fs::write(src.join("foo"), b"hello foo, changed").await?;
+        tokio::fs::write(src.join("sub").join("baz"), b"hello baz").await?;
+        tokio::fs::remove_file(src.join("sub").join("qux")).await?;
+        let mut published = Vec::new();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while published.len() < 2 {
+                let event = events.recv().await.context("provider stopped")?;
+                if let Event::CollectionPublished { hash, ticket } = event {
+                    assert_eq!(ticket.hash, hash);
+                    assert_eq!(ticket.peer, provider.peer_id());
+                    published.push((hash, ticket.auth));
+                }
+            }
+            anyhow::Ok(())
+        })
+        .await??;
+        // The initial collection is announced as well, all tickets share a token.
+        assert_eq!(published[0].0, hash);
+        let (new_hash, ref auth) = published[1];
+        assert_ne!(new_hash, hash);
+        assert_eq!(*auth, published[0].1);
+        assert_eq!(
+            get_collection(new_hash, auth.clone()).await?,
+            vec![
+                ("foo".to_string(), b"hello foo, changed".to_vec()),
+                ("sub/bar".to_string(), b"hello bar".to_vec()),
+                ("sub/baz".to_string(), b"hello baz".to_vec()),
+            ]
+        );
+        let mut collections: Vec<_> = provider.database().collections().collect();
+        collections.sort_by_key(|hash| *hash != new_hash);
+        assert_eq!(collections, vec![new_hash, other_hash]);
+        get_collection(hash, provider.auth_token().into())
+            .await
+            .expect_err("previous collection is removed");
+        get_collection(hash, auth.clone())
+            .await
+            .expect_err("token moved to the new collection");
+        assert_eq!(
+            get_collection(other_hash, provider.auth_token().into()).await?,
+            vec![("other".to_string(), b"hello foo".to_vec())]
+        );
+        let qux_hash = Hash::new(b"hello qux");
+        assert!(provider
+            .database()
+            .blobs()
+            .any(|(hash, _, _)| hash == qux_hash));
+
+        provider.shutdown();
+        provider.await?;
+        Ok(())
+    }
+
     // Run the test creating random data for each blob, using the size specified by the file
     // options
     async fn transfer_random_data<S>(file_opts: Vec<(S, usize)>) -> Result<()>
@@ -206,11 +1448,14 @@ mod tests {
         let (db, collection_hash) = provider::create_collection(files).await?;
 
         let addr = "127.0.0.1:0".parse().unwrap();
-        let provider = provider::Provider::builder(db).bind_addr(addr).spawn()?;
-        let mut provider_events = provider.subscribe();
+        let (sink, mut provider_events) = tokio::sync::mpsc::channel(8);
+        let provider = provider::Provider::builder(db)
+            .bind_addr(addr)
+            .event_sink(sink)
+            .spawn()?;
         let events_task = tokio::task::spawn(async move {
             let mut events = Vec::new();
-            while let Ok(event) = provider_events.recv().await {
+            while let Some(event) = provider_events.recv().await {
                 match event {
                     Event::TransferCompleted { .. } | Event::TransferAborted { .. } => {
                         events.push(event);
@@ -226,6 +1471,8 @@ mod tests {
             addr: provider.listen_addr(),
             peer_id: Some(provider.peer_id()),
             keylog: true,
+            keypair: None,
+            limits: Default::default(),
         };
 
         let i = AtomicUsize::new(0);
@@ -267,16 +1514,37 @@ mod tests {
         provider.shutdown();
         provider.await?;
 
-        assert_events(events);
+        assert_events(events, num_blobs);
 
         Ok(())
     }
 
-    fn assert_events(events: Vec<Event>) {
-        assert_eq!(events.len(), 3);
-        assert!(matches!(events[0], Event::ClientConnected { .. }));
+    fn assert_events(events: Vec<Event>, num_blobs: usize) {
+        let events: Vec<_> = events
+            .into_iter()
+            .filter(|event| !matches!(event, Event::TransferProgress { .. }))
+            .collect();
+        assert_eq!(events.len(), 3 + 2 * num_blobs);
+        assert!(matches!(
+            events[0],
+            Event::ClientConnected {
+                peer_id: Some(_),
+                ..
+            }
+        ));
         assert!(matches!(events[1], Event::RequestReceived { .. }));
-        assert!(matches!(events[2], Event::TransferCompleted { .. }));
+        for blob in events[2..events.len() - 1].chunks(2) {
+            match blob {
+                [Event::BlobStarted { hash, .. }, Event::BlobCompleted { hash: sent, .. }] => {
+                    assert_eq!(hash, sent)
+                }
+                _ => panic!("unexpected events {blob:?}"),
+            }
+        }
+        assert!(matches!(
+            events[events.len() - 1],
+            Event::TransferCompleted { .. }
+        ));
     }
 
     fn setup_logging() {
@@ -335,6 +1603,8 @@ mod tests {
                 addr: provider_addr,
                 peer_id: None,
                 keylog: true,
+                keypair: None,
+                limits: Default::default(),
             },
             || async move { Ok(()) },
             |_collection| async move { Ok(()) },
@@ -384,6 +1654,8 @@ mod tests {
                     addr: provider_addr,
                     peer_id: None,
                     keylog: true,
+                    keypair: None,
+                    limits: Default::default(),
                 },
                 || async move { Ok(()) },
                 |_collection| async move { Ok(()) },
@@ -1,15 +1,28 @@
-use std::{fmt, net::SocketAddr, path::PathBuf, str::FromStr};
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    fmt,
+    io::SeekFrom,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::{bail, Context, Result};
-use clap::{Parser, Subcommand};
+use anyhow::{bail, ensure, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
 use console::style;
 use indicatif::{
     HumanBytes, HumanDuration, ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle,
 };
-use sendme::protocol::AuthToken;
-use sendme::provider::Ticket;
-use tokio::io::AsyncWriteExt;
-use tokio::sync::Mutex;
+use sendme::archive::{self, TarWriter};
+use sendme::blobs::{entry_path, EntryKind, Metadata};
+use sendme::capability::Capability;
+use sendme::protocol::{Auth, AuthToken};
+use sendme::provider::{AccessLog, Ticket};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{mpsc, Mutex};
 use tracing_subscriber::{prelude::*, EnvFilter};
 
 use sendme::{get, provider, Hash, Keypair, PeerId};
@@ -25,7 +38,7 @@ struct Cli {
 #[derive(Subcommand, Debug, Clone)]
 #[allow(clippy::large_enum_variant)]
 enum Commands {
-    /// Serve the data from the given path. If it is a folder, all files in that folder will be served. If none is specified reads from STDIN.
+    /// Serve the data from the given path. If it is a folder, all files in that folder will be served. If none is specified reads from STDIN, unless --data-dir is given.
     #[clap(about = "Serve the data from the given path")]
     Provide {
         path: Option<PathBuf>,
@@ -42,6 +55,36 @@ enum Commands {
         /// Log SSL pre-master key to file in SSLKEYLOGFILE environment variable.
         #[clap(long)]
         keylog: bool,
+        /// Directory of a persistent store. Data from the given path is added to the store and everything in the store is served. If no path is given, only the existing store is served.
+        #[clap(long)]
+        data_dir: Option<PathBuf>,
+        /// Format of the data read from STDIN. A tar archive is served as a collection of its entries.
+        #[clap(long, value_enum, default_value_t = Format::Raw)]
+        format: Format,
+        /// Name of the data read from STDIN in raw format. If none is specified the data is unnamed and stored under its hash.
+        #[clap(long, conflicts_with = "path")]
+        name: Option<String>,
+        /// Watch the directory given as path, publishing a new collection whenever its files change.
+        #[clap(long, requires = "path", conflicts_with = "data_dir")]
+        watch: bool,
+        /// Print tickets with a capability signed by the provider, expiring after this many seconds, instead of a token.
+        #[clap(long)]
+        ticket_expiry: Option<u64>,
+        /// Only allow getters with this PeerID to connect, they can get all data without a token. Can be given multiple times.
+        #[clap(long)]
+        allow_peer: Vec<PeerId>,
+        /// Limit the total upload bandwidth, in bytes per second. Binary units like 512K or 10M can be used.
+        #[clap(long, value_parser = parse_rate)]
+        max_rate: Option<u64>,
+        /// Limit the upload bandwidth for each getter, in bytes per second. Binary units like 512K or 10M can be used.
+        #[clap(long, value_parser = parse_rate)]
+        max_rate_per_peer: Option<u64>,
+        /// Serve Prometheus metrics over HTTP at /metrics on this address.
+        #[clap(long)]
+        metrics_addr: Option<SocketAddr>,
+        /// Write a JSON-lines record of every request to this file, which is rotated when it grows beyond 100MiB.
+        #[clap(long)]
+        access_log: Option<PathBuf>,
     },
     /// Fetch some data by hash.
     #[clap(about = "Fetch the data from the hash")]
@@ -51,9 +94,9 @@ enum Commands {
         /// PeerId of the provider.
         #[clap(long, short)]
         peer: PeerId,
-        /// The authentication token to present to the server.
-        #[clap(long)]
-        token: String,
+        /// The authentication token to present to the server. If none is specified the PeerID of `--key` must be allowed by the provider.
+        #[clap(long, required_unless_present = "key")]
+        token: Option<String>,
         /// Optional address of the provider, defaults to 127.0.0.1:4433.
         #[clap(long, short)]
         addr: Option<SocketAddr>,
@@ -63,6 +106,15 @@ enum Commands {
         /// Log SSL pre-master key to file in SSLKEYLOGFILE environment variable.
         #[clap(long)]
         keylog: bool,
+        /// Restore the file modes and modification times of the collection when writing to `--out`.
+        #[clap(long)]
+        preserve: bool,
+        /// Format of the data written to STDOUT, defaults to tar for collections with more than one entry.
+        #[clap(long, value_enum, conflicts_with = "out")]
+        format: Option<Format>,
+        /// If this path is provided and it exists, the private key identifying the getter is read from this file, if it does not exist the private key will be persisted to this location.
+        #[clap(long)]
+        key: Option<PathBuf>,
     },
     /// Fetches some data from a ticket,
     ///
@@ -81,6 +133,15 @@ enum Commands {
         /// Log SSL pre-master key to file in SSLKEYLOGFILE environment variable.
         #[clap(long)]
         keylog: bool,
+        /// Restore the file modes and modification times of the collection when writing to `--out`.
+        #[clap(long)]
+        preserve: bool,
+        /// Format of the data written to STDOUT, defaults to tar for collections with more than one entry.
+        #[clap(long, value_enum, conflicts_with = "out")]
+        format: Option<Format>,
+        /// If this path is provided and it exists, the private key identifying the getter is read from this file, if it does not exist the private key will be persisted to this location.
+        #[clap(long)]
+        key: Option<PathBuf>,
     },
 }
 
@@ -195,6 +256,8 @@ impl FromStr for Blake3Cid {
     }
 }
 
+const STDIN_PROGRESS_STYLE: &str = "{spinner:.green} Reading STDIN {bytes} ({bytes_per_sec})";
+
 const PROGRESS_STYLE: &str =
     "{msg}\n{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})";
 
@@ -215,20 +278,28 @@ async fn main() -> Result<()> {
             addr,
             out,
             keylog,
+            preserve,
+            format,
+            key,
         } => {
             let mut opts = get::Options {
                 peer_id: Some(peer),
                 keylog,
+                keypair: get_getter_keypair(key).await?,
                 ..Default::default()
             };
             if let Some(addr) = addr {
                 opts.addr = addr;
             }
-            let token =
-                AuthToken::from_str(&token).context("Wrong format for authentication token")?;
+            let auth = match token {
+                Some(token) => AuthToken::from_str(&token)
+                    .context("Wrong format for authentication token")?
+                    .into(),
+                None => Auth::Identity,
+            };
             tokio::select! {
                 biased;
-                res = get_interactive(*hash.as_hash(), opts, token, out) => {
+                res = get_interactive(*hash.as_hash(), opts, auth, out, preserve, format) => {
                     res
                 }
                 _ = tokio::signal::ctrl_c() => {
@@ -241,21 +312,26 @@ async fn main() -> Result<()> {
             out,
             ticket,
             keylog,
+            preserve,
+            format,
+            key,
         } => {
             let Ticket {
                 hash,
                 peer,
                 addr,
-                token,
+                auth,
             } = ticket;
             let opts = get::Options {
                 addr,
                 peer_id: Some(peer),
                 keylog,
+                keypair: get_getter_keypair(key).await?,
+                limits: Default::default(),
             };
             tokio::select! {
                 biased;
-                res = get_interactive(hash, opts, token, out) => {
+                res = get_interactive(hash, opts, auth, out, preserve, format) => {
                     res
                 }
                 _ = tokio::signal::ctrl_c() => {
@@ -270,10 +346,21 @@ async fn main() -> Result<()> {
             auth_token,
             key,
             keylog,
+            data_dir,
+            format,
+            name,
+            watch,
+            ticket_expiry,
+            allow_peer,
+            max_rate,
+            max_rate_per_peer,
+            metrics_addr,
+            access_log,
         } => {
+            let ticket_expiry = ticket_expiry.map(Duration::from_secs);
             tokio::select! {
                 biased;
-                res = provide_interactive(path, addr, auth_token, key, keylog) => {
+                res = provide_interactive(path, addr, auth_token, key, keylog, data_dir, format, name, watch, ticket_expiry, allow_peer, max_rate, max_rate_per_peer, metrics_addr, access_log) => {
                     res
                 }
                 _ = tokio::signal::ctrl_c() => {
@@ -285,49 +372,132 @@ async fn main() -> Result<()> {
     }
 }
 
+/// Parses a rate in bytes per second, with an optional binary unit like `512K` or `10MiB`.
+fn parse_rate(s: &str) -> Result<u64> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number.parse().context("invalid rate")?;
+    let multiplier = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1024,
+        "m" | "mib" => 1024 * 1024,
+        "g" | "gib" => 1024 * 1024 * 1024,
+        unit => bail!("unknown unit {unit}"),
+    };
+    let rate = number.checked_mul(multiplier).context("rate too large")?;
+    ensure!(rate > 0, "rate must not be zero");
+    Ok(rate)
+}
+
+/// Format of data read from STDIN or written to STDOUT.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// The data of the blobs, back to back.
+    Raw,
+    /// A tar archive of the entries of the collection.
+    Tar,
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn provide_interactive(
     path: Option<PathBuf>,
     addr: Option<SocketAddr>,
     auth_token: Option<String>,
     key: Option<PathBuf>,
     keylog: bool,
+    data_dir: Option<PathBuf>,
+    format: Format,
+    name: Option<String>,
+    watch: bool,
+    ticket_expiry: Option<Duration>,
+    allow_peer: Vec<PeerId>,
+    max_rate: Option<u64>,
+    max_rate_per_peer: Option<u64>,
+    metrics_addr: Option<SocketAddr>,
+    access_log: Option<PathBuf>,
 ) -> Result<()> {
     let out_writer = OutWriter::new();
     let keypair = get_keypair(key).await?;
 
     let mut tmp_path = None;
+    let mut tmp_dir = None;
 
-    let sources = if let Some(path) = path {
+    // A watched directory is hashed by the provider once it is running.
+    let (path, watch_dir) = match path {
+        Some(path) if watch => {
+            ensure!(path.is_dir(), "can only watch a directory");
+            (None, Some(path))
+        }
+        path => (path, None),
+    };
+
+    let created = if let Some(path) = path {
         out_writer
             .println(format!("Reading {}", path.display()))
             .await;
-        if path.is_dir() {
-            let mut paths = Vec::new();
-            let mut iter = tokio::fs::read_dir(&path).await?;
-            while let Some(el) = iter.next_entry().await? {
-                if el.path().is_file() {
-                    paths.push(el.path().into());
-                }
-            }
-            paths
+        let sources = if path.is_dir() {
+            provider::data_sources_from_dir(&path).await?
         } else if path.is_file() {
             vec![path.into()]
         } else {
             bail!("path must be either a Directory or a File");
-        }
+        };
+        Some(provider::create_collection(sources).await?)
+    } else if data_dir.is_some() || watch_dir.is_some() {
+        None
+    } else if format == Format::Tar {
+        // Unpack the tar archive from STDIN into a temporary directory
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().to_path_buf();
+        tmp_dir = Some(dir);
+        archive::unpack(std::io::stdin(), path.clone()).await?;
+        let sources = provider::data_sources_from_dir(&path).await?;
+        Some(provider::create_collection(sources).await?)
     } else {
-        // Store STDIN content into a temporary file
-        let (file, path) = tempfile::NamedTempFile::new()?.into_parts();
-        let mut file = tokio::fs::File::from_std(file);
+        // Store STDIN content into a temporary file, hashing it on the way
+        let path = tempfile::NamedTempFile::new()?.into_temp_path();
         let path_buf = path.to_path_buf();
         tmp_path = Some(path);
-        tokio::io::copy(&mut tokio::io::stdin(), &mut file).await?;
-        vec![path_buf.into()]
+        let pb = ProgressBar::new_spinner();
+        pb.set_style(ProgressStyle::with_template(STDIN_PROGRESS_STYLE).unwrap());
+        pb.set_draw_target(ProgressDrawTarget::stderr());
+        let stdin = pb.wrap_async_read(tokio::io::stdin());
+        let name = name.unwrap_or_default();
+        let created = provider::create_collection_from_reader(stdin, path_buf, name).await?;
+        pb.finish_and_clear();
+        Some(created)
     };
 
-    let (db, hash) = provider::create_collection(sources).await?;
+    let (db, hashes) = match data_dir {
+        Some(data_dir) => {
+            let new_hash = match created {
+                Some((db, hash)) => {
+                    db.save(&data_dir).await?;
+                    Some(hash)
+                }
+                None => None,
+            };
+            out_writer
+                .println(format!("Loading store {}", data_dir.display()))
+                .await;
+            let db = provider::Database::load(&data_dir).await?;
+            // Only announce the newly added collection, or everything if nothing was added.
+            let hashes = match new_hash {
+                Some(hash) => vec![hash],
+                None => db.collections().collect(),
+            };
+            (db, hashes)
+        }
+        None => match created {
+            Some((db, hash)) => (db, vec![hash]),
+            None => (provider::Database::default(), Vec::new()),
+        },
+    };
 
-    println!("Collection: {}\n", Blake3Cid::new(hash));
+    for hash in &hashes {
+        println!("Collection: {}", Blake3Cid::new(*hash));
+    }
+    println!();
     for (_, path, size) in db.blobs() {
         println!("- {}: {} bytes", path.display(), size);
     }
@@ -342,6 +512,31 @@ async fn provide_interactive(
         let auth_token = AuthToken::from_str(encoded)?;
         builder = builder.auth_token(auth_token);
     }
+    if !allow_peer.is_empty() {
+        builder = builder.allowed_peers(allow_peer);
+    }
+    if let Some(max_rate) = max_rate {
+        builder = builder.max_rate(max_rate);
+    }
+    if let Some(max_rate) = max_rate_per_peer {
+        builder = builder.max_rate_per_peer(max_rate);
+    }
+    if let Some(addr) = metrics_addr {
+        builder = builder.metrics_addr(addr);
+    }
+    if let Some(path) = access_log {
+        builder = builder.event_sink(AccessLog::new(path)?);
+    }
+    // The tickets of published collections must not be missed, so they are received from a
+    // sink rather than a subscription, which drops events when it lags behind.
+    let published = match watch_dir {
+        Some(_) => {
+            let (sink, events) = mpsc::channel(8);
+            builder = builder.event_sink(sink);
+            Some(events)
+        }
+        None => None,
+    };
     let provider = builder.spawn()?;
 
     out_writer
@@ -350,16 +545,65 @@ async fn provide_interactive(
     out_writer
         .println(format!("Auth token: {}", provider.auth_token()))
         .await;
-    out_writer
-        .println(format!("All-in-one ticket: {}", provider.ticket(hash)))
-        .await;
-    provider.await?;
+    if let Some(addr) = provider.metrics_addr() {
+        out_writer
+            .println(format!("Metrics: http://{addr}/metrics"))
+            .await;
+    }
+    if let Some(dir) = watch_dir {
+        out_writer
+            .println(format!("Watching {}", dir.display()))
+            .await;
+        // The ticket of the initial collection is printed from its event, like those of the
+        // following ones.
+        match ticket_expiry {
+            Some(expiry) => provider.watch_with_ticket_expiry(dir, expiry).await?,
+            None => provider.watch(dir).await?,
+        };
+    }
+    for hash in hashes {
+        let ticket = match ticket_expiry {
+            Some(expiry) => {
+                let capability = Capability::new([hash]).expires_in(expiry);
+                provider.ticket_with_capability(hash, capability)
+            }
+            None => provider.ticket(hash),
+        };
+        out_writer
+            .println(format!("All-in-one ticket: {ticket}"))
+            .await;
+    }
+    let print_published = async {
+        match published {
+            Some(events) => print_published(events, &out_writer).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        res = provider => res?,
+        _ = print_published => {}
+    }
 
     // Drop tempath to signal it can be destroyed
     drop(tmp_path);
+    drop(tmp_dir);
     Ok(())
 }
 
+/// Prints the tickets of the collections published while watching a directory.
+async fn print_published(mut events: mpsc::Receiver<provider::Event>, out_writer: &OutWriter) {
+    while let Some(event) = events.recv().await {
+        if let provider::Event::CollectionPublished { hash, ticket } = event {
+            out_writer
+                .println(format!("Collection: {}", Blake3Cid::new(hash)))
+                .await;
+            out_writer
+                .println(format!("All-in-one ticket: {ticket}"))
+                .await;
+        }
+    }
+}
+
 async fn get_keypair(key: Option<PathBuf>) -> Result<Keypair> {
     match key {
         Some(key_path) => {
@@ -381,13 +625,30 @@ async fn get_keypair(key: Option<PathBuf>) -> Result<Keypair> {
     }
 }
 
+/// Returns the persisted keypair of the getter, if any.
+///
+/// Without a key the getter uses a new identity for every connection.
+async fn get_getter_keypair(key: Option<PathBuf>) -> Result<Option<Arc<Keypair>>> {
+    match key {
+        Some(key) => Ok(Some(Arc::new(get_keypair(Some(key)).await?))),
+        None => Ok(None),
+    }
+}
+
 async fn get_interactive(
     hash: Hash,
     opts: get::Options,
-    token: AuthToken,
+    auth: Auth,
     out: Option<PathBuf>,
+    preserve: bool,
+    format: Option<Format>,
 ) -> Result<()> {
     let out_writer = OutWriter::new();
+    if let Some(ref keypair) = opts.keypair {
+        out_writer
+            .println(format!("PeerID: {}", PeerId::from(keypair.public())))
+            .await;
+    }
     out_writer
         .println(format!("Fetching: {}", Blake3Cid::new(hash)))
         .await;
@@ -410,6 +671,30 @@ async fn get_interactive(
             .progress_chars("#>-"),
     );
 
+    // When writing to a directory, partial downloads are kept so they can be resumed.
+    let (resume, resumed_size) = match out {
+        Some(ref outpath) => {
+            tokio::fs::create_dir_all(outpath)
+                .await
+                .with_context(|| format!("Unable to create directory {}", outpath.display()))?;
+            let (state, resumed_size) = load_resume_state(outpath, hash).await?;
+            if resumed_size > 0 {
+                out_writer
+                    .println(format!(
+                        "  Resuming, {} already downloaded",
+                        HumanBytes(resumed_size)
+                    ))
+                    .await;
+            }
+            (Some(Mutex::new(state)), resumed_size)
+        }
+        None => (None, 0),
+    };
+    let ranges = match resume {
+        Some(ref state) => state.lock().await.ranges(),
+        None => Vec::new(),
+    };
+
     let on_connected = || {
         let out_writer = &out_writer;
         async move {
@@ -419,13 +704,35 @@ async fn get_interactive(
             Ok(())
         }
     };
+    let received_collection = Mutex::new(None);
+    // Set when writing a tar archive to stdout.
+    let tar = Mutex::new(None);
     let on_collection = |collection: &sendme::blobs::Collection| {
         let pb = &pb;
         let out_writer = &out_writer;
+        let out = &out;
+        let resume = &resume;
+        let received_collection = &received_collection;
+        let tar = &tar;
         let name = collection.name().to_string();
         let total_entries = collection.total_entries();
         let size = collection.total_blobs_size();
+        let collection = collection.clone();
         async move {
+            if let (Some(outpath), Some(state)) = (out, resume) {
+                let mut state = state.lock().await;
+                state.set_collection(&collection)?;
+                save_resume_state(outpath, &state).await?;
+                create_entries_without_data(outpath, &collection).await?;
+            }
+            let use_tar = match format {
+                Some(format) => format == Format::Tar,
+                None => collection.blobs().len() > 1,
+            };
+            if out.is_none() && use_tar {
+                let entries = collection.blobs().iter().cloned().collect();
+                *tar.lock().await = Some(TarWriter::new(tokio::io::stdout(), entries));
+            }
             out_writer
                 .println(format!(
                     "{} Downloading {name}...",
@@ -440,63 +747,134 @@ async fn get_interactive(
                 .await;
             pb.set_length(size);
             pb.reset();
+            pb.set_position(resumed_size);
             pb.set_draw_target(ProgressDrawTarget::stderr());
+            *received_collection.lock().await = Some(collection);
 
             Ok(())
         }
     };
 
-    let on_blob = |hash: Hash, mut reader, name: String| {
+    let on_blob = |hash: Hash, mut reader: get::DataStream, name: String| {
         let out = &out;
         let pb = &pb;
+        let out_writer = &out_writer;
+        let resume = &resume;
+        let tar = &tar;
         async move {
-            let name = if name.is_empty() {
-                hash.to_string()
-            } else {
-                name
-            };
+            if pb.length().is_none() {
+                // A single blob was requested, there was no collection to set up progress.
+                if let (Some(outpath), Some(state)) = (out, resume) {
+                    let mut state = state.lock().await;
+                    state.set_blob();
+                    save_resume_state(outpath, &state).await?;
+                }
+                let size = reader.read_size().await?;
+                out_writer
+                    .println(format!(
+                        "{} Downloading {hash} with transfer size {}...",
+                        style("[3/3]").bold().dim(),
+                        HumanBytes(size)
+                    ))
+                    .await;
+                pb.set_length(size);
+                pb.reset();
+                pb.set_position(resumed_size);
+                pb.set_draw_target(ProgressDrawTarget::stderr());
+                if out.is_none() && format == Some(Format::Tar) {
+                    *tar.lock().await = Some(TarWriter::new(tokio::io::stdout(), VecDeque::new()));
+                }
+            }
+            let name = blob_file_name(&name, hash);
+            let size = reader.read_size().await?;
             pb.set_message(format!("Receiving '{name}'..."));
 
-            // Wrap the reader to show progress.
-            let mut wrapped_reader = pb.wrap_async_read(&mut reader);
-
+            let offset = reader.offset();
             if let Some(ref outpath) = out {
-                tokio::fs::create_dir_all(outpath)
+                let filepath = entry_path(outpath, &name)?;
+                if let Some(parent) = filepath.parent() {
+                    tokio::fs::create_dir_all(parent)
+                        .await
+                        .context("Failed to create output directory")?;
+                }
+
+                // Write to the partial file, which is kept if the transfer is interrupted.
+                let partial_path = partial_path(&filepath);
+                let mut file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .truncate(false)
+                    .write(true)
+                    .open(&partial_path)
                     .await
-                    .context("Unable to create directory {outpath}")?;
-                let dirpath = std::path::PathBuf::from(outpath);
-                let filepath = dirpath.join(name);
-
-                // Create temp file
-                let (temp_file, dup) = tokio::task::spawn_blocking(|| {
-                    let temp_file = tempfile::Builder::new()
-                        .prefix("sendme-tmp-")
-                        .tempfile_in(dirpath)
-                        .context("Failed to create temporary output file")?;
-                    let dup = temp_file.as_file().try_clone()?;
-                    Ok::<_, anyhow::Error>((temp_file, dup))
-                })
-                .await??;
-
-                let file = tokio::fs::File::from_std(dup);
+                    .context("Failed to create partial output file")?;
+                let len = file.metadata().await?.len();
+                ensure!(
+                    len >= offset,
+                    "Partial download {} changed, please retry",
+                    partial_path.display()
+                );
+                if offset > 0 && !reader.verify_prefix(&partial_path).await? {
+                    drop(file);
+                    tokio::fs::remove_file(&partial_path).await.ok();
+                    bail!(
+                        "Partial download {} does not match the hash and was removed, please retry",
+                        partial_path.display()
+                    );
+                }
+                // Only keep the data up to where the provider resumes sending.
+                file.set_len(offset).await?;
+                file.seek(SeekFrom::Start(offset)).await?;
+                // Wrap the reader to show progress.
+                let mut wrapped_reader = pb.wrap_async_read(&mut reader);
                 let mut file_buf = tokio::io::BufWriter::new(file);
                 tokio::io::copy(&mut wrapped_reader, &mut file_buf).await?;
+                file_buf.flush().await?;
 
-                // Rename temp file, to target name
-                let filepath2 = filepath.clone();
-                tokio::task::spawn_blocking(|| temp_file.persist(filepath2))
-                    .await?
+                // Rename partial file, to target name
+                tokio::fs::rename(&partial_path, &filepath)
+                    .await
                     .context("Failed to write output file")?;
             } else {
-                // Write to OUT_WRITER
-                let mut stdout = tokio::io::stdout();
-                tokio::io::copy(&mut wrapped_reader, &mut stdout).await?;
+                // Wrap the reader to show progress.
+                let mut wrapped_reader = pb.wrap_async_read(&mut reader);
+                if let Some(tar) = tar.lock().await.as_mut() {
+                    tar.append_file(&name, size, &mut wrapped_reader).await?;
+                } else {
+                    // Write to OUT_WRITER
+                    let mut stdout = tokio::io::stdout();
+                    tokio::io::copy(&mut wrapped_reader, &mut stdout).await?;
+                }
             }
 
             Ok(reader)
         }
     };
-    let stats = get::run(hash, token, opts, on_connected, on_collection, on_blob).await?;
+    let stats = get::run_ranges(
+        hash,
+        ranges,
+        auth,
+        opts,
+        on_connected,
+        on_collection,
+        on_blob,
+    )
+    .await?;
+
+    if let Some(tar) = tar.into_inner() {
+        tar.finish().await?;
+    }
+
+    if let Some(ref outpath) = out {
+        if preserve {
+            if let Some(ref collection) = *received_collection.lock().await {
+                restore_metadata(outpath, collection).await?;
+            }
+        }
+        // The download is complete, nothing left to resume.
+        tokio::fs::remove_file(resume_state_path(outpath, hash))
+            .await
+            .ok();
+    }
 
     pb.finish_and_clear();
     out_writer
@@ -505,3 +883,152 @@ async fn get_interactive(
 
     Ok(())
 }
+
+/// The file name of a blob written to the output directory.
+fn blob_file_name(name: &str, hash: Hash) -> String {
+    if name.is_empty() {
+        hash.to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// Creates the directories and symlinks of a collection below `out`.
+async fn create_entries_without_data(
+    out: &Path,
+    collection: &sendme::blobs::Collection,
+) -> Result<()> {
+    for blob in collection.blobs() {
+        let kind = match blob.metadata() {
+            Metadata::V1(meta) => &meta.kind,
+            _ => continue,
+        };
+        let path = entry_path(out, blob.name())?;
+        match kind {
+            EntryKind::File => {}
+            EntryKind::Directory => {
+                tokio::fs::create_dir_all(&path)
+                    .await
+                    .with_context(|| format!("Failed to create directory {}", path.display()))?;
+            }
+            EntryKind::Symlink(target) => {
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                if tokio::fs::symlink_metadata(&path).await.is_ok() {
+                    tokio::fs::remove_file(&path).await?;
+                }
+                create_symlink(target, &path)
+                    .await
+                    .with_context(|| format!("Failed to create symlink {}", path.display()))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn create_symlink(target: &str, path: &Path) -> Result<()> {
+    tokio::fs::symlink(target, path).await?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn create_symlink(_target: &str, path: &Path) -> Result<()> {
+    tracing::warn!("symlinks are not supported, skipping {}", path.display());
+    Ok(())
+}
+
+/// Restores the modes and modification times of the entries of a collection below `out`.
+///
+/// Entries are processed in reverse, so directories are updated after their content.
+async fn restore_metadata(out: &Path, collection: &sendme::blobs::Collection) -> Result<()> {
+    for blob in collection.blobs().iter().rev() {
+        let meta = match blob.metadata() {
+            Metadata::V1(meta) => meta.clone(),
+            _ => continue,
+        };
+        let path = entry_path(out, blob.name())?;
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let is_symlink = matches!(meta.kind, EntryKind::Symlink(_));
+            // Symlinks are never followed, a symlink where the collection has none might
+            // point outside of the output directory.
+            if std::fs::symlink_metadata(&path)?.file_type().is_symlink() != is_symlink {
+                return Ok(());
+            }
+            #[cfg(unix)]
+            if let (Some(mode), false) = (meta.mode, is_symlink) {
+                use std::os::unix::fs::PermissionsExt;
+                let permissions = std::fs::Permissions::from_mode(mode & 0o777);
+                std::fs::set_permissions(&path, permissions)?;
+            }
+            if let Some(mtime) = meta.mtime {
+                let mtime = filetime::FileTime::from_unix_time(mtime as i64, 0);
+                if is_symlink {
+                    filetime::set_symlink_file_times(&path, mtime, mtime)?;
+                } else {
+                    filetime::set_file_mtime(&path, mtime)?;
+                }
+            }
+            Ok(())
+        })
+        .await?
+        .with_context(|| format!("Failed to restore metadata of {}", blob.name()))?;
+    }
+    Ok(())
+}
+
+/// The path of the file holding the partially downloaded data of the blob written to
+/// `filepath`.
+///
+/// Partial files are keyed by the entry they are written to rather than by hash, as
+/// different entries can have the same content.
+fn partial_path(filepath: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(filepath.file_name().unwrap_or_default());
+    name.push(".sendme-partial");
+    filepath.with_file_name(name)
+}
+
+/// The path of the file holding the state to resume the download of a hash.
+fn resume_state_path(out: &Path, hash: Hash) -> PathBuf {
+    out.join(format!(".sendme-resume-{hash}"))
+}
+
+/// Loads the state of an interrupted download of `hash` into `out`.
+///
+/// Complete blobs are verified against their hash, partially downloaded blobs resume from
+/// the end of their partial file, which is verified once the provider starts sending.  Also returns the size of the data already present.
+async fn load_resume_state(out: &Path, hash: Hash) -> Result<(get::ResumeState, u64)> {
+    let mut state = match tokio::fs::read(resume_state_path(out, hash)).await {
+        Ok(bytes) => get::ResumeState::from_bytes(&bytes)?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Ok((get::ResumeState::new(hash), 0))
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let mut resumed_size = 0;
+    for entry in state.entries_mut() {
+        let filepath = entry_path(out, &blob_file_name(&entry.name, entry.hash))?;
+        entry.progress = if get::verify_file(&filepath, entry.hash)
+            .await
+            .unwrap_or(false)
+        {
+            resumed_size += tokio::fs::metadata(&filepath).await?.len();
+            get::BlobProgress::Complete
+        } else if let Ok(meta) = tokio::fs::metadata(partial_path(&filepath)).await {
+            resumed_size += meta.len();
+            get::BlobProgress::Partial(meta.len())
+        } else {
+            get::BlobProgress::Missing
+        };
+    }
+    Ok((state, resumed_size))
+}
+
+/// Persists the state of the download, so it can be resumed if interrupted.
+async fn save_resume_state(out: &Path, state: &get::ResumeState) -> Result<()> {
+    tokio::fs::write(resume_state_path(out, state.hash()), state.to_bytes())
+        .await
+        .context("Failed to write resume state")
+}
@@ -4,7 +4,7 @@ use std::io;
 use std::str::FromStr;
 
 use abao::decode::AsyncSliceDecoder;
-use anyhow::{ensure, Result};
+use anyhow::Result;
 use bytes::{Bytes, BytesMut};
 use postcard::experimental::max_size::MaxSize;
 use quinn::VarInt;
@@ -12,34 +12,149 @@ use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tracing::debug;
 
+use crate::capability::SignedCapability;
 use crate::util::{self, Hash};
 
-/// Maximum message size is limited to 100MiB for now.
-const MAX_MESSAGE_SIZE: usize = 1024 * 1024 * 100;
+/// The default maximum size of a message, 100MiB.
+pub(crate) const DEFAULT_MAX_MESSAGE_SIZE: u64 = 1024 * 1024 * 100;
 
 /// Protocol version
-pub const VERSION: u64 = 1;
+pub const VERSION: u64 = 8;
 
-#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, MaxSize)]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
 pub(crate) struct Handshake {
     pub version: u64,
-    pub token: AuthToken,
+    pub auth: Auth,
 }
 
 impl Handshake {
-    pub fn new(token: AuthToken) -> Self {
+    pub fn new(auth: Auth) -> Self {
         Self {
             version: VERSION,
-            token,
+            auth,
         }
     }
 }
 
-#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, MaxSize)]
+/// How a getter authenticates to a provider.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
+pub enum Auth {
+    /// A token registered with the provider.
+    Token(AuthToken),
+    /// A capability signed by the provider, which the provider does not need to remember.
+    Capability(Box<SignedCapability>),
+    /// The [`PeerId`](crate::PeerId) of the getter, which must be allowed by the provider.
+    ///
+    /// The getter is authenticated by the certificate it connects with, see
+    /// [`Options::keypair`](crate::get::Options::keypair).
+    Identity,
+}
+
+impl Auth {
+    /// An identifier of the token or capability, which can be logged without revealing it.
+    ///
+    /// Returns `None` for [`Auth::Identity`], the getter is identified by its
+    /// [`PeerId`](crate::PeerId) instead.
+    pub fn id(&self) -> Option<String> {
+        match self {
+            Auth::Token(token) => Some(token.id()),
+            Auth::Capability(capability) => Some(capability.id()),
+            Auth::Identity => None,
+        }
+    }
+}
+
+impl From<AuthToken> for Auth {
+    fn from(token: AuthToken) -> Self {
+        Auth::Token(token)
+    }
+}
+
+impl From<SignedCapability> for Auth {
+    fn from(capability: SignedCapability) -> Self {
+        Auth::Capability(Box::new(capability))
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
 pub(crate) struct Request {
     pub id: u64,
     /// blake3 hash
     pub name: Hash,
+    /// The ranges of the blobs to send.
+    ///
+    /// For a request of a single blob the first entry applies to the blob.  For a request
+    /// of a collection the entry at index `i` applies to the `i`th blob in the collection,
+    /// the collection itself is always sent entirely.  Missing entries default to
+    /// [`RangeSpec::All`].
+    pub ranges: Vec<RangeSpec>,
+}
+
+impl Request {
+    /// The range to send for the blob at the given index.
+    pub fn range(&self, index: usize) -> RangeSpec {
+        self.ranges.get(index).copied().unwrap_or_default()
+    }
+}
+
+/// The part of a blob to transfer.
+///
+/// Only the bao slice covering the range is transferred, which the getter verifies against
+/// the blob hash.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum RangeSpec {
+    /// Transfer the entire blob.
+    #[default]
+    All,
+    /// Transfer `len` bytes starting at `offset`.
+    ///
+    /// The range is clamped to the size of the blob.
+    Range {
+        /// The offset of the first byte to transfer.
+        offset: u64,
+        /// The number of bytes to transfer.
+        len: u64,
+    },
+    /// Do not transfer the blob at all.
+    ///
+    /// For a blob in a collection no response is sent for it.  When requesting a single
+    /// blob, only `Res::Found` is sent.
+    Skip,
+}
+
+impl RangeSpec {
+    /// Creates a range of `len` bytes starting at `offset`.
+    pub fn new(offset: u64, len: u64) -> Self {
+        RangeSpec::Range { offset, len }
+    }
+
+    /// Creates a range covering everything from `offset` to the end of the blob.
+    pub fn from_offset(offset: u64) -> Self {
+        RangeSpec::Range {
+            offset,
+            len: u64::MAX,
+        }
+    }
+
+    /// The offset and length to use for a bao slice.
+    ///
+    /// [`RangeSpec::Skip`] has an empty slice, it must not be sent at all.
+    pub(crate) fn slice(&self) -> (u64, u64) {
+        match *self {
+            RangeSpec::All => (0, u64::MAX),
+            RangeSpec::Range { offset, len } => (offset, len),
+            RangeSpec::Skip => (0, 0),
+        }
+    }
+
+    /// The number of bytes of a blob with the given size covered by this range.
+    pub fn byte_len(&self, size: u64) -> u64 {
+        if *self == RangeSpec::Skip {
+            return 0;
+        }
+        let (offset, len) = self.slice();
+        std::cmp::min(len, size.saturating_sub(offset))
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
@@ -51,8 +166,14 @@ pub(crate) struct Response {
 #[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Clone)]
 pub(crate) enum Res {
     NotFound,
-    // If found, a stream of bao data is sent as next message.
-    Found,
+    /// If found, a stream of bao data is sent as next message.
+    ///
+    /// This is the answer to a request for a single blob, as well as for each blob
+    /// following `Res::FoundCollection`.
+    Found {
+        /// The leaves of the bao tree are groups of `2^chunk_group_log` chunks.
+        chunk_group_log: u8,
+    },
     /// Indicates that the given hash referred to a collection of multiple blobs
     /// A stream of boa data that decodes to a `Collection` is sent as the next message,
     /// followed by `Res::Found` responses, send in the order indicated in the `Collection`.
@@ -60,15 +181,50 @@ pub(crate) enum Res {
         /// The size of the raw data we are planning to transfer
         total_blobs_size: u64,
     },
+    /// The data of the blob was modified on the provider since it was hashed, so it no
+    /// longer matches the hash and is not sent.
+    ///
+    /// For a collection, the transfer ends after this response.
+    Modified,
+    /// The collection was found, but the data of some of the requested blobs is not
+    /// available, so the collection is not sent.
+    CollectionIncomplete {
+        /// The indices of the unavailable blobs in the collection.
+        missing: Vec<usize>,
+    },
+}
+
+/// A configured limit was exceeded by the peer.
+///
+/// Errors caused by exceeding a limit can be recognised by downcasting them to this type.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    /// A message was larger than the maximum message size.
+    #[error("message too large: {size} > {max}")]
+    MessageSize {
+        /// The size of the message.
+        size: u64,
+        /// The maximum size of a message.
+        max: u64,
+    },
+    /// A collection was larger than the maximum collection size.
+    #[error("collection too large: more than {max}")]
+    CollectionSize {
+        /// The maximum size of a collection.
+        max: u64,
+    },
+    /// The data was larger than the maximum data size.
+    #[error("data too large: {size} > {max}")]
+    DataSize {
+        /// The size of the data.
+        size: u64,
+        /// The maximum size of the data.
+        max: u64,
+    },
 }
 
 /// Write the given data to the provider sink, with a unsigned varint length prefix.
 pub(crate) async fn write_lp<W: AsyncWrite + Unpin>(writer: &mut W, data: &[u8]) -> Result<()> {
-    ensure!(
-        data.len() < MAX_MESSAGE_SIZE,
-        "sending message is too large"
-    );
-
     // send length prefix
     let data_len = data.len() as u64;
     writer.write_u64_le(data_len).await?;
@@ -79,9 +235,12 @@ pub(crate) async fn write_lp<W: AsyncWrite + Unpin>(writer: &mut W, data: &[u8])
 }
 
 /// Read and deserialize into the given type from the provided source, based on the length prefix.
+///
+/// Fails with [`LimitExceeded::MessageSize`] if the message is larger than `max_size`.
 pub(crate) async fn read_lp<'a, R: AsyncRead + Unpin, T: Deserialize<'a>>(
     mut reader: R,
     buffer: &'a mut BytesMut,
+    max_size: u64,
 ) -> Result<Option<(T, usize)>> {
     // read length prefix
     let size = match read_prefix(&mut reader).await {
@@ -89,6 +248,7 @@ pub(crate) async fn read_lp<'a, R: AsyncRead + Unpin, T: Deserialize<'a>>(
         Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
         Err(err) => return Err(err.into()),
     };
+    check_message_size(size, max_size)?;
     let mut reader = reader.take(size);
 
     let size = usize::try_from(size)?;
@@ -130,32 +290,52 @@ pub(crate) async fn read_size_data<R: AsyncRead + Unpin>(
 
 /// Read and decode the given bao encoded data from the provided source.
 ///
+/// Fails with [`LimitExceeded::CollectionSize`] if the data is larger than `max_size`.
+///
 /// After the data is read successfully, the reader will be at the end of the data.
 /// If there is an error, the reader can be anywhere, so it is recommended to discard it.
 pub(crate) async fn read_bao_encoded<R: AsyncRead + Unpin>(
     reader: R,
     hash: Hash,
+    max_size: u64,
 ) -> Result<Vec<u8>> {
-    let mut decoder = AsyncSliceDecoder::new(reader, &hash.into(), 0, u64::MAX);
+    let decoder = AsyncSliceDecoder::new(reader, &hash.into(), 0, u64::MAX);
     // we don't know the size yet, so we just allocate a reasonable amount
     let mut decoded = Vec::with_capacity(4096);
-    decoder.read_to_end(&mut decoded).await?;
+    decoder
+        .take(max_size.saturating_add(1))
+        .read_to_end(&mut decoded)
+        .await?;
+    if decoded.len() as u64 > max_size {
+        return Err(LimitExceeded::CollectionSize { max: max_size }.into());
+    }
     Ok(decoded)
 }
 
 /// Return a buffer of the data, based on the length prefix, from the given source.
 /// The new buffer is split off from the buffer that is passed in the function.
+///
+/// Fails with [`LimitExceeded::MessageSize`] if the message is larger than `max_size`.
 pub(crate) async fn read_lp_data<R: AsyncRead + Unpin>(
     mut reader: R,
     buffer: &mut BytesMut,
+    max_size: u64,
 ) -> Result<Option<Bytes>> {
     // read length prefix
     let size = read_prefix(&mut reader).await?;
+    check_message_size(size, max_size)?;
 
     let response = read_size_data(size, reader, buffer).await?;
     Ok(Some(response))
 }
 
+fn check_message_size(size: u64, max: u64) -> Result<(), LimitExceeded> {
+    if size > max {
+        return Err(LimitExceeded::MessageSize { size, max });
+    }
+    Ok(())
+}
+
 async fn read_prefix<R: AsyncRead + Unpin>(mut reader: R) -> Result<u64, io::Error> {
     // read length prefix
     let size = reader.read_u64_le().await?;
@@ -178,6 +358,11 @@ impl AuthToken {
             bytes: rand::random(),
         }
     }
+
+    /// An identifier of the token, which can be logged without revealing the token.
+    pub fn id(&self) -> String {
+        util::encode(&blake3::hash(&self.bytes).as_bytes()[..8])
+    }
 }
 
 /// Serialises the [`AuthToken`] to base64.
@@ -236,6 +421,11 @@ pub(crate) enum Closed {
     /// Only a single request is allowed on a stream, if more data is received after this a
     /// provider may send this error code in a STOP_STREAM frame.
     RequestReceived = 2,
+    /// The content being transferred was removed from the provider.
+    ///
+    /// The provider resets the stream of any transfer including content which was removed
+    /// while it was in progress.
+    ContentRemoved = 3,
 }
 
 impl Closed {
@@ -244,6 +434,7 @@ impl Closed {
             Closed::StreamDropped => &b"stream dropped"[..],
             Closed::ProviderTerminating => &b"provider terminating"[..],
             Closed::RequestReceived => &b"request received"[..],
+            Closed::ContentRemoved => &b"content removed"[..],
         }
     }
 }
@@ -267,6 +458,7 @@ impl TryFrom<VarInt> for Closed {
             0 => Ok(Self::StreamDropped),
             1 => Ok(Self::ProviderTerminating),
             2 => Ok(Self::RequestReceived),
+            3 => Ok(Self::ContentRemoved),
             val => Err(UnknownErrorCode(val)),
         }
     }
@@ -293,4 +485,20 @@ mod tests {
         println!("err {err:#}");
         assert!(matches!(err, AuthTokenParseError::Length(3)));
     }
+
+    #[tokio::test]
+    async fn test_read_lp_max_size() {
+        let mut data = Vec::new();
+        write_lp(&mut data, b"hello").await.unwrap();
+
+        let mut buffer = BytesMut::new();
+        let read = read_lp_data(&data[..], &mut buffer, 5).await.unwrap();
+        assert_eq!(read.as_deref(), Some(&b"hello"[..]));
+
+        let err = read_lp_data(&data[..], &mut buffer, 4).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<LimitExceeded>(),
+            Some(&LimitExceeded::MessageSize { size: 5, max: 4 })
+        );
+    }
 }
@@ -4,61 +4,362 @@
 //! To create a provider, create a database using [`create_collection`], then build a
 //! provider using [`Builder`] and spawn it using [`Builder::spawn`].
 //!
-//! You can monitor what is happening in the provider using [`Provider::subscribe`].
+//! You can monitor what is happening in the provider using [`Provider::subscribe`], or
+//! using an [`EventSink`] added with [`Builder::event_sink`] if no event may be missed.
+//!
+//! The database can be persisted using [`Database::save`] and loaded again using
+//! [`Database::load`], which allows restarting a provider without rehashing all data.
 //!
 //! To shut down the provider, call [`Provider::shutdown`].
+use std::collections::{HashMap, HashSet};
 use std::fmt::{self, Display};
 use std::future::Future;
-use std::io::{BufReader, Read};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::task::Poll;
-use std::{collections::HashMap, sync::Arc};
+use std::time::{Duration, Instant};
 
 use abao::encode::SliceExtractor;
 use anyhow::{bail, ensure, Context, Result};
 use bytes::{Bytes, BytesMut};
 use futures::future;
 use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use tokio::sync::broadcast;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc, Notify};
 use tokio::task::{JoinError, JoinHandle};
-use tokio_util::io::SyncIoBridge;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, debug_span, warn};
 use tracing_futures::Instrument;
 
-use crate::blobs::{Blob, Collection};
+use crate::bao;
+use crate::blobs::{Blob, Collection, EntryKind, Metadata, MetadataV1};
+use crate::capability::{Capability, SignedCapability};
 use crate::protocol::{
-    read_lp, write_lp, AuthToken, Closed, Handshake, Request, Res, Response, VERSION,
+    read_lp, write_lp, Auth, AuthToken, Closed, Handshake, RangeSpec, Request, Res, Response,
+    DEFAULT_MAX_MESSAGE_SIZE, VERSION,
 };
-use crate::tls::{self, Keypair, PeerId};
+use crate::tls::{self, Keypair, PeerId, PublicKey};
 use crate::util::{self, Hash};
 
-const MAX_CONNECTIONS: u32 = 1024;
-const MAX_STREAMS: u64 = 10;
+#[cfg(feature = "cli")]
+mod access_log;
+mod events;
+mod metrics;
+mod throttle;
+mod tokens;
+#[cfg(feature = "cli")]
+mod watch;
+
+#[cfg(feature = "cli")]
+pub use self::access_log::AccessLog;
+pub use self::events::EventSink;
+use self::events::Events;
+use self::throttle::{RateLimits, Throttle};
+pub use self::tokens::TokenScope;
+use self::tokens::Tokens;
 
 /// Database containing content-addressed data (blobs or collections).
-#[derive(Debug, Clone)]
-pub struct Database(Arc<HashMap<Hash, BlobOrCollection>>);
+///
+/// The database is a shared handle: clones refer to the same data, so content added to any
+/// clone, e.g. using [`Database::merge`], is immediately served by a [`Provider`] using it.
+#[derive(Debug, Clone, Default)]
+pub struct Database(Arc<RwLock<HashMap<Hash, BlobOrCollection>>>);
 
 impl Database {
-    fn get(&self, key: &Hash) -> Option<&BlobOrCollection> {
-        self.0.get(key)
+    fn get(&self, key: &Hash) -> Option<BlobOrCollection> {
+        self.0.read().unwrap().get(key).cloned()
     }
 
-    /// Iterate over all blobs in the database.
-    pub fn blobs(&self) -> impl Iterator<Item = (&Hash, &PathBuf, u64)> + '_ {
+    /// Returns a snapshot of all entries in the database.
+    fn entries(&self) -> Vec<(Hash, BlobOrCollection)> {
         self.0
+            .read()
+            .unwrap()
             .iter()
+            .map(|(k, v)| (*k, v.clone()))
+            .collect()
+    }
+
+    /// Iterate over all blobs in the database.
+    ///
+    /// This iterates over a snapshot, content added or removed meanwhile is not reflected.
+    pub fn blobs(&self) -> impl Iterator<Item = (Hash, PathBuf, u64)> {
+        self.entries()
+            .into_iter()
             .filter_map(|(k, v)| match v {
                 BlobOrCollection::Blob(data) => Some((k, data)),
                 BlobOrCollection::Collection(_) => None,
             })
-            .map(|(k, data)| (k, &data.path, data.size))
+            .map(|(k, data)| (k, data.path, data.size))
+    }
+
+    /// Iterate over the hashes of all collections in the database.
+    ///
+    /// This iterates over a snapshot, content added or removed meanwhile is not reflected.
+    pub fn collections(&self) -> impl Iterator<Item = Hash> {
+        self.entries().into_iter().filter_map(|(k, v)| match v {
+            BlobOrCollection::Blob(_) => None,
+            BlobOrCollection::Collection(_) => Some(k),
+        })
+    }
+
+    /// Returns the hashes of the blobs referenced by the collections in the database.
+    #[cfg(feature = "cli")]
+    fn referenced_blobs(&self) -> HashSet<Hash> {
+        let mut referenced = HashSet::new();
+        for (_, entry) in self.entries() {
+            if let BlobOrCollection::Collection((_, data)) = entry {
+                if let Ok(collection) = Collection::from_bytes(&data) {
+                    referenced.extend(collection.blobs.iter().map(|blob| blob.hash));
+                }
+            }
+        }
+        referenced
+    }
+
+    /// Adds all blobs and collections from `other` to this database.
+    ///
+    /// Entries which already exist are replaced.
+    pub fn merge(&self, other: &Database) {
+        let entries = other.entries();
+        self.0.write().unwrap().extend(entries);
+    }
+
+    /// Removes a blob or collection, returns whether it was present.
+    ///
+    /// Removing a collection does not remove its blobs.
+    pub(crate) fn remove(&self, hash: &Hash) -> bool {
+        self.0.write().unwrap().remove(hash).is_some()
+    }
+
+    /// Loads a database previously written using [`Database::save`].
+    ///
+    /// No data is rehashed: the paths and collections are read from the store.  The outboards
+    /// of blobs are not loaded into memory, they are read from the store on demand while
+    /// serving, so the store must stay in place while the database is in use.  Blobs whose
+    /// data file is missing or was modified since it was hashed are skipped with a warning.
+    ///
+    /// The loaded database can be used to spawn a provider using [`Provider::builder`].
+    pub async fn load(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        let outboards_dir = dir.join(OUTBOARDS_DIR);
+        let paths_dir = dir.join(PATHS_DIR);
+        let stamps_dir = dir.join(STAMPS_DIR);
+        let collections_dir = dir.join(COLLECTIONS_DIR);
+
+        let mut db = HashMap::new();
+        let mut entries = tokio::fs::read_dir(&outboards_dir)
+            .await
+            .with_context(|| format!("failed to read store at {}", dir.display()))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let file_name = entry.file_name();
+            let hash = match file_name.to_str().and_then(store_name_to_hash) {
+                Some(hash) => hash,
+                None => {
+                    warn!("ignoring unknown file in store: {}", entry.path().display());
+                    continue;
+                }
+            };
+            let name = hash_to_store_name(&hash);
+            let collection_path = collections_dir.join(&name);
+            if collection_path.exists() {
+                let outboard = Bytes::from(tokio::fs::read(entry.path()).await?);
+                ensure!(
+                    outboard.len() >= 8,
+                    "invalid outboard for {}: too short",
+                    hash
+                );
+                let data = Bytes::from(tokio::fs::read(&collection_path).await?);
+                ensure!(
+                    Hash::new(&data) == hash,
+                    "collection {} does not match its hash",
+                    hash
+                );
+                db.insert(hash, BlobOrCollection::Collection((outboard, data)));
+                continue;
+            }
+
+            // Blob outboards are prefixed with their chunk group log.  Only this header is
+            // read, the outboard itself is read from the store when the blob is sent.
+            let mut header = [0u8; 9];
+            let mut file = tokio::fs::File::open(entry.path()).await?;
+            file.read_exact(&mut header)
+                .await
+                .with_context(|| format!("invalid outboard for {hash}: too short"))?;
+            let chunk_group_log = header[0];
+            let size = u64::from_le_bytes(header[1..].try_into().unwrap());
+            ensure!(
+                chunk_group_log <= bao::MAX_CHUNK_GROUP_LOG,
+                "invalid outboard for {}: chunk group log {} too large",
+                hash,
+                chunk_group_log
+            );
+            ensure!(
+                file.metadata().await?.len() == 1 + bao::outboard_size(size, chunk_group_log),
+                "invalid outboard for {}: wrong size",
+                hash
+            );
+
+            let path = tokio::fs::read_to_string(paths_dir.join(&name))
+                .await
+                .with_context(|| format!("missing path for blob {hash}"))?;
+            let path = PathBuf::from(path);
+            let meta = match tokio::fs::metadata(&path).await {
+                Ok(meta) => meta,
+                Err(err) => {
+                    warn!("skipping blob {}: {}: {err}", hash, path.display());
+                    continue;
+                }
+            };
+            // Stores written without stamps only record the size of the data.
+            let stamp = match tokio::fs::read(stamps_dir.join(&name)).await {
+                Ok(stamp) => postcard::from_bytes(&stamp)
+                    .with_context(|| format!("invalid stamp for blob {hash}"))?,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => FileStamp::new(&meta),
+                Err(err) => return Err(err.into()),
+            };
+            let data = Data {
+                outboard: Outboard::File {
+                    path: entry.path(),
+                    offset: 1,
+                },
+                chunk_group_log,
+                path,
+                size,
+                stamp,
+            };
+            if !data.is_unmodified(&meta) {
+                warn!(
+                    "skipping blob {}: {} has changed",
+                    hash,
+                    data.path.display()
+                );
+                continue;
+            }
+            db.insert(hash, BlobOrCollection::Blob(data));
+        }
+
+        Ok(Database(Arc::new(RwLock::new(db))))
+    }
+
+    /// Writes the database to a store in the given directory.
+    ///
+    /// The store contains the outboards of all blobs, prefixed with their chunk group log,
+    /// and collections, the absolute paths and stamps (modification time and inode)
+    /// of the blob data and the raw collection data.  The blob data itself is not copied,
+    /// it must stay unmodified at its original location.
+    ///
+    /// Entries already present in the store are kept, so multiple databases can be saved to
+    /// the same directory.
+    pub async fn save(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        let outboards_dir = dir.join(OUTBOARDS_DIR);
+        let paths_dir = dir.join(PATHS_DIR);
+        let stamps_dir = dir.join(STAMPS_DIR);
+        let collections_dir = dir.join(COLLECTIONS_DIR);
+        tokio::fs::create_dir_all(&outboards_dir).await?;
+        tokio::fs::create_dir_all(&paths_dir).await?;
+        tokio::fs::create_dir_all(&stamps_dir).await?;
+        tokio::fs::create_dir_all(&collections_dir).await?;
+
+        for (hash, entry) in self.entries() {
+            let name = hash_to_store_name(&hash);
+            // The outboard is written last, it marks the entry as complete.
+            match entry {
+                BlobOrCollection::Blob(Data {
+                    outboard,
+                    chunk_group_log,
+                    path,
+                    stamp,
+                    ..
+                }) => {
+                    let path = tokio::fs::canonicalize(&path)
+                        .await
+                        .with_context(|| format!("failed to resolve {}", path.display()))?;
+                    let path = path
+                        .to_str()
+                        .with_context(|| format!("path is not valid UTF-8: {}", path.display()))?;
+                    write_atomic(&paths_dir.join(&name), path).await?;
+                    write_atomic(&stamps_dir.join(&name), postcard::to_stdvec(&stamp)?).await?;
+                    save_outboard(&outboard, chunk_group_log, &outboards_dir.join(&name)).await?;
+                }
+                BlobOrCollection::Collection((outboard, data)) => {
+                    write_atomic(&collections_dir.join(&name), data).await?;
+                    write_atomic(&outboards_dir.join(&name), outboard).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes the outboard of a blob to `target`, prefixed with its chunk group log.
+///
+/// Outboards are streamed rather than read into memory.  If the outboard was loaded from
+/// `target` itself, it is left untouched.
+async fn save_outboard(outboard: &Outboard, chunk_group_log: u8, target: &Path) -> Result<()> {
+    let (path, offset) = outboard.location();
+    if let (Ok(source), Ok(target)) = (
+        tokio::fs::canonicalize(path).await,
+        tokio::fs::canonicalize(target).await,
+    ) {
+        if source == target {
+            return Ok(());
+        }
     }
+    let mut source = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("failed to open outboard {}", path.display()))?;
+    source.seek(SeekFrom::Start(offset)).await?;
+    let temp = temp_path(target);
+    let mut file = tokio::fs::File::create(&temp).await?;
+    file.write_all(&[chunk_group_log]).await?;
+    tokio::io::copy(&mut source, &mut file).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&temp, target).await?;
+    Ok(())
+}
+
+/// Writes `data` to `target` through a temporary file, so a crash never leaves `target`
+/// truncated.
+async fn write_atomic(target: &Path, data: impl AsRef<[u8]>) -> Result<()> {
+    let temp = temp_path(target);
+    let mut file = tokio::fs::File::create(&temp).await?;
+    file.write_all(data.as_ref()).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&temp, target).await?;
+    Ok(())
+}
+
+/// The temporary file a store file is written to before being renamed into place.
+fn temp_path(target: &Path) -> PathBuf {
+    let mut name = target.as_os_str().to_os_string();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+/// Directory in the store containing the bao outboards, for both blobs and collections.
+const OUTBOARDS_DIR: &str = "outboards";
+/// Directory in the store containing the paths to the data of blobs.
+const PATHS_DIR: &str = "paths";
+/// Directory in the store containing the stamps of the data of blobs.
+const STAMPS_DIR: &str = "stamps";
+/// Directory in the store containing the raw data of collections.
+const COLLECTIONS_DIR: &str = "collections";
+
+/// The file name used for the given hash in a store.
+fn hash_to_store_name(hash: &Hash) -> String {
+    blake3::Hash::from(*hash).to_hex().to_string()
+}
+
+/// Parses a file name in a store back into a hash.
+fn store_name_to_hash(name: &str) -> Option<Hash> {
+    blake3::Hash::from_hex(name).ok().map(Hash::from)
 }
 
 /// Builder for the [`Provider`].
@@ -75,9 +376,39 @@ pub struct Builder {
     auth_token: AuthToken,
     db: Database,
     keylog: bool,
+    allowed_peers: Option<HashSet<PeerId>>,
+    max_rate: Option<u64>,
+    max_rate_per_peer: Option<u64>,
+    limits: Limits,
+    event_sinks: Vec<Arc<dyn EventSink>>,
+    metrics_addr: Option<SocketAddr>,
 }
 
-#[derive(Debug)]
+/// Limits on the connections and requests accepted by a [`Provider`].
+///
+/// Connections and streams over the limits are refused by QUIC.  Messages over the limit
+/// fail the request with a [`LimitExceeded`](crate::protocol::LimitExceeded) error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The maximum number of concurrent connections, defaults to 1024.
+    pub max_connections: u32,
+    /// The maximum number of concurrent requests on a connection, defaults to 10.
+    pub max_streams: u32,
+    /// The maximum size of a message received from a getter, defaults to 100MiB.
+    pub max_message_size: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_connections: 1024,
+            max_streams: 10,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) enum BlobOrCollection {
     Blob(Data),
     Collection((Bytes, Bytes)),
@@ -92,6 +423,12 @@ impl Builder {
             auth_token: AuthToken::generate(),
             db,
             keylog: false,
+            allowed_peers: None,
+            max_rate: None,
+            max_rate_per_peer: None,
+            limits: Limits::default(),
+            event_sinks: Vec::new(),
+            metrics_addr: None,
         }
     }
 
@@ -110,6 +447,9 @@ impl Builder {
     }
 
     /// Uses the given [`AuthToken`] instead of a newly generated one.
+    ///
+    /// This token gives unrestricted access to all content, see [`Provider::mint_token`]
+    /// for tokens with a limited scope.
     pub fn auth_token(mut self, auth_token: AuthToken) -> Self {
         self.auth_token = auth_token;
         self
@@ -125,63 +465,158 @@ impl Builder {
         self
     }
 
+    /// Only accepts connections from getters with one of the given [`PeerId`]s.
+    ///
+    /// By default any getter can connect.  Getters on the allowlist can get all content
+    /// using [`Auth::Identity`], without an [`AuthToken`] or capability.  Other
+    /// getters are rejected during the TLS handshake.
+    pub fn allowed_peers(mut self, peers: impl IntoIterator<Item = PeerId>) -> Self {
+        self.allowed_peers = Some(peers.into_iter().collect());
+        self
+    }
+
+    /// Limits the total upload bandwidth of the provider, in bytes per second.
+    ///
+    /// By default the bandwidth is not limited.  The limit applies to the data of blobs and
+    /// collections sent to all getters together.
+    pub fn max_rate(mut self, bytes_per_sec: u64) -> Self {
+        self.max_rate = Some(bytes_per_sec);
+        self
+    }
+
+    /// Limits the upload bandwidth for each getter, in bytes per second.
+    ///
+    /// All connections of a getter share the limit, getters are identified by their
+    /// [`PeerId`].  Getters using a new [`PeerId`] for every connection, which is the
+    /// default, are thus limited per connection.
+    pub fn max_rate_per_peer(mut self, bytes_per_sec: u64) -> Self {
+        self.max_rate_per_peer = Some(bytes_per_sec);
+        self
+    }
+
+    /// Sets the limits on connections and requests, see [`Limits`] for the defaults.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Adds a sink receiving every [`Event`] of the provider.
+    ///
+    /// Use this instead of [`Provider::subscribe`] when no event may be missed, e.g. with a
+    /// bounded [`tokio::sync::mpsc::Sender`].  See [`EventSink`] for how a slow sink affects
+    /// the provider.
+    pub fn event_sink(mut self, sink: impl EventSink) -> Self {
+        self.event_sinks.push(Arc::new(sink));
+        self
+    }
+
+    /// Serves the metrics of the provider over HTTP at `/metrics` on the given address.
+    ///
+    /// The metrics are in the Prometheus text format, see [`Provider::metrics`].
+    pub fn metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
     /// Spawns the [`Provider`] in a tokio task.
     ///
     /// This will create the underlying network server and spawn a tokio task accepting
     /// connections.  The returned [`Provider`] can be used to control the task as well as
     /// get information about it.
     pub fn spawn(self) -> Result<Provider> {
-        let tls_server_config = tls::make_server_config(&self.keypair, self.keylog)?;
+        ensure!(
+            self.max_rate != Some(0) && self.max_rate_per_peer != Some(0),
+            "rate limits must not be zero"
+        );
+        let tls_server_config =
+            tls::make_server_config(&self.keypair, self.allowed_peers.clone(), self.keylog)?;
         let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(tls_server_config));
         let mut transport_config = quinn::TransportConfig::default();
         transport_config
-            .max_concurrent_bidi_streams(MAX_STREAMS.try_into()?)
+            .max_concurrent_bidi_streams(self.limits.max_streams.into())
             .max_concurrent_uni_streams(0u32.into());
 
         server_config
             .transport_config(Arc::new(transport_config))
-            .concurrent_connections(MAX_CONNECTIONS);
+            .concurrent_connections(self.limits.max_connections);
 
         let endpoint = quinn::Endpoint::server(server_config, self.bind_addr)?;
         let listen_addr = endpoint.local_addr().unwrap();
-        let db2 = self.db.clone();
-        let (events_sender, _events_receiver) = broadcast::channel(8);
-        let events = events_sender.clone();
+        let events = Events::new(self.event_sinks);
         let cancel_token = CancellationToken::new();
+        let metrics_addr = match self.metrics_addr {
+            Some(addr) => {
+                let listener = std::net::TcpListener::bind(addr)
+                    .with_context(|| format!("failed to bind metrics listener to {addr}"))?;
+                listener.set_nonblocking(true)?;
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                let metrics_addr = listener.local_addr()?;
+                let metrics = events.metrics().clone();
+                let cancel_token = cancel_token.clone();
+                tokio::spawn(async move {
+                    tokio::select! {
+                        _ = cancel_token.cancelled() => {}
+                        _ = metrics::serve(listener, metrics) => {}
+                    }
+                });
+                Some(metrics_addr)
+            }
+            None => None,
+        };
+        let draining = CancellationToken::new();
+        let transfers = Transfers::default();
+        let tokens = Tokens::default();
+        tokens.insert(self.auth_token, TokenScope::all());
+        let auth = Authenticator {
+            tokens: tokens.clone(),
+            provider: self.keypair.public(),
+            allowed_peers: self.allowed_peers.map(Arc::new),
+        };
+        let ctx = ProviderContext {
+            db: self.db.clone(),
+            auth,
+            rate_limits: RateLimits::new(self.max_rate, self.max_rate_per_peer),
+            max_message_size: self.limits.max_message_size,
+            events: events.clone(),
+            draining: draining.clone(),
+            transfers: transfers.clone(),
+        };
         let task = {
             let cancel_token = cancel_token.clone();
-            tokio::spawn(async move {
-                Self::run(endpoint, db2, self.auth_token, events_sender, cancel_token).await
-            })
+            tokio::spawn(async move { Self::run(endpoint, ctx, cancel_token).await })
         };
 
         Ok(Provider {
             listen_addr,
-            keypair: self.keypair,
+            metrics_addr,
+            keypair: Arc::new(self.keypair),
             auth_token: self.auth_token,
+            db: self.db,
             task,
             events,
             cancel_token,
+            draining,
+            transfers,
+            tokens,
         })
     }
 
-    async fn run(
-        server: quinn::Endpoint,
-        db: Database,
-        auth_token: AuthToken,
-        events: broadcast::Sender<Event>,
-        cancel_token: CancellationToken,
-    ) {
+    async fn run(server: quinn::Endpoint, ctx: ProviderContext, cancel_token: CancellationToken) {
         debug!("\nlistening at: {:#?}", server.local_addr().unwrap());
 
+        let mut refusing = false;
         loop {
             tokio::select! {
                 biased;
                 _ = cancel_token.cancelled() => break,
+                _ = ctx.draining.cancelled(), if !refusing => {
+                    // Without a server config new connections are refused.
+                    debug!("shutting down, refusing new connections");
+                    server.set_server_config(None);
+                    refusing = true;
+                }
                 Some(connecting) = server.accept() => {
-                    let db = db.clone();
-                    let events = events.clone();
-                    tokio::spawn(handle_connection(connecting, db, auth_token, events));
+                    tokio::spawn(handle_connection(connecting, ctx.clone()));
                 }
                 else => break,
             }
@@ -209,20 +644,36 @@ impl Builder {
 #[derive(Debug)]
 pub struct Provider {
     listen_addr: SocketAddr,
-    keypair: Keypair,
+    metrics_addr: Option<SocketAddr>,
+    keypair: Arc<Keypair>,
     auth_token: AuthToken,
+    db: Database,
     task: JoinHandle<()>,
-    events: broadcast::Sender<Event>,
+    events: Events,
     cancel_token: CancellationToken,
+    /// Cancelled when a graceful shutdown starts, no new transfers are accepted afterwards.
+    draining: CancellationToken,
+    transfers: Transfers,
+    tokens: Tokens,
 }
 
 /// Events emitted by the [`Provider`] informing about the current status.
 #[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum Event {
     /// A new client connected to the provider.
     ClientConnected {
         /// An unique connection id.
         connection_id: u64,
+        /// The address the client connected from.
+        remote_addr: SocketAddr,
+        /// The [`PeerId`] of the client, verified during the TLS handshake.
+        peer_id: Option<PeerId>,
+    },
+    /// A client connection was closed.
+    ClientDisconnected {
+        /// An unique connection id.
+        connection_id: u64,
     },
     /// A request was received from a client.
     RequestReceived {
@@ -232,6 +683,41 @@ pub enum Event {
         request_id: u64,
         /// The hash for which the client wants to receive data.
         hash: Hash,
+        /// The id of the token or capability used for the request, see [`Auth::id`].
+        token_id: Option<String>,
+    },
+    /// Sending the data of a blob started.
+    BlobStarted {
+        /// An unique connection id.
+        connection_id: u64,
+        /// The request id.
+        request_id: u64,
+        /// The hash of the blob.
+        hash: Hash,
+        /// The size of the blob.
+        size: u64,
+    },
+    /// The data of a blob was sent.
+    BlobCompleted {
+        /// An unique connection id.
+        connection_id: u64,
+        /// The request id.
+        request_id: u64,
+        /// The hash of the blob.
+        hash: Hash,
+        /// The number of bytes sent for the blob, including the bao encoding.
+        bytes_sent: u64,
+        /// How long sending the blob took.
+        duration: Duration,
+    },
+    /// Periodic progress of a transfer, emitted at most once per second while sending.
+    TransferProgress {
+        /// An unique connection id.
+        connection_id: u64,
+        /// The request id.
+        request_id: u64,
+        /// The number of bytes sent so far.
+        bytes_sent: u64,
     },
     /// A request was completed and the data was sent to the client.
     TransferCompleted {
@@ -239,6 +725,10 @@ pub enum Event {
         connection_id: u64,
         /// The request id.
         request_id: u64,
+        /// The number of bytes sent, including the bao encoding.
+        bytes_sent: u64,
+        /// How long the transfer took, from receiving the request.
+        duration: Duration,
     },
     /// A request was aborted because the client disconnected.
     TransferAborted {
@@ -247,7 +737,56 @@ pub enum Event {
         /// The request id. When `None`, the transfer was aborted before or during reading and decoding
         /// the transfer request.
         request_id: Option<u64>,
+        /// Why the transfer was aborted.
+        reason: AbortReason,
+        /// The number of bytes sent before the transfer was aborted.
+        bytes_sent: u64,
+    },
+    /// A blob was not sent because its file was modified or removed since it was hashed.
+    ///
+    /// The transfer is aborted after this event.  To serve the new content, create a new
+    /// collection for it and add it using [`Provider::add`].
+    BlobModified {
+        /// The quic connection id.
+        connection_id: u64,
+        /// The request id.
+        request_id: u64,
+        /// The hash of the blob.
+        hash: Hash,
+        /// The path of the modified file.
+        path: PathBuf,
     },
+    /// A new collection of a directory watched using [`Provider::watch`] was added.
+    CollectionPublished {
+        /// The hash of the collection.
+        hash: Hash,
+        /// A ticket to get the collection from the provider.
+        ///
+        /// All tickets of a watched directory share a token, which only gives access to
+        /// the latest collection.
+        ticket: Ticket,
+    },
+}
+
+/// Why a transfer was aborted, see [`Event::TransferAborted`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbortReason {
+    /// The handshake or request could not be read.
+    InvalidRequest,
+    /// The authentication of the getter was not accepted, or does not give access to the
+    /// requested content.  In the latter case the getter is told the content is not found.
+    Unauthorized,
+    /// The requested content was not found.
+    NotFound,
+    /// The requested collection was found, but some of its requested blobs are not
+    /// available, so it was not sent.
+    CollectionIncomplete,
+    /// The content was removed from the database during the transfer.
+    ContentRemoved,
+    /// The data of a blob was modified, see [`Event::BlobModified`].
+    Modified,
+    /// Sending the data failed, e.g. because the getter disconnected.
+    Failed,
 }
 
 impl Provider {
@@ -263,45 +802,216 @@ impl Provider {
         self.listen_addr
     }
 
+    /// Returns the address on which the metrics are served, see [`Builder::metrics_addr`].
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr
+    }
+
+    /// Returns the current metrics of the provider, in the Prometheus text format.
+    ///
+    /// The metrics count connections, requests by outcome, authentication failures, bytes
+    /// sent and the duration of transfers.
+    pub fn metrics(&self) -> String {
+        self.events.metrics().render()
+    }
+
     /// Returns the [`PeerId`] of the provider.
     pub fn peer_id(&self) -> PeerId {
         self.keypair.public().into()
     }
 
     /// Returns the [`AuthToken`] needed to connect to the provider.
+    ///
+    /// This token gives unrestricted access to all content, tickets use tokens limited to
+    /// the content they are for.
     pub fn auth_token(&self) -> AuthToken {
         self.auth_token
     }
 
+    /// Creates a new [`AuthToken`] accepted by the provider, limited to the given scope.
+    pub fn mint_token(&self, scope: TokenScope) -> AuthToken {
+        self.tokens.mint(scope)
+    }
+
+    /// Accepts the given [`AuthToken`], limited to the given scope.
+    ///
+    /// If the token is already accepted its scope is replaced.
+    pub fn add_token(&self, token: AuthToken, scope: TokenScope) {
+        self.tokens.insert(token, scope)
+    }
+
+    /// Stops accepting the given [`AuthToken`], returns whether it was accepted.
+    ///
+    /// Transfers already running using the token are not affected.
+    pub fn revoke_token(&self, token: &AuthToken) -> bool {
+        self.tokens.revoke(token)
+    }
+
+    /// Returns a handle to the [`Database`] served by the provider.
+    ///
+    /// Content added to the database, e.g. using [`Database::merge`], is served
+    /// immediately.  To remove content use [`Provider::remove`].
+    pub fn database(&self) -> &Database {
+        &self.db
+    }
+
+    /// Adds all blobs and collections of `db` to the database served by the provider.
+    ///
+    /// Use [`create_collection`] to create a database for new content.  The
+    /// [`PeerId`] and [`AuthToken`] of the provider do not change, so existing tickets
+    /// remain valid.
+    pub fn add(&self, db: &Database) {
+        self.db.merge(db);
+    }
+
+    /// Removes a blob or collection from the database served by the provider.
+    ///
+    /// All in-flight transfers including the content are aborted, emitting an
+    /// [`Event::TransferAborted`] for each of them.  Removing a collection does not remove
+    /// its blobs, they can still be requested individually.
+    ///
+    /// Returns whether the content was present in the database.
+    pub fn remove(&self, hash: &Hash) -> bool {
+        remove_content(&self.db, &self.transfers, hash)
+    }
+
     /// Subscribe to [`Event`]s emitted from the provider, informing about connections and
     /// progress.
+    ///
+    /// Only a few events are buffered, a receiver which lags behind misses events.  Use
+    /// [`Builder::event_sink`] to receive every event.
     pub fn subscribe(&self) -> broadcast::Receiver<Event> {
         self.events.subscribe()
     }
 
     /// Return a single token containing everything needed to get a hash.
     ///
+    /// The ticket uses an [`AuthToken`] only giving access to `hash`, so it does not expose
+    /// any other content.  All tickets for the same hash share this token, it is minted the
+    /// first time a ticket for the hash is created.  Use [`Provider::ticket_with_scope`] to
+    /// restrict the ticket further.
+    ///
     /// See [`Ticket`] for more details of how it can be used.
     pub fn ticket(&self, hash: Hash) -> Ticket {
-        // TODO: Verify that the hash exists in the db?
         Ticket {
             hash,
             peer: self.peer_id(),
             addr: self.listen_addr,
-            token: self.auth_token,
+            auth: self.tokens.ticket(hash).into(),
         }
     }
 
-    /// Aborts the provider.
+    /// Returns a ticket to get `hash`, using a newly minted token with the given scope.
+    ///
+    /// Like [`Provider::ticket`], this does not check that `hash` is in the database: a
+    /// ticket can be handed out before its content is added.  Until then, getters are told
+    /// the content is not found, which does not count as a download of the token.
+    pub fn ticket_with_scope(&self, hash: Hash, scope: TokenScope) -> Ticket {
+        Ticket {
+            hash,
+            peer: self.peer_id(),
+            addr: self.listen_addr,
+            auth: self.mint_token(scope).into(),
+        }
+    }
+
+    /// Signs a [`Capability`] with the keypair of the provider.
+    ///
+    /// The provider accepts the signed capability without keeping any state for it, so it
+    /// can not be revoked.  Limit its lifetime using [`Capability::expires_in`] instead.
+    pub fn sign_capability(&self, capability: Capability) -> SignedCapability {
+        capability.sign(&self.keypair)
+    }
+
+    /// Returns a ticket to get `hash`, using a signed capability instead of a token.
+    pub fn ticket_with_capability(&self, hash: Hash, capability: Capability) -> Ticket {
+        Ticket {
+            hash,
+            peer: self.peer_id(),
+            addr: self.listen_addr,
+            auth: self.sign_capability(capability).into(),
+        }
+    }
+
+    /// Serves the directory `dir` as a collection, keeping it up to date while it changes.
+    ///
+    /// The directory is watched until the provider shuts down.  Once changes settle, only
+    /// the files which changed are rehashed and a new collection is added to the database,
+    /// replacing the previous collection of the directory.  Every collection added this way,
+    /// including the initial one, is announced with an [`Event::CollectionPublished`].
+    ///
+    /// The tickets of the collections all use the same [`AuthToken`], which only gives
+    /// access to the latest collection.  Use [`Provider::watch_with_ticket_expiry`] for
+    /// tickets with a signed capability instead.
     ///
-    /// This does not gracefully terminate currently: all connections are closed and
-    /// anything in-transit is lost.  The task will stop running and awaiting this
-    /// [`Provider`] will complete.
+    /// Returns the hash of the initial collection.
+    #[cfg(feature = "cli")]
+    pub async fn watch(&self, dir: impl Into<PathBuf>) -> Result<Hash> {
+        watch::watch(self, dir.into(), None).await
+    }
+
+    /// Serves the directory `dir` as a collection like [`Provider::watch`], announcing
+    /// tickets with a capability which expires after `expiry`.
+    ///
+    /// See [`Provider::ticket_with_capability`], the capability of each ticket only gives
+    /// access to its own collection.
+    #[cfg(feature = "cli")]
+    pub async fn watch_with_ticket_expiry(
+        &self,
+        dir: impl Into<PathBuf>,
+        expiry: Duration,
+    ) -> Result<Hash> {
+        watch::watch(self, dir.into(), Some(expiry)).await
+    }
+
+    /// Aborts the provider.
     ///
-    /// The shutdown behaviour will become more graceful in the future.
+    /// This does not gracefully terminate: all connections are closed and anything
+    /// in-transit is lost.  The task will stop running and awaiting this [`Provider`] will
+    /// complete.  Use [`Provider::graceful_shutdown`] to finish running transfers first.
     pub fn shutdown(&self) {
         self.cancel_token.cancel();
     }
+
+    /// Shuts down the provider after finishing the transfers in progress.
+    ///
+    /// From the moment this is called no new transfers are accepted: new connections are
+    /// refused and new streams on open connections are closed.  The returned future waits
+    /// for the running transfers to finish, for at most `timeout`, and then shuts down like
+    /// [`Provider::shutdown`], aborting any transfers still running.  It completes with the
+    /// number of drained and aborted transfers.
+    pub fn graceful_shutdown(
+        &self,
+        timeout: Duration,
+    ) -> impl Future<Output = ShutdownStats> + Send + 'static {
+        self.draining.cancel();
+        let transfers = self.transfers.clone();
+        let cancel_token = self.cancel_token.clone();
+        let completed = transfers.completed();
+        async move {
+            if tokio::time::timeout(timeout, transfers.idle())
+                .await
+                .is_err()
+            {
+                debug!("shutdown timeout expired, aborting transfers");
+            }
+            let stats = ShutdownStats {
+                drained: transfers.completed() - completed,
+                aborted: transfers.running() as u64,
+            };
+            cancel_token.cancel();
+            stats
+        }
+    }
+}
+
+/// The outcome of a [`Provider::graceful_shutdown`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ShutdownStats {
+    /// The number of transfers which completed successfully while shutting down.
+    pub drained: u64,
+    /// The number of transfers which were aborted because they did not finish in time.
+    pub aborted: u64,
 }
 
 /// The future completes when the spawned tokio task finishes.
@@ -313,12 +1023,52 @@ impl Future for Provider {
     }
 }
 
-async fn handle_connection(
-    connecting: quinn::Connecting,
+/// The state of a provider shared by all its connections.
+#[derive(Debug, Clone)]
+struct ProviderContext {
     db: Database,
-    auth_token: AuthToken,
-    events: broadcast::Sender<Event>,
-) {
+    auth: Authenticator,
+    rate_limits: RateLimits,
+    max_message_size: u64,
+    events: Events,
+    /// Cancelled when a graceful shutdown starts.
+    draining: CancellationToken,
+    transfers: Transfers,
+}
+
+/// The state of a connection shared by all its streams.
+#[derive(Debug, Clone)]
+struct ConnectionContext {
+    db: Database,
+    auth: Authenticator,
+    max_message_size: u64,
+    events: Events,
+    connection_id: u64,
+    /// The [`PeerId`] of the getter, if it presented a certificate.
+    getter: Option<PeerId>,
+    /// The rate limits of the connection.
+    throttle: Throttle,
+}
+
+impl ConnectionContext {
+    async fn notify_transfer_aborted(
+        &self,
+        request_id: Option<u64>,
+        reason: AbortReason,
+        bytes_sent: u64,
+    ) {
+        self.events
+            .send(Event::TransferAborted {
+                connection_id: self.connection_id,
+                request_id,
+                reason,
+                bytes_sent,
+            })
+            .await;
+    }
+}
+
+async fn handle_connection(connecting: quinn::Connecting, ctx: ProviderContext) {
     let remote_addr = connecting.remote_address();
     let connection = match connecting.await {
         Ok(conn) => conn,
@@ -328,54 +1078,143 @@ async fn handle_connection(
         }
     };
     let connection_id = connection.stable_id() as u64;
+    let getter = getter_peer_id(&connection);
+    let conn = ConnectionContext {
+        db: ctx.db,
+        auth: ctx.auth,
+        max_message_size: ctx.max_message_size,
+        events: ctx.events,
+        connection_id,
+        getter,
+        throttle: ctx.rate_limits.throttle(getter),
+    };
     let span = debug_span!("connection", connection_id, %remote_addr);
+    conn.events
+        .send(Event::ClientConnected {
+            connection_id,
+            remote_addr,
+            peer_id: getter,
+        })
+        .await;
     async move {
-        while let Ok(stream) = connection.accept_bi().await {
+        while let Ok(mut stream) = connection.accept_bi().await {
+            if ctx.draining.is_cancelled() {
+                // Shutting down, only transfers which already started are finished.
+                debug!("shutting down, rejecting stream");
+                let error_code = Closed::ProviderTerminating;
+                stream.0.reset(error_code.into()).ok();
+                stream.1.stop(error_code.into()).ok();
+                continue;
+            }
+            // Registered right away, so a graceful shutdown waits for the handshake too.
+            let transfer = ctx.transfers.register();
             let span = debug_span!("stream", stream_id = %stream.0.id());
-            events.send(Event::ClientConnected { connection_id }).ok();
-            let db = db.clone();
-            let events = events.clone();
+            let conn = conn.clone();
             tokio::spawn(
                 async move {
-                    if let Err(err) =
-                        handle_stream(db, auth_token, connection_id, stream, events).await
-                    {
+                    if let Err(err) = handle_stream(conn, stream, transfer).await {
                         warn!("error: {err:#?}",);
                     }
                 }
                 .instrument(span),
             );
         }
+        conn.events
+            .send(Event::ClientDisconnected { connection_id })
+            .await;
     }
     .instrument(span)
     .await
 }
 
+/// Returns the [`PeerId`] of the getter, from the certificate it connected with.
+fn getter_peer_id(connection: &quinn::Connection) -> Option<PeerId> {
+    let identity = connection.peer_identity()?;
+    let certificates = identity.downcast::<Vec<rustls::Certificate>>().ok()?;
+    let certificate = tls::certificate::parse(certificates.first()?).ok()?;
+    Some(certificate.peer_id())
+}
+
+/// Checks the [`Auth`] presented by getters.
+#[derive(Debug, Clone)]
+struct Authenticator {
+    /// The tokens accepted by the provider.
+    tokens: Tokens,
+    /// The key of the provider, verifying the signatures of capabilities.
+    provider: PublicKey,
+    /// The getters allowed to connect, which are authenticated by their [`PeerId`].
+    allowed_peers: Option<Arc<HashSet<PeerId>>>,
+}
+
+impl Authenticator {
+    /// Checks whether `auth` can currently be used by `getter`.
+    fn check(&self, auth: &Auth, getter: Option<&PeerId>) -> Result<()> {
+        match auth {
+            Auth::Token(token) => self.tokens.check(token).context("AuthToken not accepted"),
+            Auth::Capability(capability) => capability
+                .verify(&self.provider, getter)
+                .context("Capability not accepted"),
+            Auth::Identity => {
+                let allowed = match (getter, &self.allowed_peers) {
+                    (Some(getter), Some(allowed_peers)) => allowed_peers.contains(getter),
+                    _ => false,
+                };
+                ensure!(allowed, "PeerId not accepted");
+                Ok(())
+            }
+        }
+    }
+
+    /// Checks whether `auth` gives `getter` access to `hash`.
+    fn authorize(&self, auth: &Auth, getter: Option<&PeerId>, hash: &Hash) -> Result<()> {
+        match auth {
+            Auth::Token(token) => Ok(self.tokens.authorize(token, hash)?),
+            Auth::Capability(capability) => {
+                // The capability may have expired since the handshake.
+                capability.verify(&self.provider, getter)?;
+                ensure!(capability.allows(hash), "hash not in capability");
+                Ok(())
+            }
+            // Allowed getters can get all content.
+            Auth::Identity => self.check(auth, getter),
+        }
+    }
+
+    /// Counts a download made using `auth`, once the content is being sent.
+    ///
+    /// Only tokens limit the number of downloads.
+    fn count_download(&self, auth: &Auth) -> Result<()> {
+        match auth {
+            Auth::Token(token) => Ok(self.tokens.count_download(token)?),
+            Auth::Capability(_) | Auth::Identity => Ok(()),
+        }
+    }
+}
+
 /// Read and decode the handshake.
 ///
-/// Will fail if there is an error while reading, there is a token
-/// mismatch, or no valid handshake was received.
+/// Will fail if there is an error while reading, or no valid handshake was received.
+/// Returns the authentication presented by the getter, which still needs to be checked.
 ///
 /// When successful, the reader is still useable after this function and the buffer will be drained of any handshake
 /// data.
 async fn read_handshake<R: AsyncRead + Unpin>(
     mut reader: R,
     buffer: &mut BytesMut,
-    token: AuthToken,
-) -> Result<()> {
-    if let Some((handshake, size)) = read_lp::<_, Handshake>(&mut reader, buffer).await? {
+    max_size: u64,
+) -> Result<Auth> {
+    if let Some((handshake, size)) = read_lp::<_, Handshake>(&mut reader, buffer, max_size).await? {
         ensure!(
             handshake.version == VERSION,
             "expected version {} but got {}",
             VERSION,
             handshake.version
         );
-        ensure!(handshake.token == token, "AuthToken mismatch");
         let _ = buffer.split_to(size);
+        Ok(handshake.auth)
     } else {
         bail!("no valid handshake received");
     }
-    Ok(())
 }
 
 /// Read the request from the getter.
@@ -384,8 +1223,12 @@ async fn read_handshake<R: AsyncRead + Unpin>(
 /// contains more data than the Request, or if no valid request is sent.
 ///
 /// When successful, the buffer is empty after this function call.
-async fn read_request(mut reader: quinn::RecvStream, buffer: &mut BytesMut) -> Result<Request> {
-    let request = read_lp::<_, Request>(&mut reader, buffer).await?;
+async fn read_request(
+    mut reader: quinn::RecvStream,
+    buffer: &mut BytesMut,
+    max_size: u64,
+) -> Result<Request> {
+    let request = read_lp::<_, Request>(&mut reader, buffer, max_size).await?;
     ensure!(
         reader.read_chunk(8, false).await?.is_none(),
         "Extra data past request"
@@ -402,28 +1245,41 @@ async fn read_request(mut reader: quinn::RecvStream, buffer: &mut BytesMut) -> R
 /// First, it transfers the collection data & its associated outboard encoding data. Then it sequentially transfers each individual blob data & its associated outboard
 /// encoding data.
 ///
+/// The blobs of the collection are added to the `transfer`, so that removing any of them
+/// from the database aborts the transfer.
+///
 /// Will fail if there is an error writing to the getter or reading from
 /// the database.
 ///
-/// If a blob from the collection cannot be found in the database, the transfer will gracefully
-/// close the writer, and return with `Ok(SentStatus::NotFound)`.
+/// If any requested blob of the collection is not available, `Res::CollectionIncomplete` is
+/// sent instead of the collection and this returns with
+/// `Ok(SentStatus::CollectionIncomplete)`.  If a
+/// blob still cannot be found in the database later on, the transfer will gracefully
+/// close the writer, and return with `Ok(SentStatus::NotFound)`.  Likewise if the data of a
+/// blob was modified during the transfer, returning `Ok(SentStatus::Modified)`.
+///
+/// The download is counted once the collection is found complete, see [`count_download`].
 ///
 /// If the transfer does _not_ end in error, the buffer will be empty and the writer is gracefully closed.
+#[allow(clippy::too_many_arguments)]
 async fn transfer_collection(
-    // Database from which to fetch blobs.
-    db: &Database,
+    // The connection, with the database from which to fetch blobs.
+    conn: &ConnectionContext,
     // Quinn stream.
-    mut writer: quinn::SendStream,
+    writer: &mut quinn::SendStream,
+    // Emits the events of the transfer.
+    progress: &mut TransferEvents,
     // Buffer used when writing to writer.
     buffer: &mut BytesMut,
-    // The id of the transfer request.
-    request_id: u64,
-    // The bao outboard encoded data.
-    outboard: &Bytes,
-    // The actual blob data.
-    data: &Bytes,
+    // The transfer request.
+    request: &Request,
+    // The authentication the request was made with.
+    credentials: &Auth,
+    // The in-flight transfer.
+    transfer: &TransferGuard,
+    // The bao outboard encoded data and the actual collection data.
+    (outboard, data): (&Bytes, &Bytes),
 ) -> Result<SentStatus> {
-    // We only respond to requests for collections, not individual blobs
     let mut extractor = SliceExtractor::new_outboard(
         std::io::Cursor::new(&data[..]),
         std::io::Cursor::new(&outboard[..]),
@@ -437,110 +1293,339 @@ async fn transfer_collection(
     extractor.read_to_end(&mut encoded)?;
 
     let c: Collection = postcard::from_bytes(data)?;
+    transfer.add(
+        c.blobs
+            .iter()
+            .filter(|blob| blob.has_data())
+            .map(|blob| blob.hash),
+    );
+
+    // Nothing is sent unless all requested blobs are available, so the getter does not
+    // start a transfer which can not complete.
+    let missing = missing_blobs(&conn.db, &c, request).await;
+    if !missing.is_empty() {
+        debug!("collection incomplete, {} blobs missing", missing.len());
+        write_response(
+            &mut *writer,
+            buffer,
+            request.id,
+            Res::CollectionIncomplete { missing },
+        )
+        .await?;
+        writer.finish().await?;
+        return Ok(SentStatus::CollectionIncomplete);
+    }
+    if !count_download(conn, credentials, &mut *writer, buffer, request).await? {
+        writer.finish().await?;
+        return Ok(SentStatus::Unauthorized);
+    }
 
-    // TODO: we should check if the blobs referenced in this container
-    // actually exist in this provider before returning `FoundCollection`
     write_response(
-        &mut writer,
+        &mut *writer,
         buffer,
-        request_id,
+        request.id,
         Res::FoundCollection {
             total_blobs_size: c.total_blobs_size,
         },
     )
     .await?;
 
-    let mut data = BytesMut::from(&encoded[..]);
-    writer.write_buf(&mut data).await?;
+    for chunk in encoded.chunks(SEND_CHUNK_SIZE) {
+        conn.throttle.acquire(chunk.len()).await;
+        writer.write_all(chunk).await?;
+        progress.sent(chunk.len()).await;
+    }
     for (i, blob) in c.blobs.iter().enumerate() {
-        debug!("writing blob {}/{}", i, c.blobs.len());
-        let (status, writer1) =
-            send_blob(db.clone(), blob.hash, writer, buffer, request_id).await?;
-        writer = writer1;
-        if SentStatus::NotFound == status {
-            write_response(&mut writer, buffer, request_id, Res::NotFound).await?;
-            writer.finish().await?;
-            return Ok(status);
+        if !blob.has_data() || request.range(i) == RangeSpec::Skip {
+            debug!("skipping blob {}/{}", i, c.blobs.len());
+            continue;
         }
-    }
+        debug!("writing blob {}/{}", i, c.blobs.len());
+        let status = send_blob(
+            conn,
+            blob.hash,
+            &mut *writer,
+            progress,
+            buffer,
+            request.id,
+            request.range(i),
+        )
+        .await?;
+        match status {
+            BlobStatus::Sent => {}
+            BlobStatus::NotFound => {
+                write_response(&mut *writer, buffer, request.id, Res::NotFound).await?;
+                writer.finish().await?;
+                return Ok(status.into());
+            }
+            BlobStatus::Modified { .. } => {
+                writer.finish().await?;
+                return Ok(status.into());
+            }
+        }
+    }
 
     writer.finish().await?;
     Ok(SentStatus::Sent)
 }
 
-fn notify_transfer_aborted(
-    events: broadcast::Sender<Event>,
-    connection_id: u64,
-    request_id: Option<u64>,
-) {
-    let _ = events.send(Event::TransferAborted {
-        connection_id,
-        request_id,
-    });
+/// Returns the indices of the requested blobs of a collection which can not be sent.
+///
+/// A blob can not be sent if it is not in the database, or if its file was removed or
+/// modified since it was hashed.
+async fn missing_blobs(db: &Database, collection: &Collection, request: &Request) -> Vec<usize> {
+    let mut missing = Vec::new();
+    for (i, blob) in collection.blobs.iter().enumerate() {
+        if !blob.has_data() || request.range(i) == RangeSpec::Skip {
+            continue;
+        }
+        let available = match db.get(&blob.hash) {
+            Some(BlobOrCollection::Blob(data)) => match tokio::fs::metadata(&data.path).await {
+                Ok(meta) => data.is_unmodified(&meta),
+                Err(_) => false,
+            },
+            _ => false,
+        };
+        if !available {
+            missing.push(i);
+        }
+    }
+    missing
+}
+
+/// Transfers a single blob.
+///
+/// Sends `Res::Found` followed by the bao encoded slice of the blob data for the requested
+/// range, or `Res::NotFound` if the blob is not in the database.  The download is counted
+/// once the blob was opened, see [`count_download`].
+///
+/// If the transfer does _not_ end in error, the writer is gracefully closed.
+async fn transfer_blob(
+    // The connection, with the database from which to fetch the blob.
+    conn: &ConnectionContext,
+    // Quinn stream.
+    writer: &mut quinn::SendStream,
+    // Emits the events of the transfer.
+    progress: &mut TransferEvents,
+    // Buffer used when writing to writer.
+    buffer: &mut BytesMut,
+    // The transfer request.
+    request: &Request,
+    // The authentication the request was made with.
+    credentials: &Auth,
+) -> Result<SentStatus> {
+    let status = match open_blob(conn, request.name, &mut *writer, buffer, request.id).await? {
+        Ok(blob) => {
+            if count_download(conn, credentials, &mut *writer, buffer, request).await? {
+                let range = request.range(0);
+                write_blob(
+                    conn,
+                    blob,
+                    &mut *writer,
+                    progress,
+                    buffer,
+                    request.id,
+                    range,
+                )
+                .await?;
+                SentStatus::Sent
+            } else {
+                SentStatus::Unauthorized
+            }
+        }
+        Err(status) => status.into(),
+    };
+    writer.finish().await?;
+    Ok(status)
+}
+
+/// Counts the download of the requested content, right before its data is sent.
+///
+/// Downloads are only counted once the content is known to be sendable, so requests which
+/// are answered without data do not use them up.  If the download can no longer be
+/// counted, e.g. because concurrent downloads exhausted the token, `Res::NotFound` is
+/// written and `false` returned.
+async fn count_download<W: AsyncWrite + Unpin>(
+    conn: &ConnectionContext,
+    credentials: &Auth,
+    mut writer: W,
+    buffer: &mut BytesMut,
+    request: &Request,
+) -> Result<bool> {
+    match conn.auth.count_download(credentials) {
+        Ok(()) => Ok(true),
+        Err(err) => {
+            debug!("not sending {}: {:#}", request.name, err);
+            write_response(&mut writer, buffer, request.id, Res::NotFound).await?;
+            Ok(false)
+        }
+    }
 }
 
 async fn handle_stream(
-    db: Database,
-    token: AuthToken,
-    connection_id: u64,
+    conn: ConnectionContext,
     (mut writer, mut reader): (quinn::SendStream, quinn::RecvStream),
-    events: broadcast::Sender<Event>,
+    transfer: TransferGuard,
 ) -> Result<()> {
     let mut out_buffer = BytesMut::with_capacity(1024);
     let mut in_buffer = BytesMut::with_capacity(1024);
 
     // 1. Read Handshake
     debug!("reading handshake");
-    if let Err(e) = read_handshake(&mut reader, &mut in_buffer, token).await {
-        notify_transfer_aborted(events, connection_id, None);
+    let max_size = conn.max_message_size;
+    let credentials = match read_handshake(&mut reader, &mut in_buffer, max_size).await {
+        Ok(credentials) => credentials,
+        Err(e) => {
+            conn.notify_transfer_aborted(None, AbortReason::InvalidRequest, 0)
+                .await;
+            return Err(e);
+        }
+    };
+    if let Err(e) = conn.auth.check(&credentials, conn.getter.as_ref()) {
+        conn.notify_transfer_aborted(None, AbortReason::Unauthorized, 0)
+            .await;
         return Err(e);
     }
 
     // 2. Decode the request.
     debug!("reading request");
-    let request = match read_request(reader, &mut in_buffer).await {
+    let request = match read_request(reader, &mut in_buffer, max_size).await {
         Ok(r) => r,
         Err(e) => {
-            notify_transfer_aborted(events, connection_id, None);
+            conn.notify_transfer_aborted(None, AbortReason::InvalidRequest, 0)
+                .await;
             return Err(e);
         }
     };
 
     let hash = request.name;
     debug!("got request({})", request.id);
-    let _ = events.send(Event::RequestReceived {
-        connection_id,
-        request_id: request.id,
-        hash,
-    });
-
-    // 4. Attempt to find hash
-    let (outboard, data) = match db.get(&hash) {
-        // We only respond to requests for collections, not individual blobs
-        Some(BlobOrCollection::Collection(d)) => d,
-        _ => {
-            debug!("not found {}", hash);
-            notify_transfer_aborted(events, connection_id, Some(request.id));
-            write_response(&mut writer, &mut out_buffer, request.id, Res::NotFound).await?;
-            writer.finish().await?;
-
-            return Ok(());
+    conn.events
+        .send(Event::RequestReceived {
+            connection_id: conn.connection_id,
+            request_id: request.id,
+            hash,
+            token_id: credentials.id(),
+        })
+        .await;
+
+    // 3. Track the hash, so the transfer is aborted if the content is removed.
+    transfer.add([hash]);
+    let start = Instant::now();
+
+    // 4. Attempt to find hash and transfer data!
+    let mut progress = TransferEvents::new(conn.events.clone(), conn.connection_id, request.id);
+    let transfer_fut = async {
+        // Content outside the scope of the token is not revealed to exist.
+        let entry = conn
+            .auth
+            .authorize(&credentials, conn.getter.as_ref(), &hash)
+            .map(|()| conn.db.get(&hash));
+        let (authorized, entry) = match entry {
+            Ok(entry) => (true, entry),
+            Err(err) => {
+                debug!("not sending {}: {:#}", hash, err);
+                (false, None)
+            }
+        };
+        match entry {
+            Some(BlobOrCollection::Collection((outboard, data))) => {
+                transfer_collection(
+                    &conn,
+                    &mut writer,
+                    &mut progress,
+                    &mut out_buffer,
+                    &request,
+                    &credentials,
+                    &transfer,
+                    (&outboard, &data),
+                )
+                .await
+            }
+            Some(BlobOrCollection::Blob(_)) => {
+                transfer_blob(
+                    &conn,
+                    &mut writer,
+                    &mut progress,
+                    &mut out_buffer,
+                    &request,
+                    &credentials,
+                )
+                .await
+            }
+            None => {
+                debug!("not found {}", hash);
+                write_response(&mut writer, &mut out_buffer, request.id, Res::NotFound).await?;
+                writer.finish().await?;
+                if authorized {
+                    Ok(SentStatus::NotFound)
+                } else {
+                    Ok(SentStatus::Unauthorized)
+                }
+            }
+        }
+    };
+    let res = tokio::select! {
+        res = transfer_fut => res,
+        _ = transfer.cancelled() => {
+            debug!("content removed, aborting transfer");
+            let error_code = Closed::ContentRemoved;
+            writer.reset(error_code.into()).ok();
+            Ok(SentStatus::Aborted)
         }
     };
 
-    // 5. Transfer data!
-    match transfer_collection(&db, writer, &mut out_buffer, request.id, outboard, data).await {
+    let request_id = Some(request.id);
+    let bytes_sent = progress.bytes_sent;
+    match res {
         Ok(SentStatus::Sent) => {
-            let _ = events.send(Event::TransferCompleted {
-                connection_id,
-                request_id: request.id,
-            });
+            transfer.completed();
+            conn.events
+                .send(Event::TransferCompleted {
+                    connection_id: conn.connection_id,
+                    request_id: request.id,
+                    bytes_sent,
+                    duration: start.elapsed(),
+                })
+                .await;
         }
         Ok(SentStatus::NotFound) => {
-            notify_transfer_aborted(events, connection_id, Some(request.id));
+            let reason = AbortReason::NotFound;
+            conn.notify_transfer_aborted(request_id, reason, bytes_sent)
+                .await;
+        }
+        Ok(SentStatus::CollectionIncomplete) => {
+            let reason = AbortReason::CollectionIncomplete;
+            conn.notify_transfer_aborted(request_id, reason, bytes_sent)
+                .await;
+        }
+        Ok(SentStatus::Unauthorized) => {
+            let reason = AbortReason::Unauthorized;
+            conn.notify_transfer_aborted(request_id, reason, bytes_sent)
+                .await;
+        }
+        Ok(SentStatus::Aborted) => {
+            let reason = AbortReason::ContentRemoved;
+            conn.notify_transfer_aborted(request_id, reason, bytes_sent)
+                .await;
+        }
+        Ok(SentStatus::Modified { hash, path }) => {
+            conn.events
+                .send(Event::BlobModified {
+                    connection_id: conn.connection_id,
+                    request_id: request.id,
+                    hash,
+                    path,
+                })
+                .await;
+            let reason = AbortReason::Modified;
+            conn.notify_transfer_aborted(request_id, reason, bytes_sent)
+                .await;
         }
         Err(e) => {
-            notify_transfer_aborted(events, connection_id, Some(request.id));
+            let reason = AbortReason::Failed;
+            conn.notify_transfer_aborted(request_id, reason, bytes_sent)
+                .await;
             return Err(e);
         }
     }
@@ -549,63 +1634,516 @@ async fn handle_stream(
     Ok(())
 }
 
+/// The outcome of a request.
 #[derive(Clone, Debug, PartialEq, Eq)]
 enum SentStatus {
     Sent,
     NotFound,
+    /// Some requested blobs of the collection are not available, so it was not sent.
+    CollectionIncomplete,
+    /// The getter is not allowed to get the content, it was told the content is not found.
+    Unauthorized,
+    /// The data of the blob was modified since it was hashed, so it was not sent.
+    Modified {
+        hash: Hash,
+        path: PathBuf,
+    },
+    /// The transfer was aborted because its content was removed from the database.
+    Aborted,
+}
+
+/// The outcome of sending a single blob, on its own or as part of a collection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum BlobStatus {
+    Sent,
+    NotFound,
+    /// The data of the blob was modified since it was hashed, so it was not sent.
+    Modified {
+        hash: Hash,
+        path: PathBuf,
+    },
 }
 
-async fn send_blob<W: AsyncWrite + Unpin + Send + 'static>(
-    db: Database,
+impl From<BlobStatus> for SentStatus {
+    fn from(status: BlobStatus) -> Self {
+        match status {
+            BlobStatus::Sent => SentStatus::Sent,
+            BlobStatus::NotFound => SentStatus::NotFound,
+            BlobStatus::Modified { hash, path } => SentStatus::Modified { hash, path },
+        }
+    }
+}
+
+/// The size of the chunks in which blob data is read from disk and sent.
+const SEND_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How many chunks of a blob are read ahead of sending or hashing them.
+const SEND_READ_AHEAD: usize = 4;
+
+/// How often [`Event::TransferProgress`] is emitted while sending.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Emits the events about the progress of a single transfer.
+#[derive(Debug)]
+struct TransferEvents {
+    events: Events,
+    connection_id: u64,
+    request_id: u64,
+    /// The number of bytes sent so far.
+    bytes_sent: u64,
+    /// When [`Event::TransferProgress`] was last emitted.
+    last_progress: Instant,
+}
+
+impl TransferEvents {
+    fn new(events: Events, connection_id: u64, request_id: u64) -> Self {
+        Self {
+            events,
+            connection_id,
+            request_id,
+            bytes_sent: 0,
+            last_progress: Instant::now(),
+        }
+    }
+
+    /// Records that `n` bytes were sent, emitting progress if it is due.
+    async fn sent(&mut self, n: usize) {
+        self.bytes_sent += n as u64;
+        if self.last_progress.elapsed() >= PROGRESS_INTERVAL {
+            self.last_progress = Instant::now();
+            self.events
+                .send(Event::TransferProgress {
+                    connection_id: self.connection_id,
+                    request_id: self.request_id,
+                    bytes_sent: self.bytes_sent,
+                })
+                .await;
+        }
+    }
+
+    async fn blob_started(&self, hash: Hash, size: u64) {
+        self.events
+            .send(Event::BlobStarted {
+                connection_id: self.connection_id,
+                request_id: self.request_id,
+                hash,
+                size,
+            })
+            .await;
+    }
+
+    async fn blob_completed(&self, hash: Hash, bytes_sent: u64, duration: Duration) {
+        self.events
+            .send(Event::BlobCompleted {
+                connection_id: self.connection_id,
+                request_id: self.request_id,
+                hash,
+                bytes_sent,
+                duration,
+            })
+            .await;
+    }
+}
+
+async fn send_blob<W: AsyncWrite + Unpin>(
+    conn: &ConnectionContext,
     name: Hash,
     mut writer: W,
+    progress: &mut TransferEvents,
     buffer: &mut BytesMut,
     id: u64,
-) -> Result<(SentStatus, W)> {
-    match db.get(&name) {
-        Some(BlobOrCollection::Blob(Data {
-            outboard,
-            path,
-            size,
-        })) => {
-            write_response(&mut writer, buffer, id, Res::Found).await?;
-            let path = path.clone();
-            let outboard = outboard.clone();
-            let size = *size;
-            // need to thread the writer though the spawn_blocking, since
-            // taking a reference does not work. spawn_blocking requires
-            // 'static lifetime.
-            writer = tokio::task::spawn_blocking(move || {
-                let file_reader = std::fs::File::open(&path)?;
-                let outboard_reader = std::io::Cursor::new(outboard);
-                let mut wrapper = SyncIoBridge::new(&mut writer);
-                let mut slice_extractor = abao::encode::SliceExtractor::new_outboard(
-                    file_reader,
-                    outboard_reader,
-                    0,
-                    size,
-                );
-                let _copied = std::io::copy(&mut slice_extractor, &mut wrapper)?;
-                std::io::Result::Ok(writer)
-            })
-            .await??;
-            Ok((SentStatus::Sent, writer))
+    range: RangeSpec,
+) -> Result<BlobStatus> {
+    match open_blob(conn, name, &mut writer, buffer, id).await? {
+        Ok(blob) => {
+            write_blob(conn, blob, writer, progress, buffer, id, range).await?;
+            Ok(BlobStatus::Sent)
         }
+        Err(status) => Ok(status),
+    }
+}
+
+/// A blob opened for sending, whose file was checked to be unmodified.
+struct OpenBlob {
+    hash: Hash,
+    data: Data,
+    file: std::fs::File,
+    outboard: OutboardReader,
+}
+
+/// Opens the data and outboard of the blob `name` for sending.
+///
+/// If the blob can not be sent, the response telling the getter why is written and the
+/// status is returned instead.
+async fn open_blob<W: AsyncWrite + Unpin>(
+    conn: &ConnectionContext,
+    name: Hash,
+    mut writer: W,
+    buffer: &mut BytesMut,
+    id: u64,
+) -> Result<Result<OpenBlob, BlobStatus>> {
+    let data = match conn.db.get(&name) {
+        Some(BlobOrCollection::Blob(data)) => data,
         _ => {
             write_response(&mut writer, buffer, id, Res::NotFound).await?;
-            Ok((SentStatus::NotFound, writer))
+            return Ok(Err(BlobStatus::NotFound));
+        }
+    };
+    let path = data.path.clone();
+    // The file is opened before checking it, so the checked file is the one sent.
+    let opened = tokio::task::spawn_blocking(move || {
+        let file = match std::fs::File::open(&data.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        if !data.is_unmodified(&file.metadata()?) {
+            return Ok(None);
+        }
+        let outboard = data.outboard.open()?;
+        Ok(Some(OpenBlob {
+            hash: name,
+            data,
+            file,
+            outboard,
+        }))
+    })
+    .await??;
+    match opened {
+        Some(blob) => Ok(Ok(blob)),
+        None => {
+            warn!("not sending {}: {} was modified", name, path.display());
+            write_response(&mut writer, buffer, id, Res::Modified).await?;
+            Ok(Err(BlobStatus::Modified { hash: name, path }))
+        }
+    }
+}
+
+/// Sends `Res::Found` followed by the bao encoded slice of an opened blob for `range`.
+async fn write_blob<W: AsyncWrite + Unpin>(
+    conn: &ConnectionContext,
+    blob: OpenBlob,
+    mut writer: W,
+    progress: &mut TransferEvents,
+    buffer: &mut BytesMut,
+    id: u64,
+    range: RangeSpec,
+) -> Result<()> {
+    let OpenBlob {
+        hash: name,
+        data,
+        file,
+        outboard,
+    } = blob;
+    let Data {
+        chunk_group_log,
+        size,
+        ..
+    } = data;
+    write_response(&mut writer, buffer, id, Res::Found { chunk_group_log }).await?;
+    if range == RangeSpec::Skip {
+        return Ok(());
+    }
+    let start = Instant::now();
+    let start_bytes = progress.bytes_sent;
+    progress.blob_started(name, size).await;
+    let (offset, len) = range.slice();
+    let mut slice_extractor = bao::SliceExtractor::new(
+        file,
+        outboard,
+        chunk_group_log,
+        offset,
+        std::cmp::min(len, size),
+    );
+    // The extractor does blocking reads, so a single blocking task reads the chunks
+    // ahead while writing to the stream happens here.  The reader stops once the
+    // receiver is dropped.
+    let (chunks_tx, mut chunks_rx) = mpsc::channel(SEND_READ_AHEAD);
+    tokio::task::spawn_blocking(move || loop {
+        let mut chunk = vec![0u8; SEND_CHUNK_SIZE];
+        let res = slice_extractor.read(&mut chunk).map(|read| {
+            chunk.truncate(read);
+            chunk
+        });
+        let done = !matches!(res, Ok(ref chunk) if !chunk.is_empty());
+        if chunks_tx.blocking_send(res).is_err() || done {
+            break;
+        }
+    });
+    while let Some(chunk) = chunks_rx.recv().await {
+        let chunk = chunk?;
+        if chunk.is_empty() {
+            break;
+        }
+        conn.throttle.acquire(chunk.len()).await;
+        writer.write_all(&chunk).await?;
+        progress.sent(chunk.len()).await;
+    }
+    progress
+        .blob_completed(name, progress.bytes_sent - start_bytes, start.elapsed())
+        .await;
+    Ok(())
+}
+
+/// Removes `hash` from the database and aborts the transfers including it.
+fn remove_content(db: &Database, transfers: &Transfers, hash: &Hash) -> bool {
+    let removed = db.remove(hash);
+    let aborted = transfers.abort(hash);
+    debug!("removed {}, aborted {} transfers", hash, aborted);
+    removed
+}
+
+/// Transfers in progress, tracked so they can be aborted when their content is removed and
+/// drained when the provider shuts down.
+#[derive(Debug, Clone, Default)]
+struct Transfers {
+    running: Arc<Mutex<HashMap<u64, TransferEntry>>>,
+    /// The number of transfers which sent all requested content.
+    completed: Arc<AtomicU64>,
+    /// Notified whenever a transfer finishes.
+    finished_notify: Arc<Notify>,
+}
+
+#[derive(Debug)]
+struct TransferEntry {
+    /// The hashes being transferred.
+    hashes: Vec<Hash>,
+    /// Cancelled to abort the transfer.
+    cancel: CancellationToken,
+}
+
+impl Transfers {
+    /// Registers a new transfer, its hashes are added once known.
+    ///
+    /// The transfer is unregistered when the returned guard is dropped.
+    fn register(&self) -> TransferGuard {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let cancel = CancellationToken::new();
+        self.running.lock().unwrap().insert(
+            id,
+            TransferEntry {
+                hashes: Vec::new(),
+                cancel: cancel.clone(),
+            },
+        );
+        TransferGuard {
+            transfers: self.clone(),
+            id,
+            cancel,
+        }
+    }
+
+    /// Aborts all transfers which include the given hash, returns how many were aborted.
+    fn abort(&self, hash: &Hash) -> usize {
+        let transfers = self.running.lock().unwrap();
+        let mut aborted = 0;
+        for transfer in transfers.values() {
+            if transfer.hashes.contains(hash) {
+                transfer.cancel.cancel();
+                aborted += 1;
+            }
+        }
+        aborted
+    }
+
+    /// The number of transfers in progress.
+    fn running(&self) -> usize {
+        self.running.lock().unwrap().len()
+    }
+
+    /// The number of transfers which completed so far.
+    fn completed(&self) -> u64 {
+        self.completed.load(Ordering::SeqCst)
+    }
+
+    /// Completes once no transfers are in progress.
+    async fn idle(&self) {
+        loop {
+            // Created before checking, so a transfer finishing in between is not missed.
+            let notified = self.finished_notify.notified();
+            if self.running() == 0 {
+                return;
+            }
+            notified.await;
         }
     }
 }
 
+/// An in-flight transfer registered with [`Transfers`].
+#[derive(Debug)]
+struct TransferGuard {
+    transfers: Transfers,
+    id: u64,
+    cancel: CancellationToken,
+}
+
+impl TransferGuard {
+    /// Adds more hashes which are part of this transfer.
+    fn add(&self, hashes: impl IntoIterator<Item = Hash>) {
+        if let Some(transfer) = self.transfers.running.lock().unwrap().get_mut(&self.id) {
+            transfer.hashes.extend(hashes);
+        }
+    }
+
+    /// Completes when the transfer is aborted.
+    async fn cancelled(&self) {
+        self.cancel.cancelled().await
+    }
+
+    /// Records that the transfer sent all requested content.
+    fn completed(&self) {
+        self.transfers.completed.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl Drop for TransferGuard {
+    fn drop(&mut self) {
+        self.transfers.running.lock().unwrap().remove(&self.id);
+        self.transfers.finished_notify.notify_waiters();
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub(crate) struct Data {
-    /// Outboard data from bao.
-    outboard: Bytes,
-    /// Path to the original data, which must not change while in use.
+    /// Outboard data from bao, with chunk groups.
+    outboard: Outboard,
+    /// The chunk groups of the outboard are `2^chunk_group_log` chunks.
+    chunk_group_log: u8,
+    /// Path to the original data, which must not change while in use.  Modifications are
+    /// detected using `stamp`, the blob is no longer sent once it changed.
     path: PathBuf,
     /// Size of the original data.
     size: u64,
+    /// Stamp of the original data when it was hashed, used to detect modifications.
+    stamp: FileStamp,
+}
+
+/// Identifies the version of a file from which an outboard was computed.
+///
+/// Together with the size, a file whose stamp changed is considered modified and no longer
+/// matches its hash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct FileStamp {
+    /// Modification time, as seconds and nanoseconds since the unix epoch.
+    mtime: Option<(u64, u32)>,
+    /// The inode number, only available on unix.
+    inode: Option<u64>,
+}
+
+impl FileStamp {
+    fn new(meta: &std::fs::Metadata) -> Self {
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|mtime| mtime.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|mtime| (mtime.as_secs(), mtime.subsec_nanos()));
+        #[cfg(unix)]
+        let inode = {
+            use std::os::unix::fs::MetadataExt;
+            Some(meta.ino())
+        };
+        #[cfg(not(unix))]
+        let inode = None;
+        FileStamp { mtime, inode }
+    }
+}
+
+impl Data {
+    /// Whether the metadata of the file still matches the data when it was hashed.
+    fn is_unmodified(&self, meta: &std::fs::Metadata) -> bool {
+        meta.is_file() && meta.len() == self.size && FileStamp::new(meta) == self.stamp
+    }
+}
+
+/// Where the outboard of a blob is stored.
+///
+/// Outboards are never held in memory, they are only read when the blob is transferred.
+/// Outboards of freshly hashed data are written to temporary files, while outboards loaded
+/// from a store stay in their sidecar file.  This keeps the memory used by a provider
+/// independent of the amount of data it serves.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Outboard {
+    /// The outboard is stored in a file, starting at `offset`.
+    File { path: PathBuf, offset: u64 },
+    /// The outboard is stored in a temporary file, deleted once the outboard is unused.
+    Temp(TempOutboard),
+}
+
+impl Outboard {
+    /// Writes a freshly computed outboard to a temporary file.
+    ///
+    /// This does blocking IO.
+    fn temp(outboard: &[u8]) -> std::io::Result<Self> {
+        let mut file = tempfile::Builder::new()
+            .prefix(".sendme-outboard-")
+            .tempfile()?;
+        file.write_all(outboard)?;
+        Ok(Outboard::Temp(TempOutboard(Arc::new(
+            file.into_temp_path(),
+        ))))
+    }
+
+    /// The file containing the outboard and the offset of the outboard in it.
+    fn location(&self) -> (&Path, u64) {
+        match self {
+            Outboard::File { path, offset } => (path, *offset),
+            Outboard::Temp(temp) => (&temp.0, 0),
+        }
+    }
+
+    /// Opens the outboard for reading.
+    ///
+    /// This does blocking IO.
+    fn open(&self) -> std::io::Result<OutboardReader> {
+        let (path, offset) = self.location();
+        let mut file = std::fs::File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(OutboardReader { file, offset })
+    }
+}
+
+/// A temporary outboard file, deleted when the last clone is dropped.
+#[derive(Clone, Debug)]
+struct TempOutboard(Arc<tempfile::TempPath>);
+
+impl PartialEq for TempOutboard {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for TempOutboard {}
+
+/// Reader for an [`Outboard`], positions are relative to the start of the outboard.
+#[derive(Debug)]
+struct OutboardReader {
+    file: std::fs::File,
+    offset: u64,
+}
+
+impl Read for OutboardReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Seek for OutboardReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => SeekFrom::Start(pos + self.offset),
+            pos => pos,
+        };
+        let pos = self.file.seek(pos)?;
+        if pos < self.offset {
+            self.file.seek(SeekFrom::Start(self.offset))?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek before the start of the outboard",
+            ));
+        }
+        Ok(pos - self.offset)
+    }
 }
 
 /// A data source
@@ -622,6 +2160,21 @@ pub enum DataSource {
         /// Custom name
         name: String,
     },
+    /// A directory.  It has no data, but is kept so that empty directories and the metadata
+    /// of directories are preserved.
+    Directory {
+        /// Path to the directory
+        path: PathBuf,
+        /// Name of the entry
+        name: String,
+    },
+    /// A symbolic link.  It has no data, the link itself is stored rather than followed.
+    Symlink {
+        /// Path to the symlink
+        path: PathBuf,
+        /// Name of the entry
+        name: String,
+    },
 }
 
 impl DataSource {
@@ -647,99 +2200,279 @@ impl From<&std::path::Path> for DataSource {
     }
 }
 
-/// Synchronously compute the outboard of a file, and return hash and outboard.
+/// Collects the entries of a directory tree as [`DataSource`]s.
+///
+/// The directory is traversed recursively, and each file, directory and symlink is named by
+/// its path relative to `root`, using `/` as the separator.  Symlinks are not followed.  The
+/// sources are sorted by name, so the same tree always results in the same collection, and
+/// directories always come before their content.
+pub async fn data_sources_from_dir(root: impl AsRef<Path>) -> Result<Vec<DataSource>> {
+    let root = root.as_ref().to_path_buf();
+    let mut files = tokio::task::spawn_blocking(move || {
+        let mut files = Vec::new();
+        walk_dir(&root, "", &mut files).map(|_| files)
+    })
+    .await??;
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(files.into_iter().map(|(_, source)| source).collect())
+}
+
+/// Recursively collects the entries below `dir`, together with their names.
+fn walk_dir(dir: &Path, prefix: &str, files: &mut Vec<(String, DataSource)>) -> Result<()> {
+    let entries = std::fs::read_dir(dir).with_context(|| format!("reading {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        let name = entry.file_name();
+        let name = name
+            .to_str()
+            .with_context(|| format!("file name is not valid UTF-8: {}", path.display()))?;
+        let name = format!("{prefix}{name}");
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            walk_dir(&path, &format!("{name}/"), files)?;
+            let source = DataSource::Directory {
+                path,
+                name: name.clone(),
+            };
+            files.push((name, source));
+        } else if file_type.is_symlink() {
+            let source = DataSource::Symlink {
+                path,
+                name: name.clone(),
+            };
+            files.push((name, source));
+        } else if file_type.is_file() {
+            files.push((name.clone(), DataSource::with_name(path, name)));
+        }
+    }
+    Ok(())
+}
+
+/// Reads the metadata of the entry at `path`, without following symlinks.
+fn read_metadata(path: &Path, kind: EntryKind) -> Result<Metadata> {
+    let meta = std::fs::symlink_metadata(path)
+        .with_context(|| format!("reading metadata of {}", path.display()))?;
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::fs::PermissionsExt;
+        Some(meta.permissions().mode() & 0o777)
+    };
+    #[cfg(not(unix))]
+    let mode = None;
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|mtime| mtime.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|mtime| mtime.as_secs());
+    Ok(Metadata::V1(MetadataV1 { kind, mode, mtime }))
+}
+
+/// An entry of a collection computed from a [`DataSource`].
+#[derive(Clone)]
+struct SourceEntry {
+    name: String,
+    hash: Hash,
+    metadata: Metadata,
+    /// The data of the blob, only present for files.
+    data: Option<Data>,
+}
+
+impl SourceEntry {
+    /// Creates the entry of a file, from its freshly computed outboard.
+    ///
+    /// This does blocking IO, the outboard is written to a temporary file.
+    fn file(
+        name: String,
+        hash: Hash,
+        metadata: Metadata,
+        path: PathBuf,
+        outboard: Vec<u8>,
+        stamp: FileStamp,
+    ) -> Result<Self> {
+        debug_assert!(outboard.len() >= 8, "outboard must at least contain size");
+        let size = u64::from_le_bytes(outboard[..8].try_into().unwrap());
+        Ok(SourceEntry {
+            name,
+            hash,
+            metadata,
+            data: Some(Data {
+                outboard: Outboard::temp(&outboard).context("failed to write outboard")?,
+                chunk_group_log: bao::DEFAULT_CHUNK_GROUP_LOG,
+                path,
+                size,
+                stamp,
+            }),
+        })
+    }
+}
+
+/// Synchronously computes the collection entry for a data source.
+fn compute_entry(source: DataSource) -> Result<SourceEntry> {
+    let (path, name, kind) = match source {
+        DataSource::File(path) => (path, None, EntryKind::File),
+        DataSource::NamedFile { path, name } => (path, Some(name), EntryKind::File),
+        DataSource::Directory { path, name } => (path, Some(name), EntryKind::Directory),
+        DataSource::Symlink { path, name } => {
+            let target = std::fs::read_link(&path)
+                .with_context(|| format!("reading symlink {}", path.display()))?;
+            let target = target
+                .to_str()
+                .with_context(|| format!("symlink target is not valid UTF-8: {}", path.display()))?
+                .to_string();
+            (path, Some(name), EntryKind::Symlink(target))
+        }
+    };
+    let metadata = read_metadata(&path, kind.clone())?;
+    if kind != EntryKind::File {
+        return Ok(SourceEntry {
+            name: name.unwrap_or_default(),
+            hash: Hash::new([]),
+            metadata,
+            data: None,
+        });
+    }
+    let (hash, outboard, stamp) = compute_outboard(&path)?;
+    // if the given name is `None`, use the filename from the given path as the name
+    let name = name.unwrap_or_else(|| {
+        path.file_name()
+            .and_then(|s| s.to_str())
+            .unwrap_or_default()
+            .to_string()
+    });
+    SourceEntry::file(name, hash, metadata, path, outboard, stamp)
+}
+
+/// Synchronously compute the outboard of a file, and return hash, outboard and stamp.
 ///
 /// It is assumed that the file is not modified while this is running.
 ///
 /// If it is modified while or after this is running, the outboard will be
 /// invalid, so any attempt to compute a slice from it will fail.
 ///
-/// If the size of the file is changed while this is running, an error will be
-/// returned.
-///
-/// path and name are returned with the result to provide context
-fn compute_outboard(
-    path: PathBuf,
-    name: Option<String>,
-) -> anyhow::Result<(PathBuf, Option<String>, Hash, Vec<u8>)> {
+/// If the size or stamp of the file is changed while this is running, an error will be
+/// returned.  Modifications after this has run are detected using the returned stamp.
+fn compute_outboard(path: &Path) -> anyhow::Result<(Hash, Vec<u8>, FileStamp)> {
     ensure!(
         path.is_file(),
         "can only transfer blob data: {}",
         path.display()
     );
-    let file = std::fs::File::open(&path)?;
-    let len = file.metadata()?.len();
-    // compute outboard size so we can pre-allocate the buffer.
-    //
-    // outboard is ~1/16 of data size, so this will fail for really large files
-    // on really small devices. E.g. you want to transfer a 1TB file from a pi4 with 1gb ram.
-    //
-    // The way to solve this would be to have larger blocks than the blake3 chunk size of 1024.
-    // I think we really want to keep the outboard in memory for simplicity.
-    let outboard_size = usize::try_from(abao::encode::outboard_size(len))
+    let file = std::fs::File::open(path)?;
+    let meta = file.metadata()?;
+    let len = meta.len();
+    let stamp = FileStamp::new(&meta);
+    // The outboard is computed in memory before it is written to a temporary file.  Using
+    // chunk groups it is only a small fraction of the data size, see
+    // `bao::DEFAULT_CHUNK_GROUP_LOG`.
+    usize::try_from(bao::outboard_size(len, bao::DEFAULT_CHUNK_GROUP_LOG))
         .context("outboard too large to fit in memory")?;
-    let mut outboard = Vec::with_capacity(outboard_size);
-
-    // copy the file into the encoder. Data will be skipped by the encoder in outboard mode.
-    let outboard_cursor = std::io::Cursor::new(&mut outboard);
-    let mut encoder = abao::encode::Encoder::new_outboard(outboard_cursor);
 
+    // copy the file into the encoder, only the outboard is kept.
+    let mut encoder = bao::Encoder::new(bao::DEFAULT_CHUNK_GROUP_LOG);
     let mut reader = BufReader::new(file);
     // the length we have actually written, should be the same as the length of the file.
     let len2 = std::io::copy(&mut reader, &mut encoder)?;
     // this can fail if the file was appended to during encoding.
     ensure!(len == len2, "file changed during encoding");
-    // this flips the outboard encoding from post-order to pre-order
-    let hash = encoder.finalize()?;
+    ensure!(
+        FileStamp::new(&reader.get_ref().metadata()?) == stamp,
+        "file changed during encoding"
+    );
+    let (hash, outboard) = encoder.finalize();
 
-    Ok((path, name, hash.into(), outboard))
+    Ok((hash.into(), outboard, stamp))
 }
 
 /// Creates a database of blobs (stored in outboard storage) and Collections, stored in memory.
 /// Returns a the hash of the collection created by the given list of DataSources
 pub async fn create_collection(data_sources: Vec<DataSource>) -> Result<(Database, Hash)> {
-    // +1 is for the collection itself
-    let mut db = HashMap::with_capacity(data_sources.len() + 1);
-    let mut blobs = Vec::with_capacity(data_sources.len());
-    let mut total_blobs_size: u64 = 0;
-    let mut blobs_encoded_size_estimate = 0;
-
     // compute outboards in parallel, using tokio's blocking thread pool
-    let outboards = data_sources.into_iter().map(|data| {
-        let (path, name) = match data {
-            DataSource::File(path) => (path, None),
-            DataSource::NamedFile { path, name } => (path, Some(name)),
-        };
-        tokio::task::spawn_blocking(move || compute_outboard(path, name))
-    });
+    let entries = data_sources
+        .into_iter()
+        .map(|data| tokio::task::spawn_blocking(move || compute_entry(data)));
     // wait for completion and collect results
-    let outboards = future::join_all(outboards)
+    let entries = future::join_all(entries)
         .await
         .into_iter()
         .collect::<Result<Result<Vec<_>, _>, _>>()??;
+    build_collection(entries)
+}
+
+/// Creates a database with a collection of a single blob, read from `reader`.
+///
+/// The data is written to the file at `path` while its outboard is computed, so the data
+/// only needs to be read once.  The file is created or truncated, and must be kept for as
+/// long as the blob is served.  An empty `name` does not persist a name, like
+/// [`DataSource::NamedFile`].
+pub async fn create_collection_from_reader<R: AsyncRead + Unpin>(
+    mut reader: R,
+    path: PathBuf,
+    name: String,
+) -> Result<(Database, Hash)> {
+    // Hashing and writing are blocking, so they happen in a single blocking task which is
+    // fed the data read here.
+    let (chunks_tx, mut chunks_rx) = mpsc::channel::<Bytes>(SEND_READ_AHEAD);
+    let encode = tokio::task::spawn_blocking(move || -> Result<_> {
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .with_context(|| format!("creating {}", path.display()))?;
+        let mut encoder = bao::Encoder::new(bao::DEFAULT_CHUNK_GROUP_LOG);
+        while let Some(chunk) = chunks_rx.blocking_recv() {
+            // only the outboard is kept by the encoder
+            encoder.update(&chunk);
+            file.write_all(&chunk)?;
+        }
+        file.sync_all()?;
+        let stamp = FileStamp::new(&file.metadata()?);
+        let (hash, outboard) = encoder.finalize();
+        SourceEntry::file(name, hash.into(), Metadata::None, path, outboard, stamp)
+    });
+    loop {
+        let mut buffer = BytesMut::with_capacity(SEND_CHUNK_SIZE);
+        if reader.read_buf(&mut buffer).await? == 0 {
+            break;
+        }
+        // the encoding task only stops early on errors, which it returns below
+        if chunks_tx.send(buffer.freeze()).await.is_err() {
+            break;
+        }
+    }
+    drop(chunks_tx);
+    build_collection(vec![encode.await??])
+}
+
+/// Inserts the entries into a new database, together with the collection of them.
+fn build_collection(entries: Vec<SourceEntry>) -> Result<(Database, Hash)> {
+    // +1 is for the collection itself
+    let mut db = HashMap::with_capacity(entries.len() + 1);
+    let mut blobs = Vec::with_capacity(entries.len());
+    let mut total_blobs_size: u64 = 0;
+    let mut blobs_encoded_size_estimate = 0;
+
     // insert outboards into the database and build collection
 
-    for (path, name, hash, outboard) in outboards {
-        debug_assert!(outboard.len() >= 8, "outboard must at least contain size");
-        let size = u64::from_le_bytes(outboard[..8].try_into().unwrap());
-        db.insert(
-            hash,
-            BlobOrCollection::Blob(Data {
-                outboard: Bytes::from(outboard),
-                path: path.clone(),
-                size,
-            }),
-        );
-        total_blobs_size += size;
-        // if the given name is `None`, use the filename from the given path as the name
-        let name = name.unwrap_or_else(|| {
-            path.file_name()
-                .and_then(|s| s.to_str())
-                .unwrap_or_default()
-                .to_string()
+    for entry in entries {
+        if let Some(data) = entry.data {
+            total_blobs_size += data.size;
+            db.insert(entry.hash, BlobOrCollection::Blob(data));
+        }
+        let metadata_size = match entry.metadata {
+            Metadata::V1(MetadataV1 {
+                kind: EntryKind::Symlink(ref target),
+                ..
+            }) => target.len() + 32,
+            _ => 32,
+        };
+        blobs_encoded_size_estimate += entry.name.len() + 32 + metadata_size;
+        blobs.push(Blob {
+            name: entry.name,
+            hash: entry.hash,
+            metadata: entry.metadata,
         });
-        blobs_encoded_size_estimate += name.len() + 32;
-        blobs.push(Blob { name, hash });
     }
 
     let c = Collection {
@@ -762,7 +2495,7 @@ pub async fn create_collection(data_sources: Vec<DataSource>) -> Result<(Databas
         BlobOrCollection::Collection((Bytes::from(outboard), Bytes::from(data.to_vec()))),
     );
 
-    Ok((Database(Arc::new(db)), hash))
+    Ok((Database(Arc::new(RwLock::new(db))), hash))
 }
 
 async fn write_response<W: AsyncWrite + Unpin>(
@@ -797,8 +2530,8 @@ pub struct Ticket {
     pub peer: PeerId,
     /// The socket address the provider is listening on.
     pub addr: SocketAddr,
-    /// The authentication token with permission to retrieve the hash.
-    pub token: AuthToken,
+    /// The authentication with permission to retrieve the hash.
+    pub auth: Auth,
 }
 
 impl Ticket {
@@ -846,12 +2579,11 @@ mod tests {
         let hash = Hash::from(hash);
         let peer = PeerId::from(Keypair::generate().public());
         let addr = SocketAddr::from_str("127.0.0.1:1234").unwrap();
-        let token = AuthToken::generate();
         let ticket = Ticket {
             hash,
             peer,
             addr,
-            token,
+            auth: AuthToken::generate().into(),
         };
         let base64 = ticket.to_string();
         println!("Ticket: {base64}");
@@ -871,29 +2603,32 @@ mod tests {
         // DataSource::File
         let foo = dir.join("foo");
         tokio::fs::write(&foo, vec![]).await?;
-        let foo = DataSource::new(foo);
         expect_blobs.push(Blob {
             name: "foo".to_string(),
             hash,
+            metadata: read_metadata(&foo, EntryKind::File)?,
         });
+        let foo = DataSource::new(foo);
 
         // DataSource::NamedFile
         let bar = dir.join("bar");
         tokio::fs::write(&bar, vec![]).await?;
-        let bar = DataSource::with_name(bar, "bat".to_string());
         expect_blobs.push(Blob {
             name: "bat".to_string(),
             hash,
+            metadata: read_metadata(&bar, EntryKind::File)?,
         });
+        let bar = DataSource::with_name(bar, "bat".to_string());
 
         // DataSource::NamedFile, empty string name
         let baz = dir.join("baz");
         tokio::fs::write(&baz, vec![]).await?;
-        let baz = DataSource::with_name(baz, "".to_string());
         expect_blobs.push(Blob {
             name: "".to_string(),
             hash,
+            metadata: read_metadata(&baz, EntryKind::File)?,
         });
+        let baz = DataSource::with_name(baz, "".to_string());
 
         let expect_collection = Collection {
             name: "collection".to_string(),
@@ -906,7 +2641,7 @@ mod tests {
         let collection = {
             let c = db.get(&hash).unwrap();
             if let BlobOrCollection::Collection((_, data)) = c {
-                Collection::from_bytes(data)?
+                Collection::from_bytes(&data)?
             } else {
                 panic!("expected hash to correspond with a `Collection`, found `Blob` instead");
             }
@@ -916,4 +2651,169 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_create_collection_from_reader() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let mut content = vec![0u8; 1024 * 1024 + 17];
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut content);
+        let foo = dir.join("foo");
+        tokio::fs::write(&foo, &content).await?;
+        let (expect_db, _) = create_collection(vec![foo.into()]).await?;
+
+        let spooled = dir.join("spooled");
+        let (db, hash) =
+            create_collection_from_reader(&content[..], spooled.clone(), "bar".to_string()).await?;
+        assert_eq!(tokio::fs::read(&spooled).await?, content);
+
+        let blob_hash = Hash::new(&content);
+        let collection = match db.get(&hash) {
+            Some(BlobOrCollection::Collection((_, data))) => Collection::from_bytes(&data)?,
+            _ => panic!("expected a collection"),
+        };
+        assert_eq!(collection.blobs.len(), 1);
+        assert_eq!(collection.blobs[0].name, "bar");
+        assert_eq!(collection.blobs[0].hash, blob_hash);
+        assert_eq!(collection.total_blobs_size, content.len() as u64);
+        match (db.get(&blob_hash), expect_db.get(&blob_hash)) {
+            (Some(BlobOrCollection::Blob(data)), Some(BlobOrCollection::Blob(expect))) => {
+                let outboard = data.outboard.location().0.to_path_buf();
+                assert_eq!(
+                    std::fs::read(&outboard)?,
+                    std::fs::read(expect.outboard.location().0)?
+                );
+                assert_eq!(data.path, spooled);
+                assert_eq!(data.size, expect.size);
+
+                // The temporary outboard is deleted once the blob is no longer used.
+                drop((db, data));
+                assert!(!outboard.exists());
+            }
+            _ => panic!("expected a blob"),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_data_sources_from_dir() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        tokio::fs::create_dir_all(dir.join("a/b")).await?;
+        tokio::fs::create_dir_all(dir.join("c")).await?;
+        tokio::fs::write(dir.join("foo"), b"foo").await?;
+        tokio::fs::write(dir.join("a/foo"), b"a foo").await?;
+        tokio::fs::write(dir.join("a/b/bar"), b"bar").await?;
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("a/foo", dir.join("link"))?;
+
+        let sources = data_sources_from_dir(&dir).await?;
+        let names: Vec<_> = sources
+            .iter()
+            .map(|source| match source {
+                DataSource::NamedFile { name, .. }
+                | DataSource::Directory { name, .. }
+                | DataSource::Symlink { name, .. } => name.as_str(),
+                DataSource::File(_) => panic!("expected named sources"),
+            })
+            .collect();
+        let mut expect = vec!["a", "a/b", "a/b/bar", "a/foo", "c", "foo"];
+        if cfg!(unix) {
+            expect.push("link");
+        }
+        assert_eq!(names, expect);
+
+        let (db, hash) = create_collection(sources).await?;
+        let collection = match db.get(&hash) {
+            Some(BlobOrCollection::Collection((_, data))) => Collection::from_bytes(&data)?,
+            _ => panic!("expected a collection"),
+        };
+        assert_eq!(collection.blobs.len(), expect.len());
+        assert_eq!(collection.total_blobs_size, 11);
+        let kinds: Vec<_> = collection
+            .blobs
+            .iter()
+            .map(|blob| match &blob.metadata {
+                Metadata::V1(meta) => meta.kind.clone(),
+                Metadata::None => panic!("expected metadata"),
+            })
+            .collect();
+        assert_eq!(kinds[0], EntryKind::Directory);
+        assert_eq!(kinds[4], EntryKind::Directory);
+        assert_eq!(kinds[5], EntryKind::File);
+        assert_eq!(collection.blobs[3].name, "a/foo");
+        assert_eq!(collection.blobs[3].hash, Hash::new(b"a foo"));
+        assert!(collection.blobs[3].has_data());
+        assert!(!collection.blobs[4].has_data());
+        #[cfg(unix)]
+        assert_eq!(kinds[6], EntryKind::Symlink("a/foo".to_string()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_database_save_load() -> Result<()> {
+        let dir: PathBuf = testdir!();
+        let foo = dir.join("foo");
+        tokio::fs::write(&foo, b"hello foo").await?;
+        let bar = dir.join("bar");
+        tokio::fs::write(&bar, b"hello bar").await?;
+        let (db, hash) = create_collection(vec![foo.into(), bar.into()]).await?;
+
+        let store = dir.join("store");
+        db.save(&store).await?;
+        let loaded = Database::load(&store).await?;
+
+        assert_eq!(loaded.collections().collect::<Vec<_>>(), vec![hash]);
+        let mut expect = db.blobs().map(|(h, _, s)| (h, s)).collect::<Vec<_>>();
+        let mut got = loaded.blobs().map(|(h, _, s)| (h, s)).collect::<Vec<_>>();
+        expect.sort_by_key(|(h, _)| h.to_string());
+        got.sort_by_key(|(h, _)| h.to_string());
+        assert_eq!(expect, got);
+        assert_eq!(db.get(&hash), loaded.get(&hash));
+
+        // Blob outboards are not loaded into memory, but read from the store.
+        let read_outboard = |db: &Database, hash: &Hash| -> Result<(Vec<u8>, u8)> {
+            match db.get(hash) {
+                Some(BlobOrCollection::Blob(data)) => {
+                    let mut outboard = Vec::new();
+                    data.outboard.open()?.read_to_end(&mut outboard)?;
+                    Ok((outboard, data.chunk_group_log))
+                }
+                _ => bail!("missing blob {}", hash),
+            }
+        };
+        for (hash, _, _) in db.blobs() {
+            match loaded.get(&hash) {
+                Some(BlobOrCollection::Blob(Data {
+                    outboard: Outboard::File { .. },
+                    ..
+                })) => {}
+                other => panic!("unexpected entry {other:?}"),
+            }
+            assert_eq!(read_outboard(&db, &hash)?, read_outboard(&loaded, &hash)?);
+        }
+
+        // Saving a loaded database, to the same or another store, keeps the outboards.
+        loaded.save(&store).await?;
+        let other_store = dir.join("other_store");
+        loaded.save(&other_store).await?;
+        for store in [&store, &other_store] {
+            let reloaded = Database::load(store).await?;
+            for (hash, _, _) in db.blobs() {
+                assert_eq!(read_outboard(&db, &hash)?, read_outboard(&reloaded, &hash)?);
+            }
+        }
+
+        // A blob whose data changed is not loaded.
+        tokio::fs::write(dir.join("foo"), b"hello foo, changed").await?;
+        let loaded = Database::load(&store).await?;
+        assert_eq!(loaded.blobs().count(), 1);
+
+        // Also if its size did not change.
+        let bar = dir.join("bar");
+        tokio::fs::write(&bar, b"hello BAR").await?;
+        filetime::set_file_mtime(&bar, filetime::FileTime::from_unix_time(1_000_000_000, 0))?;
+        let loaded = Database::load(&store).await?;
+        assert_eq!(loaded.blobs().count(), 0);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,449 @@
+//! A JSON-lines log of the requests served by a provider.
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use futures::future::{self, BoxFuture};
+use futures::FutureExt;
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use super::{AbortReason, Event, EventSink};
+use crate::tls::PeerId;
+use crate::util::Hash;
+
+/// The maximum number of connections and of requests tracked until they finish.
+///
+/// Entries are only removed by the events finishing them, which are missed if e.g. a task is
+/// aborted.  Beyond this the oldest entries are dropped, so they can not pile up.
+const MAX_PENDING: usize = 64 * 1024;
+
+/// The number of events queued for the writer before [`EventSink::send`] waits.
+const QUEUE_SIZE: usize = 1024;
+
+/// An [`EventSink`] writing a record of every request to a file, one JSON object per line.
+///
+/// Each record has the time the request finished, the remote address and [`PeerId`] of the
+/// getter, the requested hash, the id of the token used, the outcome and the number of
+/// bytes sent.  Streams which failed before a request was read, e.g. because the
+/// authentication was not accepted, are recorded without a hash.
+///
+/// When the file grows beyond [`AccessLog::max_size`] it is rotated: `access.log` is
+/// renamed to `access.log.1`, `access.log.1` to `access.log.2` and so on, keeping at most
+/// [`AccessLog::max_files`] rotated files.
+///
+/// The file is written by a dedicated thread, which receives the events over a channel and
+/// exits once the log is dropped.
+#[derive(Debug, Clone)]
+pub struct AccessLog {
+    events: mpsc::Sender<Event>,
+    rotation: Arc<Rotation>,
+}
+
+/// When the log is rotated, changeable while the writer is running.
+#[derive(Debug)]
+struct Rotation {
+    max_size: AtomicU64,
+    max_files: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    rotation: Arc<Rotation>,
+    /// The open connections, with their remote address and getter.
+    connections: Pending<u64, (SocketAddr, Option<PeerId>)>,
+    /// The requests in progress, by connection and request id.
+    requests: Pending<(u64, u64), (Hash, Option<String>)>,
+}
+
+/// Entries tracked until they finish, bounded to [`MAX_PENDING`] entries.
+#[derive(Debug)]
+struct Pending<K, V> {
+    /// The entries, with the sequence number of their insertion.
+    entries: HashMap<K, (u64, V)>,
+    /// The keys in insertion order, with their sequence number.
+    ///
+    /// Keys of entries which were removed or inserted again since are skipped, and dropped
+    /// once they make up half of the queue.
+    order: VecDeque<(u64, K)>,
+    next_seq: u64,
+}
+
+impl<K: std::hash::Hash + Eq + Copy, V> Pending<K, V> {
+    fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            next_seq: 0,
+        }
+    }
+
+    /// Adds an entry, dropping the oldest one if there are too many.
+    fn insert(&mut self, key: K, value: V) {
+        if self.entries.len() >= MAX_PENDING {
+            while let Some((seq, oldest)) = self.order.pop_front() {
+                if self.is_current(seq, &oldest) {
+                    debug!(
+                        "access log tracks too many connections or requests, dropping the oldest"
+                    );
+                    self.entries.remove(&oldest);
+                    break;
+                }
+            }
+        }
+        if self.order.len() >= 2 * MAX_PENDING {
+            let entries = &self.entries;
+            self.order.retain(
+                |(seq, key)| matches!(entries.get(key), Some((current, _)) if current == seq),
+            );
+        }
+        self.entries.insert(key, (self.next_seq, value));
+        self.order.push_back((self.next_seq, key));
+        self.next_seq += 1;
+    }
+
+    /// Whether `seq` is the insertion of the entry for `key` which is still tracked.
+    fn is_current(&self, seq: u64, key: &K) -> bool {
+        matches!(self.entries.get(key), Some((current, _)) if *current == seq)
+    }
+
+    fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).map(|(_, value)| value)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        self.entries.remove(key).map(|(_, value)| value)
+    }
+}
+
+/// A line of the access log.
+#[derive(Debug, Serialize)]
+struct Record {
+    timestamp: String,
+    remote_addr: Option<SocketAddr>,
+    peer_id: Option<String>,
+    hash: Option<String>,
+    token_id: Option<String>,
+    outcome: &'static str,
+    bytes_sent: u64,
+}
+
+impl AccessLog {
+    /// Opens the access log at `path`, appending to it if it exists.
+    ///
+    /// By default the log is rotated at 100MiB, keeping 5 rotated files.
+    pub fn new(path: impl AsRef<Path>) -> Result<Self> {
+        let rotation = Arc::new(Rotation {
+            max_size: AtomicU64::new(100 * 1024 * 1024),
+            max_files: AtomicUsize::new(5),
+        });
+        let mut log = LogFile::open(path.as_ref(), rotation.clone())?;
+        let (events, mut queue) = mpsc::channel(QUEUE_SIZE);
+        std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || {
+                while let Some(event) = queue.blocking_recv() {
+                    if let Err(err) = log.record(event) {
+                        warn!("failed to write access log: {err:#}");
+                    }
+                }
+            })?;
+        Ok(Self { events, rotation })
+    }
+
+    /// Sets the size in bytes at which the log is rotated.
+    pub fn max_size(self, max_size: u64) -> Self {
+        self.rotation.max_size.store(max_size, Ordering::Relaxed);
+        self
+    }
+
+    /// Sets how many rotated files are kept, older ones are deleted.
+    pub fn max_files(self, max_files: usize) -> Self {
+        self.rotation.max_files.store(max_files, Ordering::Relaxed);
+        self
+    }
+}
+
+impl EventSink for AccessLog {
+    fn send(&self, event: Event) -> BoxFuture<'_, ()> {
+        match event {
+            Event::ClientConnected { .. }
+            | Event::ClientDisconnected { .. }
+            | Event::RequestReceived { .. }
+            | Event::TransferCompleted { .. }
+            | Event::TransferAborted { .. } => async move {
+                // The writer only stops once all senders are dropped.
+                self.events.send(event).await.ok();
+            }
+            .boxed(),
+            _ => future::ready(()).boxed(),
+        }
+    }
+}
+
+impl LogFile {
+    fn open(path: &Path, rotation: Arc<Rotation>) -> Result<Self> {
+        let path = path.to_path_buf();
+        let file = open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            rotation,
+            connections: Pending::new(),
+            requests: Pending::new(),
+        })
+    }
+
+    fn record(&mut self, event: Event) -> Result<()> {
+        let (connection_id, request_id, outcome, bytes_sent) = match event {
+            Event::ClientConnected {
+                connection_id,
+                remote_addr,
+                peer_id,
+            } => {
+                self.connections
+                    .insert(connection_id, (remote_addr, peer_id));
+                return Ok(());
+            }
+            Event::ClientDisconnected { connection_id } => {
+                self.connections.remove(&connection_id);
+                return Ok(());
+            }
+            Event::RequestReceived {
+                connection_id,
+                request_id,
+                hash,
+                token_id,
+            } => {
+                self.requests
+                    .insert((connection_id, request_id), (hash, token_id));
+                return Ok(());
+            }
+            Event::TransferCompleted {
+                connection_id,
+                request_id,
+                bytes_sent,
+                ..
+            } => (connection_id, Some(request_id), "found", bytes_sent),
+            Event::TransferAborted {
+                connection_id,
+                request_id,
+                reason,
+                bytes_sent,
+            } => {
+                let outcome = match reason {
+                    AbortReason::InvalidRequest => "invalid_request",
+                    AbortReason::Unauthorized => "unauthorized",
+                    AbortReason::NotFound => "not_found",
+                    AbortReason::CollectionIncomplete => "collection_incomplete",
+                    AbortReason::ContentRemoved => "content_removed",
+                    AbortReason::Modified => "modified",
+                    AbortReason::Failed => "failed",
+                };
+                (connection_id, request_id, outcome, bytes_sent)
+            }
+            _ => return Ok(()),
+        };
+        let (remote_addr, peer_id) = match self.connections.get(&connection_id) {
+            Some((remote_addr, peer_id)) => (Some(*remote_addr), *peer_id),
+            None => (None, None),
+        };
+        let (hash, token_id) = match request_id
+            .and_then(|request_id| self.requests.remove(&(connection_id, request_id)))
+        {
+            Some((hash, token_id)) => (Some(hash), token_id),
+            None => (None, None),
+        };
+        let record = Record {
+            timestamp: OffsetDateTime::now_utc().format(&Rfc3339)?,
+            remote_addr,
+            peer_id: peer_id.map(|peer_id| peer_id.to_string()),
+            hash: hash.map(|hash| hash.to_string()),
+            token_id,
+            outcome,
+            bytes_sent,
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        self.write(&line)
+    }
+
+    fn write(&mut self, line: &[u8]) -> Result<()> {
+        let max_size = self.rotation.max_size.load(Ordering::Relaxed);
+        if self.size > 0 && self.size + line.len() as u64 > max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Moves the log to the first rotated file and starts a new one.
+    ///
+    /// The log is synced first, so a rotated file is complete on disk.
+    fn rotate(&mut self) -> Result<()> {
+        self.file.sync_data()?;
+        let rotated = |i: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{i}"));
+            PathBuf::from(name)
+        };
+        let max_files = self.rotation.max_files.load(Ordering::Relaxed);
+        if max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let oldest = rotated(max_files);
+            if oldest.exists() {
+                fs::remove_file(oldest)?;
+            }
+            for i in (1..max_files).rev() {
+                let from = rotated(i);
+                if from.exists() {
+                    fs::rename(&from, rotated(i + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open access log {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use testdir::testdir;
+
+    use super::*;
+    use crate::tls::Keypair;
+
+    #[test]
+    fn test_access_log_rotation() {
+        let dir = testdir!();
+        let path = dir.join("access.log");
+        let rotation = Arc::new(Rotation {
+            max_size: AtomicU64::new(400),
+            max_files: AtomicUsize::new(2),
+        });
+        let mut log = LogFile::open(&path, rotation).unwrap();
+        let peer_id: PeerId = Keypair::generate().public().into();
+        let hash = Hash::new(b"hello");
+
+        log.record(Event::ClientConnected {
+            connection_id: 1,
+            remote_addr: "127.0.0.1:1234".parse().unwrap(),
+            peer_id: Some(peer_id),
+        })
+        .unwrap();
+        for request_id in 0..4 {
+            log.record(Event::RequestReceived {
+                connection_id: 1,
+                request_id,
+                hash,
+                token_id: Some("token".to_string()),
+            })
+            .unwrap();
+            log.record(Event::TransferCompleted {
+                connection_id: 1,
+                request_id,
+                bytes_sent: 100,
+                duration: Duration::from_millis(1),
+            })
+            .unwrap();
+        }
+
+        let line = fs::read_to_string(&path).unwrap();
+        let record: serde_json::Value = serde_json::from_str(line.trim_end()).unwrap();
+        assert_eq!(record["remote_addr"], "127.0.0.1:1234");
+        assert_eq!(record["peer_id"], peer_id.to_string());
+        assert_eq!(record["hash"], hash.to_string());
+        assert_eq!(record["token_id"], "token");
+        assert_eq!(record["outcome"], "found");
+        assert_eq!(record["bytes_sent"], 100);
+
+        // Two records exceed the maximum size, so every record is written to a new file and
+        // only the last two rotated files are kept.
+        let lines = |path: PathBuf| fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(lines(path.clone()), 1);
+        assert_eq!(lines(dir.join("access.log.1")), 1);
+        assert_eq!(lines(dir.join("access.log.2")), 1);
+        assert!(!dir.join("access.log.3").exists());
+    }
+
+    #[tokio::test]
+    async fn test_access_log_writer() {
+        let dir = testdir!();
+        let path = dir.join("access.log");
+        let log = AccessLog::new(&path).unwrap();
+        log.send(Event::ClientConnected {
+            connection_id: 1,
+            remote_addr: "127.0.0.1:1234".parse().unwrap(),
+            peer_id: None,
+        })
+        .await;
+        log.send(Event::TransferAborted {
+            connection_id: 1,
+            request_id: None,
+            reason: AbortReason::Unauthorized,
+            bytes_sent: 0,
+        })
+        .await;
+        drop(log);
+
+        // The record is written by the writer thread.
+        let mut line = String::new();
+        for _ in 0..100 {
+            line = fs::read_to_string(&path).unwrap();
+            if !line.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let record: serde_json::Value = serde_json::from_str(line.trim_end()).unwrap();
+        assert_eq!(record["remote_addr"], "127.0.0.1:1234");
+        assert_eq!(record["outcome"], "unauthorized");
+    }
+
+    #[test]
+    fn test_pending_bounded() {
+        let mut pending = Pending::new();
+        for i in 0..=MAX_PENDING as u64 {
+            pending.insert(i, ());
+        }
+        assert_eq!(pending.entries.len(), MAX_PENDING);
+        assert!(pending.get(&0).is_none());
+        assert!(pending.get(&1).is_some());
+        assert_eq!(pending.remove(&(MAX_PENDING as u64)), Some(()));
+
+        // The keys of finished entries do not pile up either.
+        for i in 0..4 * MAX_PENDING as u64 {
+            pending.insert(u64::MAX - i, ());
+            pending.remove(&(u64::MAX - i));
+        }
+        assert!(pending.order.len() <= 2 * MAX_PENDING);
+        assert_eq!(pending.entries.len(), MAX_PENDING - 1);
+    }
+}
@@ -0,0 +1,76 @@
+//! Delivering the [`Event`]s of a provider.
+use std::fmt;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use tokio::sync::{broadcast, mpsc};
+
+use super::metrics::Metrics;
+use super::Event;
+
+/// A consumer of [`Event`]s which must not miss any of them.
+///
+/// Unlike subscribers from [`Provider::subscribe`](super::Provider::subscribe), which miss
+/// events when lagging behind, a sink receives every event.  The provider waits for
+/// [`EventSink::send`] to complete, so a slow sink slows down the connections and
+/// transfers emitting events.
+pub trait EventSink: Send + Sync + 'static {
+    /// Delivers an event.
+    fn send(&self, event: Event) -> BoxFuture<'_, ()>;
+}
+
+impl fmt::Debug for dyn EventSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EventSink")
+    }
+}
+
+/// Delivers events to a bounded channel, waiting for capacity when it is full.
+///
+/// Events are dropped once the receiver is closed.
+impl EventSink for mpsc::Sender<Event> {
+    fn send(&self, event: Event) -> BoxFuture<'_, ()> {
+        async move {
+            mpsc::Sender::send(self, event).await.ok();
+        }
+        .boxed()
+    }
+}
+
+/// Sends events to the subscribers and sinks of a provider.
+#[derive(Debug, Clone)]
+pub(super) struct Events {
+    subscribers: broadcast::Sender<Event>,
+    sinks: Arc<[Arc<dyn EventSink>]>,
+    metrics: Arc<Metrics>,
+}
+
+impl Events {
+    pub(super) fn new(sinks: Vec<Arc<dyn EventSink>>) -> Self {
+        let (subscribers, _) = broadcast::channel(8);
+        Self {
+            subscribers,
+            sinks: sinks.into(),
+            metrics: Default::default(),
+        }
+    }
+
+    /// Records `event` in the metrics and sends it to all subscribers, waiting until all
+    /// sinks received it.
+    pub(super) async fn send(&self, event: Event) {
+        self.metrics.record(&event);
+        for sink in self.sinks.iter() {
+            sink.send(event.clone()).await;
+        }
+        self.subscribers.send(event).ok();
+    }
+
+    pub(super) fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    pub(super) fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.subscribers.subscribe()
+    }
+}
@@ -0,0 +1,272 @@
+//! Metrics of a provider, served in the Prometheus text format.
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, warn};
+
+use super::{AbortReason, Event};
+
+/// The upper bounds of the transfer duration histogram buckets, in seconds.
+const DURATION_BUCKETS: [f64; 9] = [0.01, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+/// The maximum size of the head of a request to the metrics listener.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// How long a client of the metrics listener may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Metrics collected from the [`Event`]s of a provider.
+#[derive(Debug, Default)]
+pub(super) struct Metrics(Mutex<Counters>);
+
+#[derive(Debug, Default)]
+struct Counters {
+    connections: u64,
+    active_connections: u64,
+    active_streams: u64,
+    requests_found: u64,
+    requests_not_found: u64,
+    requests_incomplete: u64,
+    requests_aborted: u64,
+    auth_failures: u64,
+    bytes_sent: u64,
+    transfer_duration: Histogram,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// The number of observations in each of [`DURATION_BUCKETS`], not cumulative.
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(i) = DURATION_BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+impl Metrics {
+    /// Updates the metrics with an event.
+    pub(super) fn record(&self, event: &Event) {
+        let mut counters = self.0.lock().unwrap();
+        match event {
+            Event::ClientConnected { .. } => {
+                counters.connections += 1;
+                counters.active_connections += 1;
+            }
+            Event::ClientDisconnected { .. } => {
+                counters.active_connections = counters.active_connections.saturating_sub(1);
+            }
+            Event::RequestReceived { .. } => counters.active_streams += 1,
+            Event::TransferCompleted {
+                bytes_sent,
+                duration,
+                ..
+            } => {
+                counters.active_streams = counters.active_streams.saturating_sub(1);
+                counters.requests_found += 1;
+                counters.bytes_sent += bytes_sent;
+                counters.transfer_duration.observe(*duration);
+            }
+            Event::TransferAborted {
+                request_id,
+                reason,
+                bytes_sent,
+                ..
+            } => {
+                if *reason == AbortReason::Unauthorized {
+                    counters.auth_failures += 1;
+                }
+                counters.bytes_sent += bytes_sent;
+                // Streams failing before the request was read never were a request.
+                if request_id.is_some() {
+                    counters.active_streams = counters.active_streams.saturating_sub(1);
+                    match reason {
+                        // The getter is told the content is not found.
+                        AbortReason::NotFound | AbortReason::Unauthorized => {
+                            counters.requests_not_found += 1
+                        }
+                        AbortReason::CollectionIncomplete => counters.requests_incomplete += 1,
+                        _ => counters.requests_aborted += 1,
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Renders the metrics in the Prometheus text format.
+    pub(super) fn render(&self) -> String {
+        let counters = self.0.lock().unwrap();
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, values: &[(&str, u64)]| {
+            writeln!(out, "# HELP {name} {help}").unwrap();
+            writeln!(out, "# TYPE {name} {kind}").unwrap();
+            for (labels, value) in values {
+                writeln!(out, "{name}{labels} {value}").unwrap();
+            }
+        };
+        metric(
+            "sendme_provider_connections_total",
+            "counter",
+            "Connections accepted.",
+            &[("", counters.connections)],
+        );
+        metric(
+            "sendme_provider_active_connections",
+            "gauge",
+            "Connections currently open.",
+            &[("", counters.active_connections)],
+        );
+        metric(
+            "sendme_provider_active_streams",
+            "gauge",
+            "Streams currently serving a request.",
+            &[("", counters.active_streams)],
+        );
+        metric(
+            "sendme_provider_requests_total",
+            "counter",
+            "Requests served, by outcome.",
+            &[
+                ("{outcome=\"found\"}", counters.requests_found),
+                ("{outcome=\"not_found\"}", counters.requests_not_found),
+                ("{outcome=\"incomplete\"}", counters.requests_incomplete),
+                ("{outcome=\"aborted\"}", counters.requests_aborted),
+            ],
+        );
+        metric(
+            "sendme_provider_auth_failures_total",
+            "counter",
+            "Requests whose authentication was not accepted.",
+            &[("", counters.auth_failures)],
+        );
+        metric(
+            "sendme_provider_bytes_sent_total",
+            "counter",
+            "Bytes of bao encoded data sent.",
+            &[("", counters.bytes_sent)],
+        );
+
+        let name = "sendme_provider_transfer_duration_seconds";
+        let histogram = &counters.transfer_duration;
+        writeln!(out, "# HELP {name} Duration of completed transfers.").unwrap();
+        writeln!(out, "# TYPE {name} histogram").unwrap();
+        let mut cumulative = 0;
+        for (bound, count) in DURATION_BUCKETS.iter().zip(histogram.buckets) {
+            cumulative += count;
+            writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}").unwrap();
+        }
+        writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", histogram.count).unwrap();
+        writeln!(out, "{name}_sum {}", histogram.sum).unwrap();
+        writeln!(out, "{name}_count {}", histogram.count).unwrap();
+        out
+    }
+}
+
+/// Serves the metrics over HTTP at `/metrics`, until the returned future is dropped.
+pub(super) async fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, remote_addr)) => {
+                let metrics = metrics.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = handle_request(stream, &metrics).await {
+                        debug!(%remote_addr, "metrics request failed: {err:#}");
+                    }
+                });
+            }
+            Err(err) => warn!("error accepting metrics connection: {err:#}"),
+        }
+    }
+}
+
+async fn handle_request(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    // Clients which do not send a complete request in time are dropped, so they can not
+    // keep connections open.
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "request timed out"))??;
+    let request = match request {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+    let response = if request.starts_with(b"GET /metrics ") {
+        let body = metrics.render();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Reads the head of a request, returns `None` if it is incomplete or too large.
+async fn read_request(stream: &mut TcpStream) -> std::io::Result<Option<Vec<u8>>> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 || request.len() + read > MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    Ok(Some(request))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::Hash;
+
+    #[test]
+    fn test_metrics_render() {
+        let metrics = Metrics::default();
+        metrics.record(&Event::RequestReceived {
+            connection_id: 0,
+            request_id: 1,
+            hash: Hash::new(b"hello"),
+            token_id: None,
+        });
+        metrics.record(&Event::TransferCompleted {
+            connection_id: 0,
+            request_id: 1,
+            bytes_sent: 100,
+            duration: Duration::from_millis(200),
+        });
+        metrics.record(&Event::TransferAborted {
+            connection_id: 0,
+            request_id: None,
+            reason: AbortReason::Unauthorized,
+            bytes_sent: 0,
+        });
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("sendme_provider_active_streams 0\n"));
+        assert!(rendered.contains("sendme_provider_requests_total{outcome=\"found\"} 1\n"));
+        assert!(rendered.contains("sendme_provider_requests_total{outcome=\"not_found\"} 0\n"));
+        assert!(rendered.contains("sendme_provider_auth_failures_total 1\n"));
+        assert!(rendered.contains("sendme_provider_bytes_sent_total 100\n"));
+        assert!(
+            rendered.contains("sendme_provider_transfer_duration_seconds_bucket{le=\"0.1\"} 0\n")
+        );
+        assert!(
+            rendered.contains("sendme_provider_transfer_duration_seconds_bucket{le=\"0.5\"} 1\n")
+        );
+        assert!(rendered.contains("sendme_provider_transfer_duration_seconds_count 1\n"));
+    }
+}
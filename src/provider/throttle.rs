@@ -0,0 +1,158 @@
+//! Limiting the upload bandwidth of a provider.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::tls::PeerId;
+
+/// How long a limiter can send at full rate after being idle.
+const BURST: Duration = Duration::from_millis(100);
+
+/// A token bucket limiting the number of bytes sent per second.
+#[derive(Debug, Clone)]
+struct RateLimiter(Arc<Mutex<Bucket>>);
+
+#[derive(Debug)]
+struct Bucket {
+    /// The rate in bytes per second.
+    rate: u64,
+    /// The number of bytes which can be sent right away, negative if sending is ahead.
+    available: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: u64) -> Self {
+        let rate = rate.max(1);
+        Self(Arc::new(Mutex::new(Bucket {
+            rate,
+            available: rate as f64 * BURST.as_secs_f64(),
+            updated: Instant::now(),
+        })))
+    }
+
+    /// Takes `n` bytes from the bucket, returns how long to wait before sending them.
+    fn take(&self, n: usize) -> Duration {
+        let mut bucket = self.0.lock().unwrap();
+        let now = Instant::now();
+        let rate = bucket.rate as f64;
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.available = (bucket.available + elapsed * rate).min(rate * BURST.as_secs_f64());
+        bucket.updated = now;
+        bucket.available -= n as f64;
+        if bucket.available >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.available / rate)
+        }
+    }
+}
+
+/// The rate limits applied to the streams of a single connection.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Throttle(Vec<RateLimiter>);
+
+impl Throttle {
+    /// Waits until `n` bytes can be sent without exceeding any limit.
+    pub(super) async fn acquire(&self, n: usize) {
+        let wait = self
+            .0
+            .iter()
+            .map(|limiter| limiter.take(n))
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// The upload bandwidth limits of a provider, in bytes per second.
+#[derive(Debug, Clone, Default)]
+pub(super) struct RateLimits {
+    /// Shared by all connections.
+    total: Option<RateLimiter>,
+    per_peer: Option<u64>,
+    /// The limiters of the peers with open connections.
+    peers: Arc<Mutex<HashMap<PeerId, Weak<Mutex<Bucket>>>>>,
+}
+
+impl RateLimits {
+    pub(super) fn new(total: Option<u64>, per_peer: Option<u64>) -> Self {
+        Self {
+            total: total.map(RateLimiter::new),
+            per_peer,
+            peers: Default::default(),
+        }
+    }
+
+    /// Returns the throttle for a new connection of `peer`.
+    ///
+    /// All connections of a peer share its limit while any of them is open.  Connections
+    /// without a known peer are limited individually.
+    pub(super) fn throttle(&self, peer: Option<PeerId>) -> Throttle {
+        let mut limiters: Vec<_> = self.total.iter().cloned().collect();
+        if let Some(rate) = self.per_peer {
+            let limiter = match peer {
+                Some(peer) => {
+                    let mut peers = self.peers.lock().unwrap();
+                    peers.retain(|_, bucket| bucket.strong_count() > 0);
+                    match peers.get(&peer).and_then(Weak::upgrade) {
+                        Some(bucket) => RateLimiter(bucket),
+                        None => {
+                            let limiter = RateLimiter::new(rate);
+                            peers.insert(peer, Arc::downgrade(&limiter.0));
+                            limiter
+                        }
+                    }
+                }
+                None => RateLimiter::new(rate),
+            };
+            limiters.push(limiter);
+        }
+        Throttle(limiters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls::Keypair;
+
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(1000);
+        // A burst is sent right away, anything more has to wait.
+        assert_eq!(limiter.take(100), Duration::ZERO);
+        let wait = limiter.take(100);
+        assert!(wait > Duration::from_millis(90), "{wait:?}");
+        assert!(wait <= Duration::from_millis(100), "{wait:?}");
+    }
+
+    #[test]
+    fn test_rate_limits_per_peer() {
+        let limits = RateLimits::new(None, Some(1000));
+        let peer: PeerId = Keypair::generate().public().into();
+        let other: PeerId = Keypair::generate().public().into();
+
+        // Connections of the same peer share a limit.
+        let first = limits.throttle(Some(peer));
+        let second = limits.throttle(Some(peer));
+        assert_eq!(first.0[0].take(100), Duration::ZERO);
+        assert!(second.0[0].take(100) > Duration::ZERO);
+        assert_eq!(limits.throttle(Some(other)).0[0].take(100), Duration::ZERO);
+        assert_eq!(limits.throttle(None).0[0].take(100), Duration::ZERO);
+
+        // The limit of a peer is dropped with its last connection.
+        drop((first, second));
+        assert_eq!(limits.throttle(Some(peer)).0[0].take(100), Duration::ZERO);
+
+        // The total limit is shared by all peers.
+        let limits = RateLimits::new(Some(1000), None);
+        assert_eq!(limits.throttle(Some(peer)).0[0].take(100), Duration::ZERO);
+        assert!(limits.throttle(Some(other)).0[0].take(100) > Duration::ZERO);
+    }
+}
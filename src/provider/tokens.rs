@@ -0,0 +1,268 @@
+//! Registry of the auth tokens accepted by a provider.
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::protocol::AuthToken;
+use crate::util::Hash;
+
+/// The content an [`AuthToken`] gives access to, and for how long.
+///
+/// By default a scope gives unrestricted access to everything served by the provider.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TokenScope {
+    hashes: Option<HashSet<Hash>>,
+    expires: Option<SystemTime>,
+    max_downloads: Option<u64>,
+}
+
+impl TokenScope {
+    /// A scope giving unrestricted access to all content of the provider.
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    /// A scope giving access only to the given hashes.
+    ///
+    /// Access to a collection includes its blobs as part of the collection, but they can
+    /// not be requested individually unless they are in scope as well.
+    pub fn hashes(hashes: impl IntoIterator<Item = Hash>) -> Self {
+        Self {
+            hashes: Some(hashes.into_iter().collect()),
+            ..Default::default()
+        }
+    }
+
+    /// Expires the token at the given time.
+    pub fn expires_at(mut self, time: SystemTime) -> Self {
+        self.expires = Some(time);
+        self
+    }
+
+    /// Expires the token once the given duration elapsed from now.
+    pub fn expires_in(self, duration: Duration) -> Self {
+        self.expires_at(SystemTime::now() + duration)
+    }
+
+    /// Limits the number of requests which can be made using the token.
+    pub fn max_downloads(mut self, max_downloads: u64) -> Self {
+        self.max_downloads = Some(max_downloads);
+        self
+    }
+
+    /// Whether the token can no longer be used at `now`.
+    fn is_expired(&self, now: SystemTime) -> bool {
+        matches!(self.expires, Some(expires) if expires <= now)
+    }
+
+    /// Whether the hash may be requested.
+    fn contains(&self, hash: &Hash) -> bool {
+        match self.hashes {
+            Some(ref hashes) => hashes.contains(hash),
+            None => true,
+        }
+    }
+}
+
+/// Why an [`AuthToken`] was not accepted.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TokenError {
+    /// The token was never registered or was revoked.
+    #[error("unknown or revoked token")]
+    Unknown,
+    /// The token expired.
+    #[error("token expired")]
+    Expired,
+    /// All downloads allowed for the token were used.
+    #[error("download limit of token reached")]
+    Exhausted,
+    /// The requested hash is not in the scope of the token.
+    #[error("hash not in scope of token")]
+    OutOfScope,
+}
+
+/// The tokens accepted by a provider, with their scopes.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Tokens(Arc<Mutex<Registry>>);
+
+#[derive(Debug, Default)]
+struct Registry {
+    tokens: HashMap<AuthToken, TokenEntry>,
+    /// The tokens of the tickets for a single hash, shared by all tickets for the hash.
+    tickets: HashMap<Hash, AuthToken>,
+}
+
+#[derive(Debug)]
+struct TokenEntry {
+    scope: TokenScope,
+    /// The number of downloads made using the token.
+    downloads: u64,
+}
+
+impl TokenEntry {
+    /// Whether the token can never be used again.
+    fn is_spent(&self, now: SystemTime) -> bool {
+        self.scope.is_expired(now)
+            || matches!(self.scope.max_downloads, Some(max) if self.downloads >= max)
+    }
+}
+
+impl Tokens {
+    /// Adds a token, replacing the scope if it is already present.
+    ///
+    /// Tokens which expired or used up their downloads are removed, so they do not pile up.
+    pub(super) fn insert(&self, token: AuthToken, scope: TokenScope) {
+        self.0.lock().unwrap().insert(token, scope);
+    }
+
+    /// Adds a newly generated token with the given scope.
+    pub(super) fn mint(&self, scope: TokenScope) -> AuthToken {
+        let token = AuthToken::generate();
+        self.insert(token, scope);
+        token
+    }
+
+    /// Returns the token giving access only to `hash`, minting it on first use.
+    ///
+    /// The token is shared by all tickets for the hash, so handing out tickets does not
+    /// grow the registry.  A new token is minted if the previous one was revoked.
+    pub(super) fn ticket(&self, hash: Hash) -> AuthToken {
+        let mut registry = self.0.lock().unwrap();
+        if let Some(token) = registry.ticket_token(&hash) {
+            return token;
+        }
+        let token = AuthToken::generate();
+        registry.insert(token, TokenScope::hashes([hash]));
+        registry.tickets.insert(hash, token);
+        token
+    }
+
+    /// Removes a token, returns whether it was present.
+    pub(super) fn revoke(&self, token: &AuthToken) -> bool {
+        let mut registry = self.0.lock().unwrap();
+        registry.tickets.retain(|_, ticket| ticket != token);
+        registry.tokens.remove(token).is_some()
+    }
+
+    /// Checks whether the token can currently be used.
+    pub(super) fn check(&self, token: &AuthToken) -> Result<(), TokenError> {
+        let mut registry = self.0.lock().unwrap();
+        Self::check_entry(&mut registry.tokens, token).map(|_| ())
+    }
+
+    /// Checks whether the token gives access to `hash`.
+    ///
+    /// This does not count as a download, see [`Tokens::count_download`].
+    pub(super) fn authorize(&self, token: &AuthToken, hash: &Hash) -> Result<(), TokenError> {
+        let mut registry = self.0.lock().unwrap();
+        let entry = Self::check_entry(&mut registry.tokens, token)?;
+        if !entry.scope.contains(hash) {
+            return Err(TokenError::OutOfScope);
+        }
+        Ok(())
+    }
+
+    /// Counts a download made using the token, once the content is being sent.
+    ///
+    /// Fails if the token can no longer be used, e.g. because a concurrent request used
+    /// the last allowed download.
+    pub(super) fn count_download(&self, token: &AuthToken) -> Result<(), TokenError> {
+        let mut registry = self.0.lock().unwrap();
+        let entry = Self::check_entry(&mut registry.tokens, token)?;
+        entry.downloads += 1;
+        Ok(())
+    }
+
+    fn check_entry<'a>(
+        tokens: &'a mut HashMap<AuthToken, TokenEntry>,
+        token: &AuthToken,
+    ) -> Result<&'a mut TokenEntry, TokenError> {
+        let expired = match tokens.get(token) {
+            Some(entry) => entry.scope.is_expired(SystemTime::now()),
+            None => return Err(TokenError::Unknown),
+        };
+        if expired {
+            // Expired tokens never become valid again.
+            tokens.remove(token);
+            return Err(TokenError::Expired);
+        }
+        let entry = tokens.get_mut(token).expect("checked above");
+        if matches!(entry.scope.max_downloads, Some(max) if entry.downloads >= max) {
+            return Err(TokenError::Exhausted);
+        }
+        Ok(entry)
+    }
+}
+
+impl Registry {
+    fn insert(&mut self, token: AuthToken, scope: TokenScope) {
+        let now = SystemTime::now();
+        self.tokens.retain(|_, entry| !entry.is_spent(now));
+        let tokens = &self.tokens;
+        self.tickets.retain(|_, ticket| tokens.contains_key(ticket));
+        self.tokens.insert(
+            token,
+            TokenEntry {
+                scope,
+                downloads: 0,
+            },
+        );
+    }
+
+    /// The shared ticket token for `hash`, if it is still registered.
+    fn ticket_token(&self, hash: &Hash) -> Option<AuthToken> {
+        let token = self.tickets.get(hash)?;
+        self.tokens.contains_key(token).then_some(*token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_scopes() {
+        let tokens = Tokens::default();
+        let foo = Hash::new(b"foo");
+        let bar = Hash::new(b"bar");
+
+        let all = tokens.mint(TokenScope::all());
+        assert_eq!(tokens.authorize(&all, &foo), Ok(()));
+        assert_eq!(tokens.authorize(&all, &bar), Ok(()));
+
+        let scoped = tokens.mint(TokenScope::hashes([foo]).max_downloads(2));
+        assert_eq!(tokens.check(&scoped), Ok(()));
+        assert_eq!(tokens.authorize(&scoped, &foo), Ok(()));
+        assert_eq!(tokens.authorize(&scoped, &bar), Err(TokenError::OutOfScope));
+        assert_eq!(tokens.count_download(&scoped), Ok(()));
+        assert_eq!(tokens.authorize(&scoped, &foo), Ok(()));
+        assert_eq!(tokens.count_download(&scoped), Ok(()));
+        assert_eq!(tokens.authorize(&scoped, &foo), Err(TokenError::Exhausted));
+        assert_eq!(tokens.count_download(&scoped), Err(TokenError::Exhausted));
+        assert_eq!(tokens.check(&scoped), Err(TokenError::Exhausted));
+
+        // Spent tokens are pruned when adding another one.
+        let expired = tokens.mint(TokenScope::all().expires_at(SystemTime::now()));
+        assert_eq!(tokens.check(&scoped), Err(TokenError::Unknown));
+        assert_eq!(tokens.check(&expired), Err(TokenError::Expired));
+        assert_eq!(tokens.check(&expired), Err(TokenError::Unknown));
+        let expiring = tokens.mint(TokenScope::all().expires_in(Duration::from_secs(60)));
+        assert_eq!(tokens.authorize(&expiring, &foo), Ok(()));
+
+        // Tickets for the same hash share a token, until it is revoked.
+        let ticket = tokens.ticket(foo);
+        assert_eq!(tokens.ticket(foo), ticket);
+        assert_ne!(tokens.ticket(bar), ticket);
+        assert_eq!(tokens.authorize(&ticket, &bar), Err(TokenError::OutOfScope));
+        assert!(tokens.revoke(&ticket));
+        assert_ne!(tokens.ticket(foo), ticket);
+
+        assert!(tokens.revoke(&all));
+        assert!(!tokens.revoke(&all));
+        assert_eq!(tokens.check(&all), Err(TokenError::Unknown));
+        assert_eq!(
+            tokens.check(&AuthToken::generate()),
+            Err(TokenError::Unknown)
+        );
+    }
+}
@@ -0,0 +1,249 @@
+//! Watching a directory, re-publishing its collection when files change.
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use futures::future;
+use notify::{EventKind, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use super::{
+    build_collection, compute_entry, data_sources_from_dir, read_metadata, remove_content,
+    DataSource, Database, Event, Events, Provider, SourceEntry, Ticket, TokenScope, Tokens,
+    Transfers,
+};
+use crate::blobs::EntryKind;
+use crate::capability::Capability;
+use crate::protocol::AuthToken;
+use crate::tls::{Keypair, PeerId};
+use crate::util::Hash;
+
+/// How long the directory must be unchanged before its collection is rebuilt.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Serves `dir` as a collection of `provider`, see [`Provider::watch`].
+///
+/// With an `expiry` the tickets of the collections use capabilities expiring after it,
+/// otherwise they share a token.
+pub(super) async fn watch(
+    provider: &Provider,
+    dir: PathBuf,
+    expiry: Option<Duration>,
+) -> Result<Hash> {
+    // Only a single pending change is kept, changes are coalesced while rebuilding.
+    let (changes_tx, mut changes_rx) = mpsc::channel(1);
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        match res {
+            // Reading files, e.g. to hash or send them, does not change them.
+            Ok(event) if matches!(event.kind, EventKind::Access(_)) => {}
+            Ok(_) => {
+                changes_tx.try_send(()).ok();
+            }
+            Err(err) => warn!("error watching directory: {err}"),
+        }
+    })?;
+    watcher
+        .watch(&dir, RecursiveMode::Recursive)
+        .with_context(|| format!("failed to watch {}", dir.display()))?;
+
+    let mut watched = WatchedDir {
+        dir,
+        files: HashMap::new(),
+        published: None,
+        db: provider.db.clone(),
+        transfers: provider.transfers.clone(),
+        events: provider.events.clone(),
+        peer: provider.peer_id(),
+        addr: provider.listen_addr,
+        tokens: provider.tokens.clone(),
+        ticket_auth: match expiry {
+            Some(expiry) => TicketAuth::Capability {
+                keypair: provider.keypair.clone(),
+                expiry,
+            },
+            None => TicketAuth::Token(AuthToken::generate()),
+        },
+    };
+    let hash = watched
+        .update()
+        .await?
+        .expect("initial collection is always published");
+
+    let cancel_token = provider.cancel_token.clone();
+    tokio::spawn(async move {
+        // Watching stops when the watcher is dropped.
+        let _watcher = watcher;
+        loop {
+            tokio::select! {
+                biased;
+                _ = cancel_token.cancelled() => break,
+                changed = changes_rx.recv() => {
+                    if changed.is_none() {
+                        break;
+                    }
+                }
+            }
+            // Wait until the directory is quiet, e.g. after a build finished writing.
+            while let Ok(Some(())) = tokio::time::timeout(DEBOUNCE, changes_rx.recv()).await {}
+            debug!("{} changed, updating collection", watched.dir.display());
+            if let Err(err) = watched.update().await {
+                warn!(
+                    "failed to update collection of {}: {err:#}",
+                    watched.dir.display()
+                );
+            }
+        }
+    });
+
+    Ok(hash)
+}
+
+/// A directory whose collection is published in a [`Database`].
+struct WatchedDir {
+    dir: PathBuf,
+    /// The entries of the files of the published collection, reused while unchanged.
+    files: HashMap<PathBuf, SourceEntry>,
+    /// The published collection and the hashes of it which were added to the database by
+    /// this watch, rather than e.g. using [`Provider::add`].
+    published: Option<(Hash, HashSet<Hash>)>,
+    /// The database of the provider.
+    db: Database,
+    /// The transfers of the provider, aborted when their content is removed.
+    transfers: Transfers,
+    /// The events of the provider.
+    events: Events,
+    /// The peer, address and tokens of the provider, to create tickets for new collections.
+    peer: PeerId,
+    addr: SocketAddr,
+    tokens: Tokens,
+    ticket_auth: TicketAuth,
+}
+
+/// How the tickets of the published collections authenticate.
+#[derive(Debug)]
+enum TicketAuth {
+    /// A single token, its scope moves to each newly published collection.
+    Token(AuthToken),
+    /// A capability for the collection, signed when it is published.
+    Capability {
+        keypair: Arc<Keypair>,
+        expiry: Duration,
+    },
+}
+
+impl WatchedDir {
+    /// Rehashes the changed files and publishes the new collection.
+    ///
+    /// Returns the hash of the new collection, or `None` if the collection did not change.
+    async fn update(&mut self) -> Result<Option<Hash>> {
+        let sources = data_sources_from_dir(&self.dir).await?;
+        let entries = sources.into_iter().map(|source| {
+            let cached = match source {
+                DataSource::NamedFile { ref path, .. } => self.files.get(path).cloned(),
+                _ => None,
+            };
+            tokio::task::spawn_blocking(move || update_entry(source, cached))
+        });
+        let entries = future::join_all(entries)
+            .await
+            .into_iter()
+            .collect::<Result<Result<Vec<_>, _>, _>>()??;
+        self.files = entries
+            .iter()
+            .filter_map(|entry| {
+                let data = entry.data.as_ref()?;
+                Some((data.path.clone(), entry.clone()))
+            })
+            .collect();
+
+        let (db, hash) = build_collection(entries)?;
+        if matches!(self.published, Some((published, _)) if published == hash) {
+            debug!("collection of {} is unchanged", self.dir.display());
+            return Ok(None);
+        }
+        self.publish(db, hash).await;
+        Ok(Some(hash))
+    }
+
+    /// Adds the collection to the database, replacing the previously published one.
+    async fn publish(&mut self, db: Database, hash: Hash) {
+        let previous = self.published.take();
+        let owned = |hash: &Hash| matches!(previous, Some((_, ref owned)) if owned.contains(hash));
+        let added: HashSet<Hash> = db
+            .entries()
+            .into_iter()
+            .map(|(hash, _)| hash)
+            .filter(|hash| owned(hash) || self.db.get(hash).is_none())
+            .collect();
+        self.db.merge(&db);
+        if let Some((previous_hash, previous)) = previous {
+            // Transfers of the previous collection continue, unless the content they send is
+            // removed: blobs which were added by this watch and are no longer used by any
+            // collection, e.g. the new one for unchanged files.
+            if previous.contains(&previous_hash) {
+                self.db.remove(&previous_hash);
+            }
+            let referenced = self.db.referenced_blobs();
+            for hash in previous.difference(&added) {
+                if *hash != previous_hash && !referenced.contains(hash) {
+                    remove_content(&self.db, &self.transfers, hash);
+                }
+            }
+        }
+        self.published = Some((hash, added));
+
+        let auth = match self.ticket_auth {
+            TicketAuth::Token(token) => {
+                // Tickets of previous collections stop working, like their content is no
+                // longer served.
+                self.tokens.insert(token, TokenScope::hashes([hash]));
+                token.into()
+            }
+            TicketAuth::Capability {
+                ref keypair,
+                expiry,
+            } => Capability::new([hash])
+                .expires_in(expiry)
+                .sign(keypair)
+                .into(),
+        };
+        let ticket = Ticket {
+            hash,
+            peer: self.peer,
+            addr: self.addr,
+            auth,
+        };
+        debug!("published collection {} of {}", hash, self.dir.display());
+        self.events
+            .send(Event::CollectionPublished { hash, ticket })
+            .await;
+    }
+}
+
+/// Synchronously computes the entry for `source`, reusing `cached` if its file is unchanged.
+fn update_entry(source: DataSource, cached: Option<SourceEntry>) -> Result<SourceEntry> {
+    if let DataSource::NamedFile { ref path, ref name } = source {
+        if let Some(SourceEntry {
+            hash,
+            data: Some(data),
+            ..
+        }) = cached
+        {
+            let meta = std::fs::metadata(path)
+                .with_context(|| format!("reading metadata of {}", path.display()))?;
+            if data.is_unmodified(&meta) {
+                return Ok(SourceEntry {
+                    name: name.clone(),
+                    hash,
+                    metadata: read_metadata(path, EntryKind::File)?,
+                    data: Some(data),
+                });
+            }
+        }
+    }
+    compute_entry(source)
+}
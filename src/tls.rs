@@ -7,7 +7,9 @@ pub mod certificate;
 mod verifier;
 
 use std::{
+    collections::HashSet,
     fmt::{Debug, Display},
+    hash::{Hash, Hasher},
     ops::Deref,
     str::FromStr,
     sync::Arc,
@@ -69,7 +71,7 @@ impl Keypair {
         }
     }
 
-    fn sign(&self, msg: &[u8]) -> Signature {
+    pub(crate) fn sign(&self, msg: &[u8]) -> Signature {
         use ed25519_dalek::Signer;
 
         self.0.sign(msg)
@@ -95,6 +97,12 @@ impl From<ed25519_dalek::Keypair> for Keypair {
 #[derive(Clone, PartialEq, Eq, Copy, Serialize, Deserialize)]
 pub struct PeerId(PublicKey);
 
+impl Hash for PeerId {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.as_bytes().hash(state);
+    }
+}
+
 impl From<PublicKey> for PeerId {
     fn from(key: PublicKey) -> Self {
         PeerId(key)
@@ -172,11 +180,14 @@ pub fn make_client_config(
 
 /// Create a TLS server configuration.
 ///
+/// If *allowed_peers* is given, only clients with one of these [`PeerId`]s can connect.
+///
 /// If *keylog* is `true` this will enable logging of the pre-master key to the file in the
 /// `SSLKEYLOGFILE` environment variable.  This can be used to inspect the traffic for
 /// debugging purposes.
 pub fn make_server_config(
     keypair: &Keypair,
+    allowed_peers: Option<HashSet<PeerId>>,
     keylog: bool,
 ) -> Result<rustls::ServerConfig, certificate::GenError> {
     let (certificate, private_key) = certificate::generate(keypair)?;
@@ -186,7 +197,9 @@ pub fn make_server_config(
         .with_safe_default_kx_groups()
         .with_protocol_versions(verifier::PROTOCOL_VERSIONS)
         .expect("Cipher suites and kx groups are configured; qed")
-        .with_client_cert_verifier(Arc::new(verifier::Libp2pCertificateVerifier::new()))
+        .with_client_cert_verifier(Arc::new(
+            verifier::Libp2pCertificateVerifier::with_allowed_peers(allowed_peers),
+        ))
         .with_single_cert(vec![certificate], private_key)
         .expect("Server cert key DER is valid; qed");
     crypto.alpn_protocols = vec![P2P_ALPN.to_vec()];
@@ -3,6 +3,8 @@
 //! This module handles a verification of a client/server certificate chain
 //! and signatures allegedly by the given certificates.
 
+use std::collections::HashSet;
+
 use super::{certificate, PeerId};
 use rustls::{
     cipher_suite::{
@@ -39,6 +41,8 @@ pub static CIPHERSUITES: &[SupportedCipherSuite] = &[
 pub struct Libp2pCertificateVerifier {
     /// The peer ID we intend to connect to
     remote_peer_id: Option<PeerId>,
+    /// The peer IDs of the clients allowed to connect, any client if `None`
+    allowed_peers: Option<HashSet<PeerId>>,
 }
 
 /// libp2p requires the following of X.509 server certificate chains:
@@ -48,13 +52,17 @@ pub struct Libp2pCertificateVerifier {
 /// - The certificate must have a valid libp2p extension that includes a
 ///   signature of its public key.
 impl Libp2pCertificateVerifier {
-    pub fn new() -> Self {
+    pub fn with_remote_peer_id(remote_peer_id: Option<PeerId>) -> Self {
         Self {
-            remote_peer_id: None,
+            remote_peer_id,
+            allowed_peers: None,
         }
     }
-    pub fn with_remote_peer_id(remote_peer_id: Option<PeerId>) -> Self {
-        Self { remote_peer_id }
+    pub fn with_allowed_peers(allowed_peers: Option<HashSet<PeerId>>) -> Self {
+        Self {
+            remote_peer_id: None,
+            allowed_peers,
+        }
     }
 
     /// Return the list of SignatureSchemes that this verifier will handle,
@@ -145,7 +153,15 @@ impl ClientCertVerifier for Libp2pCertificateVerifier {
         intermediates: &[Certificate],
         _now: std::time::SystemTime,
     ) -> Result<ClientCertVerified, rustls::Error> {
-        verify_presented_certs(end_entity, intermediates)?;
+        let peer_id = verify_presented_certs(end_entity, intermediates)?;
+
+        if let Some(ref allowed_peers) = self.allowed_peers {
+            if !allowed_peers.contains(&peer_id) {
+                return Err(rustls::Error::General(format!(
+                    "peer ID {peer_id} is not allowed to connect"
+                )));
+            }
+        }
 
         Ok(ClientCertVerified::assertion())
     }